@@ -21,16 +21,19 @@ use crate::cache::gcs::GCSCache;
 use crate::cache::gha::GHACache;
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
+use crate::cache::multi::MultiStorage;
 #[cfg(feature = "oss")]
 use crate::cache::oss::OSSCache;
+use crate::cache::readonly::ReadOnlyStorage;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
+use crate::cache::shared::SharedDiskCache;
+use crate::cache::tiered::TieredStorage;
 #[cfg(feature = "webdav")]
 use crate::cache::webdav::WebdavCache;
 use crate::compiler::PreprocessorCacheEntry;
-use crate::config::Config;
 #[cfg(any(
     feature = "azure",
     feature = "gcs",
@@ -41,7 +44,8 @@ use crate::config::Config;
     feature = "webdav",
     feature = "oss"
 ))]
-use crate::config::{self, CacheType};
+use crate::config;
+use crate::config::{CacheType, Config, RemoteCacheConfig};
 use async_trait::async_trait;
 use fs_err as fs;
 
@@ -176,6 +180,15 @@ impl CacheRead {
         Ok(mode)
     }
 
+    /// Get the raw data of this cache entry, as it is stored.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut reader = self.zip.into_inner();
+        let mut bytes = Vec::new();
+        reader.rewind()?;
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
     /// Get the stdout from this cache entry, if it exists.
     pub fn get_stdout(&mut self) -> Vec<u8> {
         self.get_bytes("stdout")
@@ -246,6 +259,14 @@ impl CacheWrite {
         }
     }
 
+    /// Create a cache entry from the raw data of another, as returned by
+    /// `CacheRead::into_bytes` or `CacheWrite::finish`.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<CacheWrite> {
+        let zip =
+            ZipWriter::new_append(io::Cursor::new(bytes)).context("Failed to parse cache entry")?;
+        Ok(CacheWrite { zip })
+    }
+
     /// Create a new cache entry populated with the contents of `objects`.
     pub async fn from_objects<T>(objects: T, pool: &tokio::runtime::Handle) -> Result<CacheWrite>
     where
@@ -558,191 +579,230 @@ pub(in crate::cache) fn normalize_key(key: &str) -> String {
 }
 
 /// Get a suitable `Storage` implementation from configuration.
-#[allow(clippy::cognitive_complexity)] // TODO simplify!
 pub fn storage_from_config(
     config: &Config,
     pool: &tokio::runtime::Handle,
 ) -> Result<Arc<dyn Storage>> {
-    if let Some(cache_type) = &config.cache {
-        match cache_type {
-            #[cfg(feature = "azure")]
-            CacheType::Azure(config::AzureCacheConfig {
-                connection_string,
-                container,
-                key_prefix,
-            }) => {
-                debug!("Init azure cache with container {container}, key_prefix {key_prefix}");
-                let storage = AzureBlobCache::build(connection_string, container, key_prefix)
-                    .map_err(|err| anyhow!("create azure cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "gcs")]
-            CacheType::GCS(config::GCSCacheConfig {
+    let mut caches: Vec<Arc<dyn Storage>> = vec![];
+    for RemoteCacheConfig { cache, write } in &config.caches {
+        if let Some(storage) = remote_storage_from_config(cache, pool)? {
+            caches.push(if *write {
+                storage
+            } else {
+                Arc::new(ReadOnlyStorage(storage))
+            });
+        }
+    }
+    let remote: Arc<dyn Storage> = match caches.len() {
+        0 => return Ok(Arc::new(disk_storage_from_config(config, pool))),
+        1 => caches.remove(0),
+        _ => Arc::new(MultiStorage::new(caches)),
+    };
+    Ok(with_local_tier(config, pool, remote))
+}
+
+/// Get the `Storage` implementation of a remote cache, if it is supported
+/// by this build.
+#[allow(clippy::cognitive_complexity)] // TODO simplify!
+fn remote_storage_from_config(
+    cache_type: &CacheType,
+    pool: &tokio::runtime::Handle,
+) -> Result<Option<Arc<dyn Storage>>> {
+    match cache_type {
+        #[cfg(feature = "azure")]
+        CacheType::Azure(config::AzureCacheConfig {
+            connection_string,
+            container,
+            key_prefix,
+        }) => {
+            debug!("Init azure cache with container {container}, key_prefix {key_prefix}");
+            let storage = AzureBlobCache::build(connection_string, container, key_prefix)
+                .map_err(|err| anyhow!("create azure cache failed: {err:?}"))?;
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "gcs")]
+        CacheType::GCS(config::GCSCacheConfig {
+            bucket,
+            key_prefix,
+            cred_path,
+            rw_mode,
+            service_account,
+            credential_url,
+        }) => {
+            debug!("Init gcs cache with bucket {bucket}, key_prefix {key_prefix}");
+
+            let storage = GCSCache::build(
                 bucket,
                 key_prefix,
-                cred_path,
-                rw_mode,
-                service_account,
-                credential_url,
-            }) => {
-                debug!("Init gcs cache with bucket {bucket}, key_prefix {key_prefix}");
-
-                let storage = GCSCache::build(
-                    bucket,
-                    key_prefix,
-                    cred_path.as_deref(),
-                    service_account.as_deref(),
-                    (*rw_mode).into(),
-                    credential_url.as_deref(),
-                )
-                .map_err(|err| anyhow!("create gcs cache failed: {err:?}"))?;
-
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "gha")]
-            CacheType::GHA(config::GHACacheConfig { version, .. }) => {
-                debug!("Init gha cache with version {version}");
+                cred_path.as_deref(),
+                service_account.as_deref(),
+                (*rw_mode).into(),
+                credential_url.as_deref(),
+            )
+            .map_err(|err| anyhow!("create gcs cache failed: {err:?}"))?;
+
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "gha")]
+        CacheType::GHA(config::GHACacheConfig { version, .. }) => {
+            debug!("Init gha cache with version {version}");
 
-                let storage = GHACache::build(version)
-                    .map_err(|err| anyhow!("create gha cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "memcached")]
-            CacheType::Memcached(config::MemcachedCacheConfig {
-                url,
-                username,
-                password,
-                expiration,
-                key_prefix,
-            }) => {
-                debug!("Init memcached cache with url {url}");
-
-                let storage = MemcachedCache::build(
-                    url,
-                    username.as_deref(),
-                    password.as_deref(),
-                    key_prefix,
-                    *expiration,
-                )
-                .map_err(|err| anyhow!("create memcached cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "redis")]
-            CacheType::Redis(config::RedisCacheConfig {
-                endpoint,
-                cluster_endpoints,
-                username,
-                password,
-                db,
+            let storage = GHACache::build(version)
+                .map_err(|err| anyhow!("create gha cache failed: {err:?}"))?;
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "memcached")]
+        CacheType::Memcached(config::MemcachedCacheConfig {
+            url,
+            username,
+            password,
+            expiration,
+            key_prefix,
+        }) => {
+            debug!("Init memcached cache with url {url}");
+
+            let storage = MemcachedCache::build(
                 url,
-                ttl,
+                username.as_deref(),
+                password.as_deref(),
                 key_prefix,
-            }) => {
-                let storage = match (endpoint, cluster_endpoints, url) {
-                    (Some(url), None, None) => {
-                        debug!("Init redis single-node cache with url {url}");
-                        RedisCache::build_single(
-                            url,
-                            username.as_deref(),
-                            password.as_deref(),
-                            *db,
-                            key_prefix,
-                            *ttl,
-                        )
-                    }
-                    (None, Some(urls), None) => {
-                        debug!("Init redis cluster cache with urls {urls}");
-                        RedisCache::build_cluster(
-                            urls,
-                            username.as_deref(),
-                            password.as_deref(),
-                            *db,
-                            key_prefix,
-                            *ttl,
-                        )
+                *expiration,
+            )
+            .map_err(|err| anyhow!("create memcached cache failed: {err:?}"))?;
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "redis")]
+        CacheType::Redis(config::RedisCacheConfig {
+            endpoint,
+            cluster_endpoints,
+            username,
+            password,
+            db,
+            url,
+            ttl,
+            key_prefix,
+        }) => {
+            let storage = match (endpoint, cluster_endpoints, url) {
+                (Some(url), None, None) => {
+                    debug!("Init redis single-node cache with url {url}");
+                    RedisCache::build_single(
+                        url,
+                        username.as_deref(),
+                        password.as_deref(),
+                        *db,
+                        key_prefix,
+                        *ttl,
+                    )
+                }
+                (None, Some(urls), None) => {
+                    debug!("Init redis cluster cache with urls {urls}");
+                    RedisCache::build_cluster(
+                        urls,
+                        username.as_deref(),
+                        password.as_deref(),
+                        *db,
+                        key_prefix,
+                        *ttl,
+                    )
+                }
+                (None, None, Some(url)) => {
+                    warn!("Init redis single-node cache from deprecated API with url {url}");
+                    if username.is_some() || password.is_some() || *db != crate::config::DEFAULT_REDIS_DB {
+                        bail!("`username`, `password` and `db` has no effect when `url` is set. Please use `endpoint` or `cluster_endpoints` for new API accessing");
                     }
-                    (None, None, Some(url)) => {
-                        warn!("Init redis single-node cache from deprecated API with url {url}");
-                        if username.is_some() || password.is_some() || *db != crate::config::DEFAULT_REDIS_DB {
-                            bail!("`username`, `password` and `db` has no effect when `url` is set. Please use `endpoint` or `cluster_endpoints` for new API accessing");
-                        }
 
-                        RedisCache::build_from_url(url, key_prefix, *ttl)
-                    }
-                    _ => bail!("Only one of `endpoint`, `cluster_endpoints`, `url` must be set"),
+                    RedisCache::build_from_url(url, key_prefix, *ttl)
                 }
-                .map_err(|err| anyhow!("create redis cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "s3")]
-            CacheType::S3(c) => {
-                debug!(
-                    "Init s3 cache with bucket {}, endpoint {:?}",
-                    c.bucket, c.endpoint
-                );
-                let storage_builder =
-                    S3Cache::new(c.bucket.clone(), c.key_prefix.clone(), c.no_credentials);
-                let storage = storage_builder
-                    .with_region(c.region.clone())
-                    .with_endpoint(c.endpoint.clone())
-                    .with_use_ssl(c.use_ssl)
-                    .with_server_side_encryption(c.server_side_encryption)
-                    .with_enable_virtual_host_style(c.enable_virtual_host_style)
-                    .build()
-                    .map_err(|err| anyhow!("create s3 cache failed: {err:?}"))?;
-
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "webdav")]
-            CacheType::Webdav(c) => {
-                debug!("Init webdav cache with endpoint {}", c.endpoint);
-
-                let storage = WebdavCache::build(
-                    &c.endpoint,
-                    &c.key_prefix,
-                    c.username.as_deref(),
-                    c.password.as_deref(),
-                    c.token.as_deref(),
-                )
-                .map_err(|err| anyhow!("create webdav cache failed: {err:?}"))?;
-
-                return Ok(Arc::new(storage));
-            }
-            #[cfg(feature = "oss")]
-            CacheType::OSS(c) => {
-                debug!(
-                    "Init oss cache with bucket {}, endpoint {:?}",
-                    c.bucket, c.endpoint
-                );
-
-                let storage = OSSCache::build(
-                    &c.bucket,
-                    &c.key_prefix,
-                    c.endpoint.as_deref(),
-                    c.no_credentials,
-                )
-                .map_err(|err| anyhow!("create oss cache failed: {err:?}"))?;
-
-                return Ok(Arc::new(storage));
+                _ => bail!("Only one of `endpoint`, `cluster_endpoints`, `url` must be set"),
             }
-            #[allow(unreachable_patterns)]
-            // if we build only with `cargo build --no-default-features`
-            // we only want to use sccache with a local cache (no remote storage)
-            _ => {}
+            .map_err(|err| anyhow!("create redis cache failed: {err:?}"))?;
+            return Ok(Some(Arc::new(storage)));
         }
+        #[cfg(feature = "s3")]
+        CacheType::S3(c) => {
+            debug!(
+                "Init s3 cache with bucket {}, endpoint {:?}",
+                c.bucket, c.endpoint
+            );
+            let storage_builder =
+                S3Cache::new(c.bucket.clone(), c.key_prefix.clone(), c.no_credentials);
+            let storage = storage_builder
+                .with_region(c.region.clone())
+                .with_endpoint(c.endpoint.clone())
+                .with_use_ssl(c.use_ssl)
+                .with_server_side_encryption(c.server_side_encryption)
+                .with_enable_virtual_host_style(c.enable_virtual_host_style)
+                .build()
+                .map_err(|err| anyhow!("create s3 cache failed: {err:?}"))?;
+
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "webdav")]
+        CacheType::Webdav(c) => {
+            debug!("Init webdav cache with endpoint {}", c.endpoint);
+
+            let storage = WebdavCache::build(
+                &c.endpoint,
+                &c.key_prefix,
+                c.username.as_deref(),
+                c.password.as_deref(),
+                c.token.as_deref(),
+            )
+            .map_err(|err| anyhow!("create webdav cache failed: {err:?}"))?;
+
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[cfg(feature = "oss")]
+        CacheType::OSS(c) => {
+            debug!(
+                "Init oss cache with bucket {}, endpoint {:?}",
+                c.bucket, c.endpoint
+            );
+
+            let storage = OSSCache::build(
+                &c.bucket,
+                &c.key_prefix,
+                c.endpoint.as_deref(),
+                c.no_credentials,
+            )
+            .map_err(|err| anyhow!("create oss cache failed: {err:?}"))?;
+
+            return Ok(Some(Arc::new(storage)));
+        }
+        CacheType::Shared(c) => {
+            debug!("Init shared disk cache with dir {:?}", c.dir);
+            let storage = SharedDiskCache::new(&c.dir, c.size, pool);
+            return Ok(Some(Arc::new(storage)));
+        }
+        #[allow(unreachable_patterns)]
+        // if we build only with `cargo build --no-default-features`
+        // we only want to use sccache with a local cache (no remote storage)
+        _ => {}
     }
 
+    Ok(None)
+}
+
+fn disk_storage_from_config(config: &Config, pool: &tokio::runtime::Handle) -> DiskCache {
     let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
     let preprocessor_cache_mode_config = config.fallback_cache.preprocessor_cache_mode;
     let rw_mode = config.fallback_cache.rw_mode.into();
     debug!("Init disk cache with dir {:?}, size {}", dir, size);
-    Ok(Arc::new(DiskCache::new(
-        dir,
-        size,
-        pool,
-        preprocessor_cache_mode_config,
-        rw_mode,
-    )))
+    DiskCache::new(dir, size, pool, preprocessor_cache_mode_config, rw_mode)
+}
+
+/// Put the disk cache in front of the `remote` storage, if it is configured
+/// as a local tier.
+fn with_local_tier(
+    config: &Config,
+    pool: &tokio::runtime::Handle,
+    remote: Arc<dyn Storage>,
+) -> Arc<dyn Storage> {
+    if !config.fallback_cache.tiered {
+        return remote;
+    }
+    let local = Arc::new(disk_storage_from_config(config, pool));
+    Arc::new(TieredStorage::new(local, remote))
 }
 
 #[cfg(test)]
@@ -768,7 +828,7 @@ mod test {
 
         // Use disk cache.
         let mut config = Config {
-            cache: None,
+            caches: vec![],
             ..Default::default()
         };
 
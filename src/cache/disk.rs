@@ -14,11 +14,12 @@
 
 use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage};
 use crate::compiler::PreprocessorCacheEntry;
-use crate::lru_disk_cache::LruDiskCache;
-use crate::lru_disk_cache::{Error as LruError, ReadSeek};
+use crate::lru_disk_cache::{LruDiskCache, ReadSeek, remove_evicted, write_reserved};
 use async_trait::async_trait;
+use filetime::FileTime;
+use fs_err::File;
 use std::ffi::{OsStr, OsString};
-use std::io::{BufWriter, Write};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -66,8 +67,12 @@ impl LazyDiskCache {
 }
 
 /// A cache that stores entries at local disk paths.
+///
+/// The lock on the `LruDiskCache` is only held to look up entries, and to account
+/// for their size and decide which ones to evict. Files are read, written and
+/// removed without it, so that parallel compiles don't wait on each other's I/O.
 pub struct DiskCache {
-    /// `LruDiskCache` does all the real work here.
+    /// `LruDiskCache` keeps track of the entries.
     lru: Arc<Mutex<LazyDiskCache>>,
     /// Thread pool to execute disk I/O
     pool: tokio::runtime::Handle,
@@ -108,6 +113,48 @@ fn make_key_path(key: &str) -> PathBuf {
     Path::new(&key[0..1]).join(&key[1..2]).join(key)
 }
 
+/// Open the file of the entry at path `key` of `lru`, if there is one.
+fn get_file(lru: &Mutex<LazyDiskCache>, key: &OsStr) -> Result<Option<File>> {
+    let Some(path) = lru.lock().unwrap().get_or_init()?.lookup(key) else {
+        return Ok(None);
+    };
+    let t = FileTime::now();
+    match filetime::set_file_times(&path, t, t).and_then(|()| File::open(&path)) {
+        Ok(f) => Ok(Some(f)),
+        // Evicted since the lookup, or removed from outside, unless it was
+        // added back in the meantime
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            lru.lock().unwrap().get_or_init()?.forget_missing(key);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Add an entry holding `data` at path `key` of `lru`.
+fn put_file(lru: &Mutex<LazyDiskCache>, key: &OsStr, data: &[u8]) -> Result<()> {
+    let size = data.len() as u64;
+    let (root, evicted) = {
+        let mut lru = lru.lock().unwrap();
+        let lru = lru.get_or_init()?;
+        (lru.path().to_owned(), lru.reserve(key, size)?)
+    };
+    remove_evicted(evicted);
+    let written = write_reserved(&root, key, data);
+    let mut lru = lru.lock().unwrap();
+    let lru = lru.get_or_init()?;
+    match written {
+        Ok(()) => {
+            lru.insert_reserved(key, size);
+            Ok(())
+        }
+        Err(e) => {
+            lru.release(key, size);
+            Err(e.into())
+        }
+    }
+}
+
 #[async_trait]
 impl Storage for DiskCache {
     async fn get(&self, key: &str) -> Result<Cache> {
@@ -117,21 +164,12 @@ impl Storage for DiskCache {
         let key = key.to_owned();
 
         self.pool
-            .spawn_blocking(move || {
-                let io = match lru.lock().unwrap().get_or_init()?.get(&path) {
-                    Ok(f) => f,
-                    Err(LruError::FileNotInCache) => {
-                        trace!("DiskCache::get({}): FileNotInCache", key);
-                        return Ok(Cache::Miss);
-                    }
-                    Err(LruError::Io(e)) => {
-                        trace!("DiskCache::get({}): IoError: {:?}", key, e);
-                        return Err(e.into());
-                    }
-                    Err(_) => unreachable!(),
-                };
-                let hit = CacheRead::from(io)?;
-                Ok(Cache::Hit(hit))
+            .spawn_blocking(move || match get_file(&lru, path.as_os_str())? {
+                Some(f) => Ok(Cache::Hit(CacheRead::from(f)?)),
+                None => {
+                    trace!("DiskCache::get({}): FileNotInCache", key);
+                    Ok(Cache::Miss)
+                }
             })
             .await?
     }
@@ -152,13 +190,7 @@ impl Storage for DiskCache {
             .spawn_blocking(move || {
                 let start = Instant::now();
                 let v = entry.finish()?;
-                let mut f = lru
-                    .lock()
-                    .unwrap()
-                    .get_or_init()?
-                    .prepare_add(key, v.len() as u64)?;
-                f.as_file_mut().write_all(&v)?;
-                lru.lock().unwrap().get().unwrap().commit(f)?;
+                put_file(&lru, key.as_os_str(), &v)?;
                 Ok(start.elapsed())
             })
             .await?
@@ -183,13 +215,15 @@ impl Storage for DiskCache {
     }
     async fn get_preprocessor_cache_entry(&self, key: &str) -> Result<Option<Box<dyn ReadSeek>>> {
         let key = normalize_key(key);
-        Ok(self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get_or_init()?
-            .get(key)
-            .ok())
+        let preprocessor_cache = self.preprocessor_cache.clone();
+        self.pool
+            .spawn_blocking(move || {
+                Ok(get_file(&preprocessor_cache, key.as_ref())
+                    .ok()
+                    .flatten()
+                    .map(|f| Box::new(f) as Box<dyn ReadSeek>))
+            })
+            .await?
     }
     async fn put_preprocessor_cache_entry(
         &self,
@@ -201,19 +235,70 @@ impl Storage for DiskCache {
         }
 
         let key = normalize_key(key);
-        let mut f = self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get_or_init()?
-            .prepare_add(key, 0)?;
-        preprocessor_cache_entry.serialize_to(BufWriter::new(f.as_file_mut()))?;
-        Ok(self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get()
-            .unwrap()
-            .commit(f)?)
+        let mut data = vec![];
+        preprocessor_cache_entry.serialize_to(&mut data)?;
+        let preprocessor_cache = self.preprocessor_cache.clone();
+        self.pool
+            .spawn_blocking(move || put_file(&preprocessor_cache, key.as_ref(), &data))
+            .await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::test_utils::entry;
+    use futures::future;
+
+    #[test]
+    fn test_concurrent_access() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(8)
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let max_size = 64 * 1024;
+        let cache = Arc::new(DiskCache::new(
+            tempdir.path(),
+            max_size,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        ));
+
+        // Enough entries to evict some of them while others are being read
+        // and written.
+        let tasks = (0..200).map(|i| {
+            let cache = cache.clone();
+            runtime.spawn(async move {
+                let key = format!("{:02x}{:04}", i % 256, i);
+                let stdout = vec![i as u8; 4096];
+                cache.put(&key, entry(&stdout)).await.unwrap();
+                for _ in 0..3 {
+                    match cache.get(&key).await.unwrap() {
+                        Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), stdout),
+                        Cache::Miss => {}
+                        _ => panic!("unexpected cache result"),
+                    }
+                }
+            })
+        });
+        for task in runtime.block_on(future::join_all(tasks)) {
+            task.unwrap();
+        }
+
+        // The files left on disk are the ones accounted for.
+        let size = runtime.block_on(cache.current_size()).unwrap().unwrap();
+        assert!(size <= max_size);
+        let on_disk: u64 = walkdir::WalkDir::new(tempdir.path())
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| {
+                e.file_type().is_file() && !e.file_name().to_string_lossy().starts_with('.')
+            })
+            .map(|e| e.metadata().unwrap().len())
+            .sum();
+        assert_eq!(on_disk, size);
     }
 }
@@ -23,6 +23,7 @@ pub mod gcs;
 pub mod gha;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+pub mod multi;
 #[cfg(feature = "oss")]
 pub mod oss;
 pub mod readonly;
@@ -30,6 +31,10 @@ pub mod readonly;
 pub mod redis;
 #[cfg(feature = "s3")]
 pub mod s3;
+pub mod shared;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod tiered;
 #[cfg(feature = "webdav")]
 pub mod webdav;
 
@@ -0,0 +1,284 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future;
+
+use crate::cache::{Cache, CacheMode, CacheWrite, Storage};
+use crate::errors::*;
+
+/// A list of remote caches.
+///
+/// Lookups go through the caches in order until one of them has the entry,
+/// and new entries are written to all the caches that can be written to.
+/// Entries are not copied from one cache to another. A cache failing its
+/// check is not used until the server restarts.
+pub struct MultiStorage {
+    caches: Vec<Arc<dyn Storage>>,
+    /// Whether each cache can be written to, as found by `check`.
+    writable: Vec<AtomicBool>,
+    /// Whether each cache can be used at all, i.e. `check` didn't fail.
+    usable: Vec<AtomicBool>,
+}
+
+impl MultiStorage {
+    pub fn new(caches: Vec<Arc<dyn Storage>>) -> Self {
+        let writable = caches.iter().map(|_| AtomicBool::new(true)).collect();
+        let usable = caches.iter().map(|_| AtomicBool::new(true)).collect();
+        MultiStorage {
+            caches,
+            writable,
+            usable,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MultiStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let mut errors = vec![];
+        let caches: Vec<_> = self
+            .caches
+            .iter()
+            .zip(&self.usable)
+            .filter(|(_, usable)| usable.load(Ordering::Relaxed))
+            .map(|(cache, _)| cache)
+            .collect();
+        for cache in &caches {
+            match cache.get(key).await {
+                Ok(Cache::Hit(entry)) => return Ok(Cache::Hit(entry)),
+                Ok(_) => {}
+                // The next caches may still have the entry
+                Err(e) => {
+                    warn!("Failed to read from {}: {:#}", cache.location(), e);
+                    errors.push(e);
+                }
+            }
+        }
+        // Only report an error when no cache could be read at all
+        match errors.pop() {
+            Some(e) if errors.len() + 1 == caches.len() => Err(e),
+            _ => Ok(Cache::Miss),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let caches: Vec<_> = self
+            .caches
+            .iter()
+            .zip(&self.writable)
+            .filter(|(_, writable)| writable.load(Ordering::Relaxed))
+            .map(|(cache, _)| cache)
+            .collect();
+        if caches.is_empty() {
+            bail!("Cannot write to a read-only cache");
+        }
+        let data = entry.finish()?;
+        let entries = caches
+            .iter()
+            .map(|_| CacheWrite::from_bytes(data.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let results = future::join_all(
+            caches
+                .iter()
+                .zip(entries)
+                .map(|(cache, entry)| cache.put(key, entry)),
+        )
+        .await;
+        // The write succeeds as long as one of the caches got the entry
+        let mut duration = None;
+        let mut error = None;
+        for (cache, result) in caches.iter().zip(results) {
+            match result {
+                Ok(d) => duration = duration.max(Some(d)),
+                Err(e) => {
+                    warn!("Failed to write {} to {}: {:#}", key, cache.location(), e);
+                    error = Some(e);
+                }
+            }
+        }
+        match (duration, error) {
+            (Some(duration), _) => Ok(duration),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Check the capability of every cache. The storage can be written to
+    /// as long as one of them can, and only fails when all of them fail.
+    async fn check(&self) -> Result<CacheMode> {
+        let mut mode = None;
+        let mut error = None;
+        for ((cache, writable), usable) in self.caches.iter().zip(&self.writable).zip(&self.usable)
+        {
+            let result = cache.check().await;
+            usable.store(result.is_ok(), Ordering::Relaxed);
+            match result {
+                Ok(cache_mode) => {
+                    writable.store(cache_mode == CacheMode::ReadWrite, Ordering::Relaxed);
+                    if mode != Some(CacheMode::ReadWrite) {
+                        mode = Some(cache_mode);
+                    }
+                }
+                // The server only checks the storage when it starts, so it is
+                // left out until the server is restarted
+                Err(e) => {
+                    warn!("Failed to check {}: {:#}", cache.location(), e);
+                    writable.store(false, Ordering::Relaxed);
+                    error = Some(e.context(format!("Failed to check {}", cache.location())));
+                }
+            }
+        }
+        match (mode, error) {
+            (Some(mode), _) => Ok(mode),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn location(&self) -> String {
+        let locations: Vec<_> = self.caches.iter().map(|c| c.location()).collect();
+        locations.join(", then ")
+    }
+
+    /// The usage of the first cache.
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.caches[0].current_size().await
+    }
+
+    /// The maximum size of the first cache.
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.caches[0].max_size().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::readonly::ReadOnlyStorage;
+    use crate::cache::test_utils::{disk_cache, entry, stdout};
+
+    /// A cache that can't be reached.
+    struct Unreachable;
+
+    #[async_trait]
+    impl Storage for Unreachable {
+        async fn get(&self, _key: &str) -> Result<Cache> {
+            bail!("unreachable")
+        }
+        async fn put(&self, _key: &str, _entry: CacheWrite) -> Result<Duration> {
+            bail!("unreachable")
+        }
+        async fn check(&self) -> Result<CacheMode> {
+            bail!("unreachable")
+        }
+        fn location(&self) -> String {
+            "Unreachable".to_string()
+        }
+        async fn current_size(&self) -> Result<Option<u64>> {
+            Ok(None)
+        }
+        async fn max_size(&self) -> Result<Option<u64>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_multi_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let pool = runtime.handle();
+        let first = disk_cache(&tempdir.path().join("first"), pool, CacheMode::ReadWrite);
+        let second = disk_cache(&tempdir.path().join("second"), pool, CacheMode::ReadWrite);
+        let mirror = disk_cache(&tempdir.path().join("mirror"), pool, CacheMode::ReadWrite);
+        let storage = MultiStorage::new(vec![
+            first.clone(),
+            second.clone(),
+            Arc::new(ReadOnlyStorage(mirror.clone())),
+        ]);
+
+        runtime.block_on(async {
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadWrite);
+
+            // Writes only go to the writable caches
+            storage.put("a1", entry(b"one")).await.unwrap();
+            assert_eq!(stdout(&*first, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&*second, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&*mirror, "a1").await, None);
+
+            // Reads fall back to the next caches, in order
+            mirror.put("b2", entry(b"mirror")).await.unwrap();
+            assert_eq!(stdout(&storage, "b2").await.unwrap(), b"mirror");
+            second.put("b2", entry(b"second")).await.unwrap();
+            assert_eq!(stdout(&storage, "b2").await.unwrap(), b"second");
+            assert_eq!(stdout(&*first, "b2").await, None);
+
+            assert_eq!(stdout(&storage, "c3").await, None);
+        });
+    }
+
+    #[test]
+    fn test_multi_storage_read_only() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let pool = runtime.handle();
+        let storage = MultiStorage::new(vec![
+            Arc::new(ReadOnlyStorage(disk_cache(
+                &tempdir.path().join("a"),
+                pool,
+                CacheMode::ReadWrite,
+            ))),
+            Arc::new(ReadOnlyStorage(disk_cache(
+                &tempdir.path().join("b"),
+                pool,
+                CacheMode::ReadWrite,
+            ))),
+        ]);
+
+        runtime.block_on(async {
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadOnly);
+            assert!(storage.put("a1", entry(b"one")).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_multi_storage_check_failure() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let disk = disk_cache(tempdir.path(), runtime.handle(), CacheMode::ReadWrite);
+        let storage = MultiStorage::new(vec![Arc::new(Unreachable), disk.clone()]);
+
+        runtime.block_on(async {
+            // The other caches are still used
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadWrite);
+            storage.put("a1", entry(b"one")).await.unwrap();
+            assert_eq!(stdout(&*disk, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&storage, "a1").await.unwrap(), b"one");
+
+            let storage = MultiStorage::new(vec![Arc::new(Unreachable), Arc::new(Unreachable)]);
+            assert!(storage.check().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,313 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use filetime::FileTime;
+use fs_err as fs;
+
+use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage, normalize_key};
+use crate::config::GcTime;
+use crate::errors::*;
+
+/// Prefix of the files entries are written to before being renamed into
+/// place.
+const TEMP_PREFIX: &str = ".tmp";
+
+/// Temporary files older than this were left behind by a writer that died,
+/// and are removed by `gc`.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A cache in a directory shared by several servers, possibly on several
+/// hosts over NFS.
+///
+/// Unlike `DiskCache`, it keeps no index of the entries in memory, so that
+/// servers don't need to agree on one. Entries are written to a temporary
+/// file and renamed into place, so that readers never see a partial entry,
+/// and the cache is only trimmed by `sccache --cache-gc`.
+pub struct SharedDiskCache {
+    root: PathBuf,
+    /// Thread pool to execute disk I/O
+    pool: tokio::runtime::Handle,
+    max_size: u64,
+}
+
+impl SharedDiskCache {
+    pub fn new(root: &Path, max_size: u64, pool: &tokio::runtime::Handle) -> Self {
+        SharedDiskCache {
+            root: root.to_owned(),
+            pool: pool.clone(),
+            max_size,
+        }
+    }
+}
+
+/// Write `data` to `path` atomically, through a temporary file in the same
+/// directory.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().expect("entries have a parent directory");
+    fs::create_dir_all(dir)?;
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMP_PREFIX);
+    // Let the umask decide who can read the entry, rather than the 0600 of
+    // temporary files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    let mut file = builder
+        .tempfile_in(dir)
+        .with_context(|| format!("failed to create a temporary file in {}", dir.display()))?;
+    file.write_all(data)?;
+    file.persist(path)
+        .with_context(|| format!("failed to rename into {}", path.display()))?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SharedDiskCache {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        trace!("SharedDiskCache::get({})", key);
+        let path = self.root.join(normalize_key(key));
+        self.pool
+            .spawn_blocking(move || {
+                let data = match fs::read(&path) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Cache::Miss),
+                    Err(e) => return Err(e.into()),
+                };
+                // Mark the entry as used for `gc` with `GcTime::Mtime`, like
+                // `LruDiskCache` does. The directory may be read-only to us.
+                if let Err(e) = filetime::set_file_mtime(&path, FileTime::now()) {
+                    trace!("SharedDiskCache::get: failed to touch {:?}: {}", path, e);
+                }
+                Ok(Cache::Hit(CacheRead::from(Cursor::new(data))?))
+            })
+            .await?
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        trace!("SharedDiskCache::put({})", key);
+        let start = Instant::now();
+        let data = entry.finish()?;
+        let path = self.root.join(normalize_key(key));
+        self.pool
+            .spawn_blocking(move || write_atomically(&path, &data))
+            .await??;
+        Ok(start.elapsed())
+    }
+
+    /// Check that entries can be written to the cache directory.
+    async fn check(&self) -> Result<CacheMode> {
+        let root = self.root.clone();
+        self.pool
+            .spawn_blocking(move || {
+                fs::create_dir_all(&root)?;
+                match tempfile::Builder::new()
+                    .prefix(TEMP_PREFIX)
+                    .tempfile_in(&root)
+                {
+                    Ok(_) => Ok(CacheMode::ReadWrite),
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        Ok(CacheMode::ReadOnly)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            })
+            .await?
+    }
+
+    fn location(&self) -> String {
+        format!("Shared disk: {:?}", self.root)
+    }
+
+    /// Not tracked, as it would mean walking the whole directory.
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// The size `sccache --cache-gc` trims the cache to.
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(Some(self.max_size))
+    }
+}
+
+/// The outcome of `gc`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Number of entries removed
+    pub removed: u64,
+    /// Total size of the entries removed
+    pub removed_size: u64,
+    /// Total size of the entries left
+    pub size: u64,
+}
+
+/// Trim the shared cache in `root` to `max_size`, removing the entries with
+/// the oldest `time` first. Temporary files left behind by writers that died
+/// are removed too.
+///
+/// Several `gc` may run at once, on any host: entries that are already gone
+/// are skipped.
+pub fn gc(root: &Path, max_size: u64, time: GcTime) -> Result<GcStats> {
+    let now = SystemTime::now();
+    let mut entries = vec![];
+    let mut stats = GcStats::default();
+    for entry in walkdir::WalkDir::new(root) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) => {
+                continue;
+            }
+            Err(e) => return Err(e).context("failed to list the shared cache"),
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let modified = metadata.modified()?;
+        if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > STALE_TEMP_AGE {
+                remove(entry.path())?;
+            }
+            continue;
+        }
+        let used = match time {
+            GcTime::Atime => metadata.accessed()?,
+            GcTime::Mtime => modified,
+        };
+        stats.size += metadata.len();
+        entries.push((used, metadata.len(), entry.into_path()));
+    }
+
+    entries.sort();
+    for (_, size, path) in entries {
+        if stats.size <= max_size {
+            break;
+        }
+        remove(&path)?;
+        stats.removed += 1;
+        stats.removed_size += size;
+        stats.size -= size;
+    }
+    Ok(stats)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::test_utils::entry;
+
+    #[test]
+    fn test_shared_disk_cache() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("shared");
+        // Two servers sharing the directory
+        let a = SharedDiskCache::new(&root, 1024, runtime.handle());
+        let b = SharedDiskCache::new(&root, 1024, runtime.handle());
+
+        runtime.block_on(async {
+            assert_eq!(a.check().await.unwrap(), CacheMode::ReadWrite);
+            assert!(matches!(b.get("abcd").await.unwrap(), Cache::Miss));
+            a.put("abcd", entry(b"one")).await.unwrap();
+            match b.get("abcd").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"one"),
+                _ => panic!("expected a hit"),
+            }
+            // Hits count as a use of the entry
+            let path = root.join("a/b/c/abcd");
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(1_000, 0)).unwrap();
+            assert!(matches!(b.get("abcd").await.unwrap(), Cache::Hit(_)));
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&path).unwrap());
+            assert!(mtime.unix_seconds() > 1_000);
+            // Overwriting an entry replaces it as a whole
+            b.put("abcd", entry(b"two")).await.unwrap();
+            match a.get("abcd").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"two"),
+                _ => panic!("expected a hit"),
+            }
+        });
+        assert!(root.join("a/b/c/abcd").is_file());
+        let temp_files = walkdir::WalkDir::new(&root)
+            .into_iter()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(TEMP_PREFIX)
+            })
+            .count();
+        assert_eq!(temp_files, 0);
+    }
+
+    #[test]
+    fn test_gc() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        let write = |key: &str, size: usize, mtime: i64| {
+            let path = root.join(normalize_key(key));
+            write_atomically(&path, &vec![0; size]).unwrap();
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0)).unwrap();
+            path
+        };
+        let old = write("aaaa", 100, 1_000);
+        let new = write("bbbb", 100, 3_000);
+        let middle = write("cccc", 100, 2_000);
+        let stale_temp = root.join("a").join(format!("{}xyz", TEMP_PREFIX));
+        fs::write(&stale_temp, b"partial").unwrap();
+        filetime::set_file_mtime(&stale_temp, FileTime::from_unix_time(0, 0)).unwrap();
+        let fresh_temp = root.join("b").join(format!("{}xyz", TEMP_PREFIX));
+        fs::write(&fresh_temp, b"partial").unwrap();
+
+        assert_eq!(
+            gc(root, 1000, GcTime::Mtime).unwrap(),
+            GcStats {
+                removed: 0,
+                removed_size: 0,
+                size: 300,
+            }
+        );
+        assert!(!stale_temp.exists());
+        assert!(fresh_temp.exists());
+
+        assert_eq!(
+            gc(root, 150, GcTime::Mtime).unwrap(),
+            GcStats {
+                removed: 2,
+                removed_size: 200,
+                size: 100,
+            }
+        );
+        assert!(!old.exists());
+        assert!(!middle.exists());
+        assert!(new.exists());
+    }
+}
@@ -0,0 +1,45 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers shared by the cache tests.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cache::disk::DiskCache;
+use crate::cache::{Cache, CacheMode, CacheWrite, PreprocessorCacheModeConfig, Storage};
+
+/// A 1MiB `DiskCache` at `dir`.
+pub fn disk_cache(dir: &Path, pool: &tokio::runtime::Handle, rw_mode: CacheMode) -> Arc<DiskCache> {
+    Arc::new(DiskCache::new(
+        dir,
+        1024 * 1024,
+        pool,
+        PreprocessorCacheModeConfig::default(),
+        rw_mode,
+    ))
+}
+
+/// A cache entry holding `stdout`.
+pub fn entry(stdout: &[u8]) -> CacheWrite {
+    let mut entry = CacheWrite::new();
+    entry.put_stdout(stdout).unwrap();
+    entry
+}
+
+/// The stdout of the entry at `key` of `storage`, if it has one.
+pub async fn stdout(storage: &dyn Storage, key: &str) -> Option<Vec<u8>> {
+    match storage.get(key).await.unwrap() {
+        Cache::Hit(mut entry) => Some(entry.get_stdout()),
+        _ => None,
+    }
+}
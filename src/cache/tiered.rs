@@ -0,0 +1,222 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A local cache in front of a remote one.
+///
+/// Lookups try the local tier first, and entries found in the remote tier
+/// are copied to the local one, so that they are only fetched once. New
+/// entries are written to both tiers.
+pub struct TieredStorage {
+    local: Arc<dyn Storage>,
+    remote: Arc<dyn Storage>,
+    /// Whether the tiers can be written to, as found by `check`.
+    local_writable: AtomicBool,
+    remote_writable: AtomicBool,
+}
+
+impl TieredStorage {
+    pub fn new(local: Arc<dyn Storage>, remote: Arc<dyn Storage>) -> Self {
+        TieredStorage {
+            local,
+            remote,
+            local_writable: AtomicBool::new(true),
+            remote_writable: AtomicBool::new(true),
+        }
+    }
+
+    /// Copy an entry of the remote tier to the local one.
+    async fn promote(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.local.put(key, CacheWrite::from_bytes(data)?).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.local.get(key).await {
+            Ok(Cache::Hit(entry)) => return Ok(Cache::Hit(entry)),
+            Ok(_) => {}
+            // The remote tier may still have the entry
+            Err(e) => warn!("Failed to read from the local cache tier: {:#}", e),
+        }
+        match self.remote.get(key).await? {
+            Cache::Hit(entry) if self.local_writable.load(Ordering::Relaxed) => {
+                let data = entry.into_bytes()?;
+                if let Err(e) = self.promote(key, data.clone()).await {
+                    warn!("Failed to copy {} to the local cache tier: {:#}", key, e);
+                }
+                Ok(Cache::Hit(CacheRead::from(Cursor::new(data))?))
+            }
+            cache => Ok(cache),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        match (
+            self.local_writable.load(Ordering::Relaxed),
+            self.remote_writable.load(Ordering::Relaxed),
+        ) {
+            (false, false) => Err(anyhow!("Cannot write to a read-only cache")),
+            (true, false) => self.local.put(key, entry).await,
+            (false, true) => self.remote.put(key, entry).await,
+            (true, true) => {
+                let data = entry.finish()?;
+                let local = CacheWrite::from_bytes(data.clone())?;
+                let remote = CacheWrite::from_bytes(data)?;
+                let (local, remote) =
+                    futures::join!(self.local.put(key, local), self.remote.put(key, remote));
+                // Only the remote tier is shared, so only its errors are
+                // reported as failed cache writes
+                if let Err(e) = local {
+                    warn!("Failed to write {} to the local cache tier: {:#}", key, e);
+                }
+                remote
+            }
+        }
+    }
+
+    /// Check the capability of both tiers. The storage can be written to
+    /// as long as one of them can.
+    async fn check(&self) -> Result<CacheMode> {
+        let local = self.local.check().await?;
+        let remote = self.remote.check().await?;
+        self.local_writable
+            .store(local == CacheMode::ReadWrite, Ordering::Relaxed);
+        self.remote_writable
+            .store(remote == CacheMode::ReadWrite, Ordering::Relaxed);
+        if local == CacheMode::ReadOnly && remote == CacheMode::ReadOnly {
+            Ok(CacheMode::ReadOnly)
+        } else {
+            Ok(CacheMode::ReadWrite)
+        }
+    }
+
+    fn location(&self) -> String {
+        format!(
+            "{}, in front of {}",
+            self.local.location(),
+            self.remote.location()
+        )
+    }
+
+    /// The usage of the local tier.
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.local.current_size().await
+    }
+
+    /// The maximum size of the local tier.
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.local.max_size().await
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.local.preprocessor_cache_mode_config()
+    }
+
+    /// Preprocessor cache entries are only kept in the local tier.
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.local.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.local
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::test_utils::{disk_cache, entry, stdout};
+
+    #[test]
+    fn test_tiered_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let pool = runtime.handle();
+        let local = disk_cache(&tempdir.path().join("local"), pool, CacheMode::ReadWrite);
+        let remote = disk_cache(&tempdir.path().join("remote"), pool, CacheMode::ReadWrite);
+        let storage = TieredStorage::new(local.clone(), remote.clone());
+
+        runtime.block_on(async {
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadWrite);
+
+            // Writes go to both tiers
+            storage.put("a1", entry(b"one")).await.unwrap();
+            assert_eq!(stdout(&*local, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&*remote, "a1").await.unwrap(), b"one");
+
+            // Remote hits are copied to the local tier
+            remote.put("b2", entry(b"two")).await.unwrap();
+            assert_eq!(stdout(&*local, "b2").await, None);
+            assert_eq!(stdout(&storage, "b2").await.unwrap(), b"two");
+            assert_eq!(stdout(&*local, "b2").await.unwrap(), b"two");
+
+            assert_eq!(stdout(&storage, "c3").await, None);
+        });
+    }
+
+    #[test]
+    fn test_tiered_storage_read_only_local() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let pool = runtime.handle();
+        let local = disk_cache(&tempdir.path().join("local"), pool, CacheMode::ReadOnly);
+        let remote = disk_cache(&tempdir.path().join("remote"), pool, CacheMode::ReadWrite);
+        let storage = TieredStorage::new(local.clone(), remote.clone());
+
+        runtime.block_on(async {
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadWrite);
+
+            storage.put("a1", entry(b"one")).await.unwrap();
+            assert_eq!(stdout(&*remote, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&storage, "a1").await.unwrap(), b"one");
+            assert_eq!(stdout(&*local, "a1").await, None);
+        });
+
+        let local = disk_cache(&tempdir.path().join("local"), pool, CacheMode::ReadOnly);
+        let remote = disk_cache(&tempdir.path().join("remote"), pool, CacheMode::ReadOnly);
+        let storage = TieredStorage::new(local, remote);
+        runtime.block_on(async {
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadOnly);
+            assert!(storage.put("b2", entry(b"two")).await.is_err());
+        });
+    }
+}
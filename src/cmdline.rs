@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use crate::errors::*;
-use clap::{Arg, ArgAction, ArgGroup, ValueEnum, error::ErrorKind};
+use crate::tu_stats::TuStatsFilter;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, ValueEnum, error::ErrorKind};
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use which::which_in;
 
 const ENV_VAR_INTERNAL_START_SERVER: &str = "SCCACHE_START_SERVER";
@@ -55,10 +57,21 @@ impl Default for StatsFormat {
     }
 }
 
+/// Which statistics `--show-stats` shows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatsScope {
+    /// Those of the running server.
+    Current,
+    /// Those of the compile requests tagged with a session.
+    Session(String),
+    /// Those of the running server, added to those of its previous runs.
+    Lifetime,
+}
+
 /// A specific command to run.
 pub enum Command {
     /// Show cache statistics and exit.
-    ShowStats(StatsFormat, bool),
+    ShowStats(StatsFormat, bool, StatsScope),
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
@@ -67,6 +80,8 @@ pub enum Command {
     StopServer,
     /// Zero cache statistics and exit.
     ZeroStats,
+    /// Trim the shared filesystem cache to its maximum size.
+    CacheGc,
     /// Show the status of the distributed client.
     DistStatus,
     /// Perform a login to authenticate for distributed compilation.
@@ -89,11 +104,93 @@ pub enum Command {
     TuStats {
         /// Path to the stats database file (optional)
         stats_file: Option<PathBuf>,
-        /// Export to CSV format
-        csv: bool,
+        /// Output format of the records
+        format: TuStatsFormat,
+        /// Print an aggregated summary ranking the worst N input files
+        summary: Option<usize>,
+        /// Print the N most expensive headers across all translation units
+        headers: Option<usize>,
+        /// Write a Chrome trace of the compilations to this file
+        trace: Option<PathBuf>,
+        /// Restrict the query to matching records
+        filter: TuStatsFilter,
+    },
+    /// Apply the translation unit statistics retention policy and compact the database
+    TuStatsPrune {
+        /// Path to the stats database file (optional)
+        stats_file: Option<PathBuf>,
+    },
+    /// Export the most recent include graph recorded for an input file
+    TuStatsGraph {
+        /// Path to the stats database file (optional)
+        stats_file: Option<PathBuf>,
+        /// Input file path or glob to export the include graph of
+        input_file: String,
+        /// Output format
+        format: IncludeGraphFormat,
+    },
+    /// Compare two sets of translation unit statistics
+    TuStatsDiff {
+        /// Path to the stats database file holding both sessions (optional)
+        stats_file: Option<PathBuf>,
+        /// Database file, or session when `sessions` is set, to compare from
+        before: String,
+        /// Database file, or session when `sessions` is set, to compare to
+        after: String,
+        /// Compare two sessions of one database instead of two databases
+        sessions: bool,
     },
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+pub enum IncludeGraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TuStatsFormat {
+    Text,
+    Csv,
+    Jsonl,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum TuStatsDist {
+    Local,
+    Distributed,
+}
+
+/// Parse a point in time given either as an RFC 3339 timestamp, as seconds
+/// since the UNIX epoch, or as a duration before now (e.g. `90s`, `30m`, `2h`, `7d`).
+fn parse_time(s: &str) -> Result<SystemTime> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit())
+        .filter(|&i| i > 0)
+        .with_context(|| format!("Unrecognized time: {:?}", s))?;
+    let (value, unit) = s.split_at(unit_start);
+    let value: u64 = value.parse()?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 60 * 60 * 24,
+        _ => bail!(
+            "Unrecognized time unit in {:?}, expected one of s, m, h, d",
+            s
+        ),
+    };
+    SystemTime::now()
+        .checked_sub(Duration::from_secs(secs))
+        .with_context(|| format!("Time out of range: {:?}", s))
+}
+
 fn flag_infer_long_and_short(name: &'static str) -> Arg {
     flag_infer_long(name).short(name.chars().next().expect("Name needs at least one char"))
 }
@@ -153,6 +250,9 @@ fn get_clap_command() -> clap::Command {
             flag_infer_long_and_short("zero-stats")
                 .help("zero statistics counters")
                 .action(ArgAction::SetTrue),
+            flag_infer_long("cache-gc")
+                .help("trim the shared filesystem cache to its maximum size")
+                .action(ArgAction::SetTrue),
             flag_infer_long("dist-auth")
                 .help("authenticate for distributed compilation")
                 .action(ArgAction::SetTrue),
@@ -172,13 +272,93 @@ fn get_clap_command() -> clap::Command {
             flag_infer_long("tu-stats")
                 .help("show translation unit statistics")
                 .action(ArgAction::SetTrue),
+            flag_infer_long("tu-stats-prune")
+                .help("delete translation unit statistics beyond the configured retention limits and compact the database")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("tu-stats-graph")
+                .help("export the include graph most recently recorded for input files matching GLOB")
+                .value_name("GLOB"),
+            flag_infer_long("tu-stats-diff")
+                .help("compare the translation unit statistics of two databases")
+                .value_names(["BEFORE", "AFTER"])
+                .num_args(2),
+            flag_infer_long("diff-sessions")
+                .help("compare two sessions of the translation unit statistics database instead")
+                .action(ArgAction::SetTrue)
+                .requires("tu-stats-diff"),
+            flag_infer_long("graph-format")
+                .help("set output format of the include graph")
+                .value_name("FMT")
+                .value_parser(clap::value_parser!(IncludeGraphFormat))
+                .default_value("dot")
+                .requires("tu-stats-graph"),
             flag_infer_long("tu-stats-file")
                 .help("path to translation unit statistics database")
                 .value_name("PATH")
                 .value_parser(clap::value_parser!(PathBuf)),
+            flag_infer_long("tu-stats-format")
+                .help("set output format of translation unit statistics")
+                .value_name("FMT")
+                .value_parser(clap::value_parser!(TuStatsFormat))
+                .requires("tu-stats-query"),
             flag_infer_long("tu-stats-csv")
-                .help("export translation unit statistics to CSV format")
-                .action(ArgAction::SetTrue),
+                .help("export translation unit statistics to CSV format, same as `--tu-stats-format csv`")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("tu-stats-format"),
+            flag_infer_long("tu-stats-summary")
+                .help("summarize translation unit statistics per input file, ranking the worst N")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .num_args(0..=1)
+                .default_missing_value("10")
+                .conflicts_with_all(["tu-stats-csv", "tu-stats-format"]),
+            flag_infer_long("headers")
+                .help("rank the N headers contributing the most preprocessed lines and included by the most translation units")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .num_args(0..=1)
+                .default_missing_value("10")
+                .requires("tu-stats-query")
+                .conflicts_with_all(["tu-stats-csv", "tu-stats-format", "tu-stats-summary"]),
+            flag_infer_long("trace")
+                .help("write a Chrome trace of the compilations to PATH, for Perfetto or chrome://tracing")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tu-stats-query")
+                .conflicts_with_all([
+                    "tu-stats-csv",
+                    "tu-stats-format",
+                    "tu-stats-summary",
+                    "headers",
+                ]),
+            flag_infer_long("since")
+                .help("only show translation unit statistics recorded at or after TIME (RFC 3339, UNIX seconds, or an age like 2h)")
+                .value_name("TIME")
+                .value_parser(parse_time)
+                .requires("tu-stats-query"),
+            flag_infer_long("until")
+                .help("only show translation unit statistics recorded before TIME")
+                .value_name("TIME")
+                .value_parser(parse_time)
+                .requires("tu-stats-query"),
+            flag_infer_long("file")
+                .help("only show translation unit statistics for input files matching GLOB")
+                .value_name("GLOB")
+                .requires("tu-stats-query"),
+            flag_infer_long("session")
+                .help("only show statistics of compile requests tagged with SESSION through SCCACHE_SESSION")
+                .value_name("SESSION")
+                .requires("session-stats"),
+            flag_infer_long("lifetime")
+                .help("show the statistics accumulated over every run of the server, when they are persisted")
+                .action(ArgAction::SetTrue)
+                .requires("session-stats")
+                .conflicts_with_all(["session", "tu-stats"]),
+            flag_infer_long("dist")
+                .help("only show local or distributed compilations")
+                .value_name("KIND")
+                .value_parser(clap::value_parser!(TuStatsDist))
+                .requires("tu-stats-query"),
             Arg::new("CMD")
                 .value_parser(clap::value_parser!(OsString))
                 .trailing_var_arg(true)
@@ -195,12 +375,30 @@ fn get_clap_command() -> clap::Command {
                     "start-server",
                     "stop-server",
                     "zero-stats",
+                    "cache-gc",
                     "package-toolchain",
                     "tu-stats",
+                    "tu-stats-prune",
+                    "tu-stats-graph",
+                    "tu-stats-diff",
                     "CMD",
                 ])
                 .required(true),
         )
+        .group(ArgGroup::new("session-stats").args(["show-stats", "show-adv-stats", "tu-stats"]))
+        // Requiring `--tu-stats` itself is always satisfied by its default
+        // value, unlike requiring a group
+        .group(ArgGroup::new("tu-stats-query").arg("tu-stats"))
+}
+
+fn stats_scope(matches: &ArgMatches) -> StatsScope {
+    if let Some(session) = matches.get_one::<String>("session") {
+        StatsScope::Session(session.clone())
+    } else if matches.get_flag("lifetime") {
+        StatsScope::Lifetime
+    } else {
+        StatsScope::Current
+    }
 }
 
 /// Parse the commandline args into a `Result<Command>` to execute.
@@ -284,13 +482,13 @@ pub fn try_parse() -> Result<Command> {
                     .get_one("stats-format")
                     .cloned()
                     .expect("There is a default value");
-                Ok(Command::ShowStats(fmt, false))
+                Ok(Command::ShowStats(fmt, false, stats_scope(&matches)))
             } else if matches.get_flag("show-adv-stats") {
                 let fmt = matches
                     .get_one("stats-format")
                     .cloned()
                     .expect("There is a default value");
-                Ok(Command::ShowStats(fmt, true))
+                Ok(Command::ShowStats(fmt, true, stats_scope(&matches)))
             } else if matches.get_flag("start-server") {
                 Ok(Command::StartServer)
             } else if matches.get_flag("debug-preprocessor-cache") {
@@ -299,6 +497,8 @@ pub fn try_parse() -> Result<Command> {
                 Ok(Command::StopServer)
             } else if matches.get_flag("zero-stats") {
                 Ok(Command::ZeroStats)
+            } else if matches.get_flag("cache-gc") {
+                Ok(Command::CacheGc)
             } else if matches.get_flag("dist-auth") {
                 Ok(Command::DistAuth)
             } else if matches.get_flag("dist-status") {
@@ -317,8 +517,62 @@ pub fn try_parse() -> Result<Command> {
                 }
             } else if matches.get_flag("tu-stats") {
                 let stats_file = matches.get_one::<PathBuf>("tu-stats-file").cloned();
-                let csv = matches.get_flag("tu-stats-csv");
-                Ok(Command::TuStats { stats_file, csv })
+                let format = if matches.get_flag("tu-stats-csv") {
+                    TuStatsFormat::Csv
+                } else {
+                    matches
+                        .get_one::<TuStatsFormat>("tu-stats-format")
+                        .copied()
+                        .unwrap_or(TuStatsFormat::Text)
+                };
+                let summary = matches.get_one::<usize>("tu-stats-summary").copied();
+                let headers = matches.get_one::<usize>("headers").copied();
+                let trace = matches.get_one::<PathBuf>("trace").cloned();
+                let filter = TuStatsFilter {
+                    since: matches.get_one::<SystemTime>("since").copied(),
+                    until: matches.get_one::<SystemTime>("until").copied(),
+                    file_glob: matches.get_one::<String>("file").cloned(),
+                    distributed: matches
+                        .get_one::<TuStatsDist>("dist")
+                        .map(|dist| matches!(dist, TuStatsDist::Distributed)),
+                    session: matches.get_one::<String>("session").cloned(),
+                };
+                Ok(Command::TuStats {
+                    stats_file,
+                    format,
+                    summary,
+                    headers,
+                    trace,
+                    filter,
+                })
+            } else if matches.get_flag("tu-stats-prune") {
+                let stats_file = matches.get_one::<PathBuf>("tu-stats-file").cloned();
+                Ok(Command::TuStatsPrune { stats_file })
+            } else if let Some(input_file) = matches.get_one::<String>("tu-stats-graph") {
+                let stats_file = matches.get_one::<PathBuf>("tu-stats-file").cloned();
+                // Records hold absolute paths
+                let input_file = cwd.join(input_file).to_string_lossy().into_owned();
+                let format = matches
+                    .get_one::<IncludeGraphFormat>("graph-format")
+                    .expect("There is a default value")
+                    .clone();
+                Ok(Command::TuStatsGraph {
+                    stats_file,
+                    input_file,
+                    format,
+                })
+            } else if let Some(values) = matches.get_many::<String>("tu-stats-diff") {
+                let stats_file = matches.get_one::<PathBuf>("tu-stats-file").cloned();
+                let mut values = values.cloned();
+                let (Some(before), Some(after)) = (values.next(), values.next()) else {
+                    unreachable!("clap should enforce two values")
+                };
+                Ok(Command::TuStatsDiff {
+                    stats_file,
+                    before,
+                    after,
+                    sessions: matches.get_flag("diff-sessions"),
+                })
             } else if matches.contains_id("CMD") {
                 let mut env_vars = env::vars_os().collect::<Vec<_>>();
 
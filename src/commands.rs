@@ -14,13 +14,13 @@
 
 use crate::cache::storage_from_config;
 use crate::client::{ServerConnection, connect_to_server, connect_with_retry};
-use crate::cmdline::{Command, StatsFormat};
+use crate::cmdline::{Command, StatsFormat, StatsScope};
 use crate::compiler::ColorMode;
-use crate::config::{Config, default_disk_cache_dir};
+use crate::config::{CacheType, Config, default_disk_cache_dir};
 use crate::jobserver::Client;
 use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
 use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use crate::server::{self, DistInfo, ServerInfo, ServerStartup, ServerStats};
+use crate::server::{self, DistInfo, PersistedStats, ServerInfo, ServerStartup, ServerStats};
 use crate::util::daemonize;
 use byteorder::{BigEndian, ByteOrder};
 use fs::{File, OpenOptions};
@@ -352,6 +352,134 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<()> {
     }
 }
 
+/// Send a `GetSessionStats` request to the server, and return the `ServerInfo` of the
+/// session if the server has statistics for it.
+pub fn request_session_stats(
+    mut conn: ServerConnection,
+    session: String,
+) -> Result<Option<ServerInfo>> {
+    debug!("request_session_stats");
+    let response = conn.request(Request::GetSessionStats(session)).context(
+        "Failed to send data to or receive data from server. Mismatch of client/server versions?",
+    )?;
+    if let Response::SessionStats(stats) = response {
+        Ok(stats.map(|stats| *stats))
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetLifetimeStats` request to the server, and return its lifetime
+/// `ServerInfo` if it keeps its statistics across restarts.
+pub fn request_lifetime_stats(mut conn: ServerConnection) -> Result<Option<ServerInfo>> {
+    debug!("request_lifetime_stats");
+    let response = conn.request(Request::GetLifetimeStats).context(
+        "Failed to send data to or receive data from server. Mismatch of client/server versions?",
+    )?;
+    if let Response::LifetimeStats(stats) = response {
+        Ok(stats.map(|stats| *stats))
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send `GetTuStats` requests to the server until every record matching `filter`
+/// is received. Returns `None` if the server does not record to `stats_file`.
+#[cfg(feature = "translation-unit-stats")]
+pub fn request_tu_stats(
+    mut conn: ServerConnection,
+    stats_file: Option<&Path>,
+    filter: &crate::tu_stats::TuStatsFilter,
+) -> Result<Option<Vec<crate::tu_stats::TranslationUnitStats>>> {
+    debug!("request_tu_stats");
+    // The server may run in another directory
+    let stats_file = stats_file.map(std::path::absolute).transpose()?;
+    let mut stats = Vec::new();
+    let mut after = None;
+    loop {
+        let query = crate::protocol::TuStatsQuery {
+            stats_file: stats_file.clone(),
+            filter: filter.clone(),
+            after,
+        };
+        let response = conn.request(Request::GetTuStats(query)).context(
+            "Failed to send data to or receive data from server. Mismatch of client/server versions?",
+        )?;
+        let page = match response {
+            Response::TuStats(Some(page)) => page,
+            Response::TuStats(None) => return Ok(None),
+            _ => bail!("Unexpected server response!"),
+        };
+        let records: Vec<_> = serde_json::from_slice(&page.records)
+            .context("Failed to deserialize TU stats from server")?;
+        stats.extend(records);
+        match page.next {
+            Some(next) => after = Some(next),
+            None => return Ok(Some(stats)),
+        }
+    }
+}
+
+/// Send a `PruneTuStats` request to the server. Returns the number of deleted
+/// records, or `None` if the server does not record to `stats_file`.
+#[cfg(feature = "translation-unit-stats")]
+pub fn request_prune_tu_stats(
+    mut conn: ServerConnection,
+    stats_file: Option<&Path>,
+    policy: &crate::tu_stats::RetentionPolicy,
+) -> Result<Option<u64>> {
+    debug!("request_prune_tu_stats");
+    let prune = crate::protocol::TuStatsPrune {
+        // The server may run in another directory
+        stats_file: stats_file.map(std::path::absolute).transpose()?,
+        policy: *policy,
+    };
+    let response = conn.request(Request::PruneTuStats(prune)).context(
+        "Failed to send data to or receive data from server. Mismatch of client/server versions?",
+    )?;
+    if let Response::TuStatsPruned(removed) = response {
+        Ok(removed)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Query translation unit statistics through the server if it is running and
+/// records to `stats_file`, since it keeps that database open for writing.
+/// Otherwise open the database directly.
+#[cfg(feature = "translation-unit-stats")]
+fn query_tu_stats(
+    stats_file: Option<&Path>,
+    filter: &crate::tu_stats::TuStatsFilter,
+) -> Result<Vec<crate::tu_stats::TranslationUnitStats>> {
+    match connect_to_server(&get_addr()) {
+        Ok(conn) => match request_tu_stats(conn, stats_file, filter)? {
+            Some(stats) => return Ok(stats),
+            None => debug!("Server does not record to the TU stats database, opening it"),
+        },
+        Err(e) => debug!("No server to query TU stats through: {}", e),
+    }
+    crate::tu_stats::query_stats(stats_file, filter)
+}
+
+/// Prune translation unit statistics through the server if it is running and
+/// records to `stats_file`, since it keeps that database open for writing.
+/// Otherwise open the database directly.
+#[cfg(feature = "translation-unit-stats")]
+fn prune_tu_stats(
+    stats_file: Option<&Path>,
+    policy: &crate::tu_stats::RetentionPolicy,
+) -> Result<u64> {
+    match connect_to_server(&get_addr()) {
+        Ok(conn) => match request_prune_tu_stats(conn, stats_file, policy)? {
+            Some(removed) => return Ok(removed),
+            None => debug!("Server does not record to the TU stats database, opening it"),
+        },
+        Err(e) => debug!("No server to prune TU stats through: {}", e),
+    }
+    crate::tu_stats::prune_stats(stats_file, policy)
+}
+
 /// Send a `GetStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -654,7 +782,58 @@ pub fn run_command(cmd: Command) -> Result<i32> {
     let startup_timeout = config.server_startup_timeout;
 
     match cmd {
-        Command::ShowStats(fmt, advanced) => {
+        Command::ShowStats(fmt, advanced, StatsScope::Session(session)) => {
+            trace!("Command::ShowStats({:?}, {})", fmt, session);
+            let stats = match connect_to_server(&get_addr()) {
+                Ok(srv) => request_session_stats(srv, session.clone())
+                    .context("failed to get stats from server")?,
+                // A server that is not running has no sessions
+                Err(_) => None,
+            };
+            let Some(stats) = stats else {
+                eprintln!("No statistics for session {session}");
+                return Ok(1);
+            };
+            match fmt {
+                StatsFormat::Text => {
+                    println!("Session: {session}");
+                    stats.print(advanced)
+                }
+                StatsFormat::Json => serde_json::to_writer(&mut io::stdout(), &stats)?,
+            }
+        }
+        Command::ShowStats(fmt, advanced, StatsScope::Lifetime) => {
+            trace!("Command::ShowStats({:?}, lifetime)", fmt);
+            let stats = match connect_to_server(&get_addr()) {
+                Ok(srv) => {
+                    request_lifetime_stats(srv).context("failed to get stats from server")?
+                }
+                // Without a server, the saved stats are the lifetime ones
+                Err(_) => match config.stats_file() {
+                    Some(path) => {
+                        let stats = PersistedStats::read(&path)?.unwrap_or_default();
+                        let runtime = Runtime::new()?;
+                        let storage = storage_from_config(config, runtime.handle()).ok();
+                        Some(runtime.block_on(ServerInfo::new(stats, storage.as_deref()))?)
+                    }
+                    None => None,
+                },
+            };
+            let Some(stats) = stats else {
+                eprintln!(
+                    "Statistics are not kept across server restarts, set `persist_stats` or SCCACHE_PERSIST_STATS"
+                );
+                return Ok(1);
+            };
+            match fmt {
+                StatsFormat::Text => {
+                    println!("Lifetime statistics");
+                    stats.print(advanced)
+                }
+                StatsFormat::Json => serde_json::to_writer(&mut io::stdout(), &stats)?,
+            }
+        }
+        Command::ShowStats(fmt, advanced, StatsScope::Current) => {
             trace!("Command::ShowStats({:?})", fmt);
             let stats = match connect_to_server(&get_addr()) {
                 Ok(srv) => request_stats(srv).context("failed to get stats from server")?,
@@ -729,6 +908,26 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             request_zero_stats(conn).context("couldn't zero stats on server")?;
             eprintln!("Statistics zeroed.");
         }
+        Command::CacheGc => {
+            trace!("Command::CacheGc");
+            let shared = config.caches.iter().find_map(|c| match &c.cache {
+                CacheType::Shared(shared) => Some(shared),
+                _ => None,
+            });
+            let Some(shared) = shared else {
+                eprintln!("No shared filesystem cache is configured.");
+                return Ok(1);
+            };
+            let stats = crate::cache::shared::gc(&shared.dir, shared.size, shared.gc_time)
+                .with_context(|| format!("failed to trim the cache in {:?}", shared.dir))?;
+            println!(
+                "Removed {} entries ({}) from {:?}, {} left.",
+                stats.removed,
+                server::format_size(stats.removed_size).join(" "),
+                shared.dir,
+                server::format_size(stats.size).join(" ")
+            );
+        }
         #[cfg(feature = "dist-client")]
         Command::DistAuth => {
             use crate::config;
@@ -856,22 +1055,161 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             );
             return res.context("failed to execute compile");
         }
-        Command::TuStats { stats_file, csv } => {
+        Command::TuStats {
+            stats_file,
+            format,
+            summary,
+            headers,
+            trace,
+            filter,
+        } => {
             trace!("Command::TuStats");
             #[cfg(feature = "translation-unit-stats")]
             {
-                let stats = crate::tu_stats::query_stats(stats_file.as_deref())
+                let stats_file =
+                    stats_file.or_else(|| config.translation_unit_stats.stats_file.clone());
+                let stats = query_tu_stats(stats_file.as_deref(), &filter)
                     .context("failed to query translation unit statistics")?;
 
-                if csv {
-                    print!("{}", crate::tu_stats::export_to_csv(&stats));
+                if let Some(top_n) = summary {
+                    crate::tu_stats::print_summary(&stats, top_n);
+                } else if let Some(top_n) = headers {
+                    crate::tu_stats::print_headers(&stats, top_n);
+                } else if let Some(trace) = trace {
+                    let file = File::create(&trace)
+                        .with_context(|| format!("failed to create {}", trace.display()))?;
+                    serde_json::to_writer(
+                        io::BufWriter::new(file),
+                        &crate::tu_stats::export_to_chrome_trace(&stats),
+                    )
+                    .with_context(|| format!("failed to write {}", trace.display()))?;
+                    println!(
+                        "Wrote a trace of {} compilations to {}",
+                        stats.len(),
+                        trace.display()
+                    );
                 } else {
-                    crate::tu_stats::print_stats(&stats);
+                    use crate::cmdline::TuStatsFormat;
+                    match format {
+                        TuStatsFormat::Text => crate::tu_stats::print_stats(&stats),
+                        TuStatsFormat::Csv => print!("{}", crate::tu_stats::export_to_csv(&stats)),
+                        TuStatsFormat::Jsonl => {
+                            print!("{}", crate::tu_stats::export_to_jsonl(&stats)?)
+                        }
+                    }
+                }
+            }
+            #[cfg(not(feature = "translation-unit-stats"))]
+            {
+                let _ = (stats_file, format, summary, headers, trace, filter);
+                eprintln!("Translation unit statistics feature is not enabled.");
+                eprintln!("Please rebuild sccache with --features translation-unit-stats");
+                return Ok(1);
+            }
+        }
+        Command::TuStatsPrune { stats_file } => {
+            trace!("Command::TuStatsPrune");
+            #[cfg(feature = "translation-unit-stats")]
+            {
+                let stats_file =
+                    stats_file.or_else(|| config.translation_unit_stats.stats_file.clone());
+                let policy =
+                    crate::tu_stats::RetentionPolicy::from_config(&config.translation_unit_stats);
+                let removed = prune_tu_stats(stats_file.as_deref(), &policy)
+                    .context("failed to prune translation unit statistics")?;
+                println!("Pruned {} translation unit statistics records.", removed);
+            }
+            #[cfg(not(feature = "translation-unit-stats"))]
+            {
+                let _ = stats_file;
+                eprintln!("Translation unit statistics feature is not enabled.");
+                eprintln!("Please rebuild sccache with --features translation-unit-stats");
+                return Ok(1);
+            }
+        }
+        Command::TuStatsGraph {
+            stats_file,
+            input_file,
+            format,
+        } => {
+            trace!("Command::TuStatsGraph");
+            #[cfg(feature = "translation-unit-stats")]
+            {
+                let filter = crate::tu_stats::TuStatsFilter {
+                    file_glob: Some(input_file.clone()),
+                    ..Default::default()
+                };
+                let stats_file =
+                    stats_file.or_else(|| config.translation_unit_stats.stats_file.clone());
+                let stats = query_tu_stats(stats_file.as_deref(), &filter)
+                    .context("failed to query translation unit statistics")?;
+
+                // Records are sorted by time, use the most recent one
+                let Some(tree) = stats.iter().rev().find_map(|s| s.include_tree.as_ref()) else {
+                    eprintln!("No include graph recorded for {}", input_file);
+                    eprintln!(
+                        "Set `include_graph = true` in the [translation_unit_stats] configuration to record them."
+                    );
+                    return Ok(1);
+                };
+                use crate::cmdline::IncludeGraphFormat;
+                match format {
+                    IncludeGraphFormat::Dot => {
+                        print!("{}", crate::tu_stats::include_graph_to_dot(tree))
+                    }
+                    IncludeGraphFormat::Json => println!("{}", serde_json::to_string_pretty(tree)?),
                 }
             }
             #[cfg(not(feature = "translation-unit-stats"))]
             {
-                let _ = (stats_file, csv);
+                let _ = (stats_file, input_file, format);
+                eprintln!("Translation unit statistics feature is not enabled.");
+                eprintln!("Please rebuild sccache with --features translation-unit-stats");
+                return Ok(1);
+            }
+        }
+        Command::TuStatsDiff {
+            stats_file,
+            before,
+            after,
+            sessions,
+        } => {
+            trace!("Command::TuStatsDiff");
+            #[cfg(feature = "translation-unit-stats")]
+            {
+                use crate::tu_stats::TuStatsFilter;
+
+                let stats_file =
+                    stats_file.or_else(|| config.translation_unit_stats.stats_file.clone());
+                let query = |side: &str| {
+                    let stats = if sessions {
+                        let filter = TuStatsFilter {
+                            session: Some(side.to_owned()),
+                            ..Default::default()
+                        };
+                        query_tu_stats(stats_file.as_deref(), &filter)
+                    } else {
+                        // Opening a missing database would create an empty one
+                        let path = Path::new(side);
+                        if !path.exists() {
+                            bail!("{} does not exist", path.display());
+                        }
+                        query_tu_stats(Some(path), &TuStatsFilter::default())
+                    };
+                    stats.with_context(|| {
+                        format!("failed to query translation unit statistics of {}", side)
+                    })
+                };
+                let before_stats = query(&before)?;
+                let after_stats = query(&after)?;
+                println!("Before: {} ({} records)", before, before_stats.len());
+                println!("After:  {} ({} records)", after, after_stats.len());
+                println!();
+                crate::tu_stats::print_diff(&before_stats, &after_stats, 10);
+            }
+            #[cfg(not(feature = "translation-unit-stats"))]
+            {
+                let _ = (stats_file, before, after, sessions);
                 eprintln!("Translation unit statistics feature is not enabled.");
                 eprintln!("Please rebuild sccache with --features translation-unit-stats");
                 return Ok(1);
@@ -24,6 +24,7 @@ use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
+use crate::tu_stats::TuStatsContext;
 use crate::util::{
     Digest, HashToDigest, MetadataCtimeExt, TimeMacroFinder, Timestamp, decode_path, encode_path,
     hash_all,
@@ -131,16 +132,6 @@ impl ParsedArguments {
     }
 }
 
-/// Context for collecting translation unit statistics
-#[derive(Debug, Clone, Default)]
-struct TuStatsContext {
-    preprocessed_size: usize,
-    num_includes: usize,
-    preprocess_duration: std::time::Duration,
-    top_includes_by_count: Vec<crate::tu_stats::IncludeStats>,
-    top_includes_by_size: Vec<crate::tu_stats::IncludeStats>,
-}
-
 /// A generic implementation of the `Compilation` trait for C/C++ compilers.
 struct CCompilation<I: CCompilerImpl> {
     parsed_args: ParsedArguments,
@@ -188,6 +179,12 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + Sync + 'static {
     fn plusplus(&self) -> bool;
     /// Return the compiler version reported by the compiler executable.
     fn version(&self) -> Option<String>;
+    /// Return the prefix of the `-showIncludes` notes printed while
+    /// preprocessing, for compilers that support them.
+    #[cfg(feature = "translation-unit-stats")]
+    fn show_includes_prefix(&self) -> Option<&str> {
+        None
+    }
     /// Determine whether `arguments` are supported by this compiler.
     fn parse_arguments(
         &self,
@@ -506,6 +503,12 @@ where
                                 self.executable.to_string_lossy(),
                                 self.executable_digest
                             );
+                            // Preprocessing was skipped, only the input is known
+                            let tu_stats_context =
+                                crate::tu_stats::recording_enabled().then(|| TuStatsContext {
+                                    input_file: cwd.join(&self.parsed_args.input),
+                                    ..Default::default()
+                                });
                             return Ok(HashResult {
                                 key,
                                 compilation: Box::new(CCompilation {
@@ -518,7 +521,7 @@ where
                                     compiler: self.compiler.to_owned(),
                                     cwd: cwd.to_owned(),
                                     env_vars: env_vars.to_owned(),
-                                    tu_stats_context: None,
+                                    tu_stats_context,
                                 }),
                                 weak_toolchain_key,
                             });
@@ -631,7 +634,10 @@ where
             )
         };
 
-        // Get the number of includes before moving include_files
+        // Get the number of includes before moving include_files. With TU stats,
+        // they are counted from the preprocessor output instead, as the files are
+        // only known here in preprocessor cache mode.
+        #[cfg(not(feature = "translation-unit-stats"))]
         let num_includes = include_files.len();
 
         // Cache the preprocessing step
@@ -663,21 +669,131 @@ where
             self.executable_digest
         );
 
-        // Collect translation unit statistics context (will be recorded later if enabled)
+        // Collect translation unit statistics context, if they are recorded
+        // or checked against budgets
+        let input_file = cwd.join(&self.parsed_args.input);
+
+        #[cfg(feature = "translation-unit-stats")]
+        let budgets = crate::tu_stats::budgets();
         #[cfg(feature = "translation-unit-stats")]
-        let (top_includes_by_count, top_includes_by_size) =
-            analyze_include_contributions(&preprocessor_result.stdout, &cwd);
+        let tu_stats = if crate::tu_stats::recording_enabled() || !budgets.is_empty() {
+            let grouping = crate::tu_stats::include_grouping();
+            let per_header = crate::tu_stats::per_header_enabled();
+            let include_graph = crate::tu_stats::include_graph_enabled();
+            let (by_count, by_size, headers) = analyze_include_contributions(
+                &preprocessor_result.stdout,
+                &cwd,
+                &grouping,
+                per_header.then_some(input_file.as_path()),
+            );
+            let (mut by_count, mut by_size, headers, include_tree, num_includes) =
+                match self.compiler.show_includes_prefix() {
+                    // Preprocessed without line markers, fall back to `/showIncludes`
+                    Some(prefix) if by_count.is_empty() => {
+                        if budgets.has_prefixes() {
+                            static WARNED: std::sync::Once = std::sync::Once::new();
+                            WARNED.call_once(|| {
+                                warn!(
+                                    "Prefix budgets need line markers, they are not checked for {}",
+                                    input_file.display()
+                                )
+                            });
+                        }
+                        let (by_count, headers, include_tree, num_includes) = analyze_show_includes(
+                            &preprocessor_result.stderr,
+                            prefix,
+                            &cwd,
+                            &input_file,
+                            &grouping,
+                            per_header,
+                            include_graph,
+                        );
+                        (by_count, Vec::new(), headers, include_tree, num_includes)
+                    }
+                    _ => {
+                        let include_tree = include_graph
+                            .then(|| build_include_tree(&preprocessor_result.stdout, &cwd))
+                            .flatten();
+                        let num_includes =
+                            count_included_files(&preprocessor_result.stdout, &cwd, &input_file);
+                        (by_count, by_size, headers, include_tree, num_includes)
+                    }
+                };
 
-        #[cfg(not(feature = "translation-unit-stats"))]
-        let (top_includes_by_count, top_includes_by_size) = (Vec::new(), Vec::new());
+            // Prefix budgets are checked against every include file rather
+            // than the buckets, which may not go as deep as the prefixes
+            let include_files = if budgets.has_prefixes() && !by_size.is_empty() {
+                let ungrouped = crate::tu_stats::IncludeGrouping::ungrouped();
+                analyze_include_contributions(&preprocessor_result.stdout, &cwd, &ungrouped, None).1
+            } else {
+                Vec::new()
+            };
+            let violations = budgets.check(
+                preprocessor_result.stdout.len(),
+                num_includes,
+                &include_files,
+            );
+            by_count.truncate(10);
+            by_size.truncate(10);
+            if budgets.fail() && !violations.is_empty() {
+                let stderr = violations
+                    .iter()
+                    .map(|v| format!("sccache: error: {}: {}\n", input_file.display(), v))
+                    .collect::<String>();
+                bail!(ProcessError(process::Output {
+                    status: crate::mock_command::exit_status(1),
+                    stdout: vec![],
+                    stderr: stderr.into_bytes(),
+                }));
+            }
+            let budget_warnings = violations
+                .iter()
+                .map(|v| format!("sccache: warning: {}: {}", input_file.display(), v))
+                .collect();
+            Some((
+                by_count,
+                by_size,
+                headers,
+                include_tree,
+                budget_warnings,
+                num_includes,
+            ))
+        } else {
+            None
+        };
 
-        let tu_stats_context = Some(TuStatsContext {
-            preprocessed_size: preprocessor_result.stdout.len(),
+        #[cfg(not(feature = "translation-unit-stats"))]
+        let tu_stats = Some((
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            None,
+            Vec::new(),
             num_includes,
-            preprocess_duration,
-            top_includes_by_count,
-            top_includes_by_size,
-        });
+        ));
+
+        let preprocessed_size = preprocessor_result.stdout.len();
+        let tu_stats_context = tu_stats.map(
+            |(
+                top_includes_by_count,
+                top_includes_by_size,
+                headers,
+                include_tree,
+                budget_warnings,
+                num_includes,
+            )| TuStatsContext {
+                input_file,
+                preprocessed_size,
+                num_includes,
+                preprocess_duration,
+                top_includes_by_count,
+                top_includes_by_size,
+                headers,
+                include_tree,
+                rust_crate: None,
+                budget_warnings,
+            },
+        );
 
         Ok(HashResult {
             key,
@@ -700,6 +816,10 @@ where
         self.parsed_args.color_mode
     }
 
+    fn tu_stats_input_file(&self, cwd: &Path) -> Option<PathBuf> {
+        Some(cwd.join(&self.parsed_args.input))
+    }
+
     fn output_pretty(&self) -> Cow<'_, str> {
         self.parsed_args.output_pretty()
     }
@@ -718,54 +838,61 @@ const HASH_31_COMMAND_LINE_NEWLINE: &[u8] = b"# 31 \"<command-line>\"\n";
 const HASH_32_COMMAND_LINE_2_NEWLINE: &[u8] = b"# 32 \"<command-line>\" 2\n";
 const INCBIN_DIRECTIVE: &[u8] = b".incbin";
 
-/// Analyze preprocessed output to extract include statistics
+/// Analyze preprocessed output to extract include statistics.
+///
+/// When `per_header_input` is set to the path of the input file, the lines
+/// contributed by every other file are also returned, largest first.
 #[cfg(feature = "translation-unit-stats")]
 fn analyze_include_contributions(
     preprocessed_output: &[u8],
     cwd: &Path,
-) -> (Vec<crate::tu_stats::IncludeStats>, Vec<crate::tu_stats::IncludeStats>) {
+    grouping: &crate::tu_stats::IncludeGrouping,
+    per_header_input: Option<&Path>,
+) -> (
+    Vec<crate::tu_stats::IncludeStats>,
+    Vec<crate::tu_stats::IncludeStats>,
+    Vec<crate::tu_stats::HeaderStats>,
+) {
     use std::collections::HashMap;
 
     // Track each include file's contribution
     // Map from path prefix -> (count, total lines)
     let mut prefix_stats: HashMap<String, (usize, usize)> = HashMap::new();
+    // Map from absolute header path -> total lines
+    let mut header_lines: HashMap<String, usize> = HashMap::new();
+
+    let mut record_contribution = |file: &str, lines: usize| {
+        let prefix = extract_path_prefix(file, cwd, grouping);
+        let entry = prefix_stats.entry(prefix).or_insert((0, 0));
+        entry.0 += 1; // count
+        entry.1 += lines; // lines
+
+        if let Some(input_file) = per_header_input {
+            let path = absolute_include_path(file, cwd);
+            if Path::new(&path) != input_file {
+                *header_lines.entry(path).or_insert(0) += lines;
+            }
+        }
+    };
 
     let mut current_file: Option<String> = None;
     let mut current_file_lines = 0;
 
     for line in preprocessed_output.split(|&b| b == b'\n') {
-        // Check if this is a preprocessor line marker
-        if line.starts_with(b"# ") && line.len() > 3 {
-            // Try to parse: # <line_number> "<file_path>" [flags]
-            let mut parts = line[2..].splitn(2, |&b| b == b' ');
-            if let (Some(line_num), Some(rest)) = (parts.next(), parts.next()) {
-                // Check if line_num is a number
-                if line_num.iter().all(|&b| b >= b'0' && b <= b'9') {
-                    // Extract the file path between quotes
-                    if let Some(quote_start) = rest.iter().position(|&b| b == b'"') {
-                        if let Some(quote_end) = rest[quote_start + 1..].iter().position(|&b| b == b'"') {
-                            let file_path = &rest[quote_start + 1..quote_start + 1 + quote_end];
-
-                            // Record the previous file's contribution
-                            if let Some(prev_file) = current_file.take() {
-                                if current_file_lines > 0 {
-                                    let prefix = extract_path_prefix(&prev_file, cwd);
-                                    let entry = prefix_stats.entry(prefix).or_insert((0, 0));
-                                    entry.0 += 1; // count
-                                    entry.1 += current_file_lines; // lines
-                                }
-                            }
-
-                            // Start tracking the new file
-                            if let Ok(path_str) = std::str::from_utf8(file_path) {
-                                // Skip built-in and command-line pseudo-files
-                                if !path_str.starts_with('<') {
-                                    current_file = Some(path_str.to_string());
-                                    current_file_lines = 0;
-                                }
-                            }
-                        }
-                    }
+        if let Some((file_path, _flags)) = parse_line_marker(line) {
+            // Record the previous file's contribution
+            if let Some(prev_file) = current_file.take() {
+                if current_file_lines > 0 {
+                    record_contribution(&prev_file, current_file_lines);
+                }
+            }
+
+            // Start tracking the new file
+            if let Ok(path_str) = std::str::from_utf8(&file_path) {
+                // Skip built-in and command-line pseudo-files
+                if !path_str.starts_with('<') {
+                    current_file = Some(path_str.to_string());
+                    current_file_lines = 0;
                 }
             }
         } else if current_file.is_some() {
@@ -777,10 +904,7 @@ fn analyze_include_contributions(
     // Record the last file's contribution
     if let Some(prev_file) = current_file {
         if current_file_lines > 0 {
-            let prefix = extract_path_prefix(&prev_file, cwd);
-            let entry = prefix_stats.entry(prefix).or_insert((0, 0));
-            entry.0 += 1;
-            entry.1 += current_file_lines;
+            record_contribution(&prev_file, current_file_lines);
         }
     }
 
@@ -794,22 +918,274 @@ fn analyze_include_contributions(
         })
         .collect();
 
-    // Sort by count (descending) and take top 10
+    // Sort by count (descending)
     let mut by_count = stats.clone();
-    by_count.sort_by(|a, b| b.count.cmp(&a.count));
+    by_count.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.path_prefix.cmp(&b.path_prefix))
+    });
+
+    // Sort by lines (descending)
+    stats.sort_by(|a, b| {
+        b.lines
+            .cmp(&a.lines)
+            .then_with(|| a.path_prefix.cmp(&b.path_prefix))
+    });
+
+    let mut headers: Vec<crate::tu_stats::HeaderStats> = header_lines
+        .into_iter()
+        .map(|(path, lines)| crate::tu_stats::HeaderStats { path, lines })
+        .collect();
+    headers.sort_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.path.cmp(&b.path)));
+
+    (by_count, stats, headers)
+}
+
+/// Count the distinct files included by `input_file`, from the line markers of its
+/// preprocessed output.
+#[cfg(feature = "translation-unit-stats")]
+fn count_included_files(preprocessed_output: &[u8], cwd: &Path, input_file: &Path) -> usize {
+    let mut files = HashSet::new();
+    for line in preprocessed_output.split(|&b| b == b'\n') {
+        let Some((file_path, _flags)) = parse_line_marker(line) else {
+            continue;
+        };
+        // Skip built-in and command-line pseudo-files
+        if let Ok(path) = std::str::from_utf8(&file_path) {
+            if !path.starts_with('<') {
+                files.insert(absolute_include_path(path, cwd));
+            }
+        }
+    }
+    files.remove(&*input_file.to_string_lossy());
+    files.len()
+}
+
+/// Parse a preprocessor line marker, returning the file path and whatever
+/// follows it. Both the GCC/clang form `# <line_number> "<file_path>" [flags]`
+/// and the MSVC form `#line <line_number> "<file_path>"` are recognized.
+///
+/// Backslashes are unescaped and turned into forward slashes, so that Windows
+/// paths are bucketed like any other.
+#[cfg(feature = "translation-unit-stats")]
+fn parse_line_marker(line: &[u8]) -> Option<(Cow<'_, [u8]>, &[u8])> {
+    let marker = line
+        .strip_prefix(b"#line ")
+        .or_else(|| line.strip_prefix(b"# "))?;
+    let mut parts = marker.splitn(2, |&b| b == b' ');
+    let (line_num, rest) = (parts.next()?, parts.next()?);
+    if line_num.is_empty() || !line_num.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let quote_start = rest.iter().position(|&b| b == b'"')?;
+    let path_and_flags = &rest[quote_start + 1..];
+    let quote_end = path_and_flags.iter().position(|&b| b == b'"')?;
+    let path = &path_and_flags[..quote_end];
+
+    let path = if path.contains(&b'\\') {
+        let mut normalized = Vec::with_capacity(path.len());
+        let mut bytes = path.iter();
+        while let Some(&b) = bytes.next() {
+            if b == b'\\' {
+                // `\\` is an escaped backslash, a lone one a separator as is
+                if bytes.as_slice().first() == Some(&b'\\') {
+                    bytes.next();
+                }
+                normalized.push(b'/');
+            } else {
+                normalized.push(b);
+            }
+        }
+        Cow::Owned(normalized)
+    } else {
+        Cow::Borrowed(path)
+    };
+    Some((path, &path_and_flags[quote_end + 1..]))
+}
+
+/// Make an include path absolute against `cwd`. Windows paths with a drive
+/// letter are recognized as absolute on every platform.
+#[cfg(feature = "translation-unit-stats")]
+fn absolute_include_path(path: &str, cwd: &Path) -> String {
+    let bytes = path.as_bytes();
+    let has_drive_letter = bytes.len() > 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'/' | b'\\');
+    if has_drive_letter || Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        cwd.join(path).to_string_lossy().into_owned()
+    }
+}
+
+/// Attach the file on top of an include stack to the file including it
+#[cfg(feature = "translation-unit-stats")]
+fn pop_include_node(stack: &mut Vec<crate::tu_stats::IncludeNode>) {
+    if let Some(node) = stack.pop() {
+        if let Some(parent) = stack.last_mut() {
+            parent.children.push(node);
+        }
+    }
+}
+
+/// Rebuild the include tree of a translation unit from the push (`1`) and
+/// pop (`2`) flags of the line markers in its preprocessed output. Markers
+/// without flags, like MSVC's `#line`, return to the file if it is already
+/// being read and enter it otherwise.
+#[cfg(feature = "translation-unit-stats")]
+fn build_include_tree(
+    preprocessed_output: &[u8],
+    cwd: &Path,
+) -> Option<crate::tu_stats::IncludeNode> {
+    use crate::tu_stats::IncludeNode;
+
+    // The root is the input file, the top of the stack the file being read
+    let mut stack: Vec<IncludeNode> = Vec::new();
+    // Whether output lines currently come from the top of the stack rather
+    // than from a pseudo-file like `<built-in>`
+    let mut in_top = false;
+
+    for line in preprocessed_output.split(|&b| b == b'\n') {
+        let Some((file_path, flags)) = parse_line_marker(line) else {
+            if in_top {
+                if let Some(node) = stack.last_mut() {
+                    node.lines += 1;
+                }
+            }
+            continue;
+        };
+
+        let path = match std::str::from_utf8(&file_path) {
+            Ok(path) if !path.starts_with('<') => Some(absolute_include_path(path, cwd)),
+            _ => None,
+        };
+        let flag = flags.split(|&b| b == b' ').find(|f| !f.is_empty());
+        let on_stack = stack.iter().any(|n| Some(&n.path) == path.as_ref());
+
+        match flag {
+            _ if stack.is_empty() => stack.extend(path.clone().map(IncludeNode::new)),
+            Some(b"1") => stack.extend(path.clone().map(IncludeNode::new)),
+            Some(b"2") => {
+                // Returning to a pseudo-file unwinds everything up to the root
+                while stack.len() > 1 && stack.last().map(|n| &n.path) != path.as_ref() {
+                    pop_include_node(&mut stack);
+                }
+            }
+            None if on_stack => {
+                while stack.last().map(|n| &n.path) != path.as_ref() {
+                    pop_include_node(&mut stack);
+                }
+            }
+            None => stack.extend(path.clone().map(IncludeNode::new)),
+            _ => {}
+        }
+
+        in_top = path.is_some() && stack.last().map(|n| &n.path) == path.as_ref();
+    }
+
+    while stack.len() > 1 {
+        pop_include_node(&mut stack);
+    }
+    stack.pop()
+}
+
+/// Build the include statistics of a translation unit from the `/showIncludes`
+/// notes MSVC and clang-cl print while preprocessing, for output without line
+/// markers (`-EP`). The notes do not tell how many lines each file
+/// contributed, so only include counts and the include tree are known, along
+/// with the number of distinct files included.
+#[cfg(feature = "translation-unit-stats")]
+fn analyze_show_includes(
+    stderr: &[u8],
+    includes_prefix: &str,
+    cwd: &Path,
+    input_file: &Path,
+    grouping: &crate::tu_stats::IncludeGrouping,
+    per_header: bool,
+    include_graph: bool,
+) -> (
+    Vec<crate::tu_stats::IncludeStats>,
+    Vec<crate::tu_stats::HeaderStats>,
+    Option<crate::tu_stats::IncludeNode>,
+    usize,
+) {
+    use crate::tu_stats::{HeaderStats, IncludeNode, IncludeStats};
+    use std::collections::{BTreeSet, HashMap};
+
+    let mut prefix_counts: HashMap<String, usize> = HashMap::new();
+    let mut headers = BTreeSet::new();
+    let mut files = HashSet::new();
+    let mut stack = vec![IncludeNode::new(input_file.to_string_lossy().into_owned())];
+
+    // The notes are printed in the local codepage, like all of MSVC's output
+    let stderr = crate::compiler::msvc::from_local_codepage(stderr)
+        .unwrap_or_else(|_| String::from_utf8_lossy(stderr).into_owned());
+    for line in stderr.lines() {
+        let Some(rest) = line.strip_prefix(includes_prefix) else {
+            continue;
+        };
+        let path = rest.trim_start_matches(' ');
+        // Each nesting level adds one more space after the prefix
+        let depth = rest.len() - path.len() + 1;
+        let path = absolute_include_path(&path.trim_end().replace('\\', "/"), cwd);
+
+        *prefix_counts
+            .entry(extract_path_prefix(&path, cwd, grouping))
+            .or_insert(0) += 1;
+        if include_graph {
+            while stack.len() > depth {
+                pop_include_node(&mut stack);
+            }
+            stack.push(IncludeNode::new(path.clone()));
+        }
+        if per_header {
+            headers.insert(path.clone());
+        }
+        files.insert(path);
+    }
+
+    let mut by_count: Vec<IncludeStats> = prefix_counts
+        .into_iter()
+        .map(|(path_prefix, count)| IncludeStats {
+            path_prefix,
+            count,
+            lines: 0,
+        })
+        .collect();
+    by_count.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.path_prefix.cmp(&b.path_prefix))
+    });
     by_count.truncate(10);
 
-    // Sort by lines (descending) and take top 10
-    stats.sort_by(|a, b| b.lines.cmp(&a.lines));
-    stats.truncate(10);
+    let headers = headers
+        .into_iter()
+        .map(|path| HeaderStats { path, lines: 0 })
+        .collect();
+
+    let include_tree = include_graph.then(|| {
+        while stack.len() > 1 {
+            pop_include_node(&mut stack);
+        }
+        stack.remove(0)
+    });
 
-    (by_count, stats)
+    (by_count, headers, include_tree, files.len())
 }
 
 /// Extract a meaningful path prefix from an absolute path
-/// Returns the first 2-3 path components after finding a likely project root
+/// Returns the bucket name of the first matching grouping rule, or the first
+/// few path components (3 by default) after finding a likely project root
 #[cfg(feature = "translation-unit-stats")]
-fn extract_path_prefix(file_path: &str, cwd: &Path) -> String {
+fn extract_path_prefix(
+    file_path: &str,
+    cwd: &Path,
+    grouping: &crate::tu_stats::IncludeGrouping,
+) -> String {
+    use crate::tu_stats::IncludeBucket;
     use std::path::Path;
 
     let path = Path::new(file_path);
@@ -821,7 +1197,12 @@ fn extract_path_prefix(file_path: &str, cwd: &Path) -> String {
         path
     };
 
-    // Get the first 2-3 components
+    let depth = match grouping.bucket(&relative_path.to_string_lossy()) {
+        IncludeBucket::Named(name) => return name.to_owned(),
+        IncludeBucket::Depth(depth) => depth,
+    };
+
+    // Get the first `depth` components
     let components: Vec<_> = relative_path
         .components()
         .filter_map(|c| {
@@ -831,7 +1212,7 @@ fn extract_path_prefix(file_path: &str, cwd: &Path) -> String {
                 None
             }
         })
-        .take(3)
+        .take(depth)
         .collect();
 
     if components.is_empty() {
@@ -1392,18 +1773,8 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compilation<T> for CCompilation<I>
         )
     }
 
-    fn tu_stats_context(&self) -> Option<(PathBuf, usize, usize, std::time::Duration, Vec<crate::tu_stats::IncludeStats>, Vec<crate::tu_stats::IncludeStats>)> {
-        self.tu_stats_context.as_ref().map(|ctx| {
-            let input_file = self.cwd.join(&self.parsed_args.input);
-            (
-                input_file,
-                ctx.preprocessed_size,
-                ctx.num_includes,
-                ctx.preprocess_duration,
-                ctx.top_includes_by_count.clone(),
-                ctx.top_includes_by_size.clone(),
-            )
-        })
+    fn tu_stats_context(&self) -> Option<TuStatsContext> {
+        self.tu_stats_context.clone()
     }
 }
 
@@ -2220,4 +2591,237 @@ mod test {
             "a93900c371d997927c5bc568ea538bed59ae5c960021dcfe7b0b369da5267528",
         );
     }
+
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_analyze_include_contributions_ungrouped() {
+        let preprocessed = b"# 1 \"a.cpp\"\n\
+# 1 \"/src/third_party/boost/asio/detail/socket.hpp\" 1\n\
+int socket();\n\
+# 2 \"a.cpp\" 2\n\
+int main() {}\n";
+        let cwd = Path::new("/src");
+
+        let (_, by_size, _) = analyze_include_contributions(
+            preprocessed,
+            cwd,
+            &crate::tu_stats::IncludeGrouping::default(),
+            None,
+        );
+        assert!(
+            by_size
+                .iter()
+                .any(|inc| inc.path_prefix == "third_party/boost/asio")
+        );
+        // For the budgets, every file is kept whole
+        let (_, by_size, _) = analyze_include_contributions(
+            preprocessed,
+            cwd,
+            &crate::tu_stats::IncludeGrouping::ungrouped(),
+            None,
+        );
+        assert!(
+            by_size
+                .iter()
+                .any(|inc| inc.path_prefix == "third_party/boost/asio/detail/socket.hpp")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_analyze_include_contributions_per_header() {
+        let preprocessed = b"# 1 \"a.cpp\"\n\
+# 1 \"<built-in>\"\n\
+# 1 \"/usr/include/stdio.h\" 1 3 4\n\
+int printf();\n\
+int puts();\n\
+# 2 \"a.cpp\" 2\n\
+# 1 \"include/a.h\" 1\n\
+struct A {};\n\
+# 3 \"a.cpp\" 2\n\
+int main() {}\n";
+        let cwd = Path::new("/src");
+
+        let grouping = crate::tu_stats::IncludeGrouping::default();
+
+        let (_, by_size, headers) = analyze_include_contributions(
+            preprocessed,
+            cwd,
+            &grouping,
+            Some(Path::new("/src/a.cpp")),
+        );
+        // Ties are broken by path, so that the order is stable
+        let by_size: Vec<_> = by_size
+            .iter()
+            .map(|inc| (inc.path_prefix.as_str(), inc.lines))
+            .collect();
+        assert_eq!(
+            by_size,
+            [("a.cpp", 2), ("usr/include/stdio.h", 2), ("include/a.h", 1)]
+        );
+        assert_eq!(
+            headers,
+            vec![
+                crate::tu_stats::HeaderStats {
+                    path: "/usr/include/stdio.h".to_owned(),
+                    lines: 2,
+                },
+                crate::tu_stats::HeaderStats {
+                    path: "/src/include/a.h".to_owned(),
+                    lines: 1,
+                },
+            ]
+        );
+
+        let (_, _, headers) = analyze_include_contributions(preprocessed, cwd, &grouping, None);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_build_include_tree() {
+        use crate::tu_stats::IncludeNode;
+
+        let preprocessed = b"# 0 \"a.cpp\"\n\
+# 0 \"<built-in>\"\n\
+# 0 \"<command-line>\"\n\
+# 1 \"/usr/include/stdc-predef.h\" 1 3 4\n\
+# 0 \"<command-line>\" 2\n\
+# 1 \"a.cpp\"\n\
+# 1 \"a.h\" 1\n\
+# 1 \"/usr/include/stdio.h\" 1 3 4\n\
+int printf();\n\
+# 2 \"a.h\" 2\n\
+struct A {};\n\
+# 2 \"a.cpp\" 2\n\
+int main() {}";
+        let node = |path: &str, lines, children| IncludeNode {
+            path: path.to_owned(),
+            lines,
+            children,
+        };
+
+        assert_eq!(
+            build_include_tree(preprocessed, Path::new("/src")),
+            Some(node(
+                "/src/a.cpp",
+                1,
+                vec![
+                    node("/usr/include/stdc-predef.h", 0, vec![]),
+                    node("/src/a.h", 1, vec![node("/usr/include/stdio.h", 1, vec![])]),
+                ]
+            ))
+        );
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    fn read_fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(file!())
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap();
+        std::fs::read(path.join("tests").join(name)).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_analyze_msvc_line_markers() {
+        use crate::tu_stats::{HeaderStats, IncludeGrouping, IncludeNode};
+
+        const STDIO_H: &str =
+            "C:/Program Files (x86)/Windows Kits/10/Include/10.0.22621.0/ucrt/stdio.h";
+        let preprocessed = read_fixture("test.c.msvc-preproc");
+        let cwd = Path::new("C:/src");
+        let input_file = Path::new("C:/src/test.c");
+
+        let (_, by_size, headers) = analyze_include_contributions(
+            &preprocessed,
+            cwd,
+            &IncludeGrouping::default(),
+            Some(input_file),
+        );
+        let util = by_size
+            .iter()
+            .find(|inc| inc.path_prefix == "include/util.h")
+            .unwrap();
+        assert_eq!(util.lines, 2);
+        assert_eq!(
+            headers,
+            vec![
+                HeaderStats {
+                    path: STDIO_H.to_owned(),
+                    lines: 3,
+                },
+                HeaderStats {
+                    path: "C:/src/include/util.h".to_owned(),
+                    lines: 2,
+                },
+            ]
+        );
+
+        let tree = build_include_tree(&preprocessed, cwd).unwrap();
+        assert_eq!(tree.path, "C:/src/test.c");
+        assert_eq!(tree.children.len(), 1);
+        let util = &tree.children[0];
+        assert_eq!(util.path, "C:/src/include/util.h");
+        assert_eq!(util.lines, 2);
+        assert_eq!(
+            util.children,
+            vec![IncludeNode {
+                path: STDIO_H.to_owned(),
+                lines: 3,
+                children: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_analyze_show_includes() {
+        use crate::tu_stats::IncludeGrouping;
+
+        const UCRT: &str = "C:/Program Files (x86)/Windows Kits/10/Include/10.0.22621.0/ucrt";
+        let stderr = read_fixture("test.c.msvc-showincludes");
+        let cwd = Path::new("C:/src");
+        let grouping = IncludeGrouping::new(&[crate::config::IncludeGroupRule {
+            glob: Some("**/Windows Kits/**".to_owned()),
+            name: Some("windows-sdk".to_owned()),
+            ..Default::default()
+        }])
+        .unwrap();
+
+        let (by_count, headers, tree, num_includes) = analyze_show_includes(
+            &stderr,
+            "Note: including file: ",
+            cwd,
+            Path::new("C:/src/test.c"),
+            &grouping,
+            true,
+            true,
+        );
+        assert_eq!(by_count[0].path_prefix, "windows-sdk");
+        assert_eq!(by_count[0].count, 2);
+        assert_eq!(headers.len(), 4);
+        assert_eq!(num_includes, 4);
+
+        let tree = tree.unwrap();
+        let paths = |node: &crate::tu_stats::IncludeNode| {
+            node.children
+                .iter()
+                .map(|child| child.path.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(&tree),
+            ["C:/src/include/util.h", "C:/src/include/other.h"]
+        );
+        assert_eq!(paths(&tree.children[0]), [format!("{UCRT}/stdio.h")]);
+        assert_eq!(
+            paths(&tree.children[0].children[0]),
+            [format!("{UCRT}/corecrt.h")]
+        );
+    }
 }
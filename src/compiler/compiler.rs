@@ -407,6 +407,12 @@ where
     /// Return the state of any `--color` option passed to the compiler.
     fn color_mode(&self) -> ColorMode;
 
+    /// The input file of this compilation, if it records TU stats. Used for
+    /// the records of requests that fail before their hash key is known.
+    fn tu_stats_input_file(&self, _cwd: &Path) -> Option<PathBuf> {
+        None
+    }
+
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
     #[allow(clippy::too_many_arguments)]
@@ -421,6 +427,39 @@ where
         env_vars: Vec<(OsString, OsString)>,
         cache_control: CacheControl,
         pool: tokio::runtime::Handle,
+    ) -> Result<(CompileResult, process::Output)> {
+        self.get_cached_or_compile_after(
+            service,
+            dist_client,
+            creator,
+            storage,
+            arguments,
+            cwd,
+            env_vars,
+            cache_control,
+            pool,
+            None,
+        )
+        .await
+    }
+
+    /// Implements `get_cached_or_compile`. `prior_miss` is the lookup that
+    /// already missed for this compile request, with how long it took, when
+    /// the request is run again to preprocess it locally for distribution.
+    /// It is reported instead of the lookup skipped by the second run.
+    #[allow(clippy::too_many_arguments)]
+    async fn get_cached_or_compile_after(
+        &mut self,
+        service: &server::SccacheService<T>,
+        dist_client: Option<Arc<dyn dist::Client>>,
+        creator: T,
+        storage: Arc<dyn Storage>,
+        arguments: Vec<OsString>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        cache_control: CacheControl,
+        pool: tokio::runtime::Handle,
+        prior_miss: Option<(MissType, Duration)>,
     ) -> Result<(CompileResult, process::Output)> {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
@@ -442,13 +481,24 @@ where
                 cache_control,
             )
             .await;
+        let hash_duration = start.elapsed();
         debug!(
             "[{}]: generate_hash_key took {}",
             out_pretty,
-            fmt_duration_as_secs(&start.elapsed())
+            fmt_duration_as_secs(&hash_duration)
         );
         let (key, compilation, weak_toolchain_key) = match result {
             Err(e) => {
+                if let Some(input_file) = self.tu_stats_input_file(&cwd) {
+                    let ctx = crate::tu_stats::TuStatsContext {
+                        input_file,
+                        ..Default::default()
+                    };
+                    let mut stats = ctx.into_stats(String::new(), hash_duration);
+                    stats.cache_outcome = crate::tu_stats::CacheOutcome::Error;
+                    stats.session = server::session_from_env(&env_vars);
+                    crate::tu_stats::record_stats(stats);
+                }
                 return match e.downcast::<ProcessError>() {
                     Ok(ProcessError(output)) => Ok((CompileResult::Error, output)),
                     Err(e) => Err(e),
@@ -461,6 +511,20 @@ where
             }) => (key, compilation, weak_toolchain_key),
         };
         debug!("[{}]: Hash key: {}", out_pretty, key);
+        // Start a TU stats record, completed and recorded once the outcome is known
+        #[cfg(feature = "translation-unit-stats")]
+        let (mut tu_stats, budget_warnings) = match compilation.tu_stats_context() {
+            Some(mut ctx) => {
+                let budget_warnings = std::mem::take(&mut ctx.budget_warnings);
+                let mut stats = ctx.into_stats(key.clone(), hash_duration);
+                stats.session = server::session_from_env(&env_vars);
+                (Some(stats), budget_warnings)
+            }
+            None => (None, Vec::new()),
+        };
+        #[cfg(not(feature = "translation-unit-stats"))]
+        let (mut tu_stats, budget_warnings) =
+            (None::<crate::tu_stats::TranslationUnitStats>, Vec::new());
         // If `ForceRecache` is enabled, we won't check the cache.
         let start = Instant::now();
         let cache_status = async {
@@ -491,18 +555,23 @@ where
             })
             .collect::<Vec<_>>();
 
-        let lookup = match cache_status.await {
-            (Ok(Ok(Cache::Hit(mut entry))), duration) => {
+        let (cache_status, duration) = cache_status.await;
+        if let Some(ref mut stats) = tu_stats {
+            stats.cache_lookup_duration = prior_miss.as_ref().map_or(duration, |(_, d)| *d);
+        }
+        let lookup = match cache_status {
+            Ok(Ok(Cache::Hit(mut entry))) => {
                 debug!(
                     "[{}]: Cache hit in {}",
                     out_pretty,
                     fmt_duration_as_secs(&duration)
                 );
-                let output = process::Output {
+                let mut output = process::Output {
                     status: exit_status(0),
                     stdout: entry.get_stdout(),
                     stderr: entry.get_stderr(),
                 };
+                append_budget_warnings(&mut output, &budget_warnings);
 
                 let filtered_outputs = if compilation.is_locally_preprocessed() {
                     // In this mode, cache entries are exclusively distinguished by their preprocessed
@@ -526,7 +595,12 @@ where
                 };
 
                 let hit = CompileResult::CacheHit(duration);
-                match entry.extract_objects(filtered_outputs, &pool).await {
+                let start_read = Instant::now();
+                let extracted = entry.extract_objects(filtered_outputs, &pool).await;
+                if let Some(ref mut stats) = tu_stats {
+                    stats.cache_read_duration = start_read.elapsed();
+                }
+                match extracted {
                     Ok(()) => Ok(CacheLookupResult::Success(hit, output)),
                     Err(e) => {
                         if e.downcast_ref::<DecompressionFailure>().is_some() {
@@ -538,7 +612,7 @@ where
                     }
                 }
             }
-            (Ok(Ok(Cache::Miss)), duration) => {
+            Ok(Ok(Cache::Miss)) => {
                 debug!(
                     "[{}]: Cache miss in {}",
                     out_pretty,
@@ -546,7 +620,7 @@ where
                 );
                 Ok(CacheLookupResult::Miss(MissType::Normal))
             }
-            (Ok(Ok(Cache::None)), duration) => {
+            Ok(Ok(Cache::None)) => {
                 debug!(
                     "[{}]: Cache none in {}",
                     out_pretty,
@@ -554,7 +628,7 @@ where
                 );
                 Ok(CacheLookupResult::Miss(MissType::ForcedNoCache))
             }
-            (Ok(Ok(Cache::Recache)), duration) => {
+            Ok(Ok(Cache::Recache)) => {
                 debug!(
                     "[{}]: Cache recache in {}",
                     out_pretty,
@@ -562,7 +636,7 @@ where
                 );
                 Ok(CacheLookupResult::Miss(MissType::ForcedRecache))
             }
-            (Ok(Err(err)), duration) => {
+            Ok(Err(err)) => {
                 error!(
                     "[{}]: Cache read error: {:?} in {}",
                     out_pretty,
@@ -571,7 +645,7 @@ where
                 );
                 Ok(CacheLookupResult::Miss(MissType::CacheReadError))
             }
-            (Err(_), duration) => {
+            Err(_) => {
                 debug!(
                     "[{}]: Cache timed out {}",
                     out_pretty,
@@ -583,9 +657,21 @@ where
 
         match lookup {
             CacheLookupResult::Success(compile_result, output) => {
+                if let Some(mut stats) = tu_stats {
+                    stats.cache_outcome = crate::tu_stats::CacheOutcome::Hit;
+                    stats.timestamp = std::time::SystemTime::now();
+                    crate::tu_stats::record_stats(stats);
+                }
                 Ok::<_, Error>((compile_result, output))
             }
             CacheLookupResult::Miss(miss_type) => {
+                let lookup_duration = match (&prior_miss, &miss_type) {
+                    (Some((_, prior_duration)), _) => Some(*prior_duration),
+                    (None, MissType::ForcedNoCache | MissType::ForcedRecache) => None,
+                    (None, _) => Some(duration),
+                };
+                let miss_reason: crate::tu_stats::MissReason =
+                    prior_miss.as_ref().map_or(&miss_type, |(m, _)| m).into();
                 // Cache miss, so compile it.
                 let start = Instant::now();
 
@@ -597,8 +683,9 @@ where
                     // This compilation only had enough information to find and use a cache entry (or to
                     // run a local compile, which doesn't need locally preprocessed code).
                     // For distributed compilation, the local preprocessing step still needs to be done.
+                    // The lookup that missed was made by this call.
                     return self
-                        .get_cached_or_compile(
+                        .get_cached_or_compile_after(
                             service,
                             dist_client,
                             creator,
@@ -608,14 +695,12 @@ where
                             env_vars,
                             CacheControl::ForceRecache,
                             pool,
+                            Some((miss_type, duration)),
                         )
                         .await;
                 }
 
-                // Extract TU stats context before compilation consumes it
-                let tu_stats_ctx = compilation.tu_stats_context();
-
-                let (cacheable, dist_type, retry_count, compiler_result) = dist_or_local_compile(
+                let compiled = dist_or_local_compile(
                     service,
                     dist_client,
                     creator,
@@ -624,27 +709,64 @@ where
                     weak_toolchain_key,
                     out_pretty.clone(),
                 )
-                .await?;
+                .await;
+                let (cacheable, dist_type, job_stats, mut compiler_result) = match compiled {
+                    Ok(compiled) => compiled,
+                    Err(e) => {
+                        if let Some(mut stats) = tu_stats {
+                            stats.cache_outcome = crate::tu_stats::CacheOutcome::Error;
+                            stats.miss_reason = Some(miss_reason);
+                            stats.compile_duration = start.elapsed();
+                            stats.timestamp = std::time::SystemTime::now();
+                            crate::tu_stats::record_stats(stats);
+                        }
+                        return Err(e);
+                    }
+                };
                 let duration_compilation = start.elapsed();
-
-                // Record TU stats if we have the context
-                #[cfg(feature = "translation-unit-stats")]
-                if let Some((input_file, preprocessed_size, num_includes, preprocess_duration, top_includes_by_count, top_includes_by_size)) = tu_stats_ctx {
-                    let is_distributed = matches!(dist_type, DistType::Ok(_));
-                    let stats = crate::tu_stats::TranslationUnitStats {
-                        input_file,
-                        preprocessed_size,
-                        num_includes,
-                        preprocess_duration,
-                        compile_duration: duration_compilation,
-                        dist_retry_count: retry_count,
-                        is_distributed,
-                        top_includes_by_count,
-                        top_includes_by_size,
-                        timestamp: std::time::SystemTime::now(),
+                let timings = MissTimings {
+                    lookup: lookup_duration,
+                    compile: duration_compilation,
+                };
+                // The warnings are kept out of the cache entry, since the
+                // budgets may have changed by the time it is hit
+                let stderr_len = compiler_result.stderr.len();
+                append_budget_warnings(&mut compiler_result, &budget_warnings);
+
+                // Misses are recorded once the cache write completes
+                let mut tu_stats = tu_stats.and_then(|mut stats| {
+                    use crate::tu_stats::CacheOutcome;
+                    // Mirrors the order of the checks below
+                    stats.cache_outcome = if !compiler_result.status.success() {
+                        CacheOutcome::CompileFailed
+                    } else if miss_type == MissType::ForcedNoCache {
+                        CacheOutcome::NotCached
+                    } else if cacheable != Cacheable::Yes {
+                        CacheOutcome::NotCacheable
+                    } else {
+                        CacheOutcome::Miss
+                    };
+                    stats.miss_reason = Some(miss_reason);
+                    stats.compile_duration = duration_compilation;
+                    stats.dist_retry_count = job_stats.retry_count;
+                    stats.dist_toolchain_duration = job_stats.toolchain_duration;
+                    stats.dist_alloc_duration = job_stats.alloc_duration;
+                    stats.dist_inputs_duration = job_stats.inputs_duration;
+                    stats.dist_upload_duration = job_stats.upload_duration;
+                    stats.dist_execute_duration = job_stats.execute_duration;
+                    stats.dist_outputs_duration = job_stats.outputs_duration;
+                    stats.dist_server_id = match dist_type {
+                        DistType::Ok(server_id) => Some(server_id),
+                        _ => None,
                     };
+                    stats.is_distributed = matches!(dist_type, DistType::Ok(_));
+                    if stats.cache_outcome == CacheOutcome::Miss {
+                        return Some(stats);
+                    }
+                    stats.timestamp = std::time::SystemTime::now();
                     crate::tu_stats::record_stats(stats);
-                }
+                    None
+                });
 
                 if !compiler_result.status.success() {
                     debug!(
@@ -653,7 +775,7 @@ where
                         fmt_duration_as_secs(&duration_compilation)
                     );
                     return Ok((
-                        CompileResult::CompileFailed(dist_type, duration_compilation),
+                        CompileResult::CompileFailed(dist_type, timings),
                         compiler_result,
                     ));
                 }
@@ -665,7 +787,7 @@ where
                         fmt_duration_as_secs(&duration_compilation)
                     );
                     return Ok((
-                        CompileResult::NotCached(dist_type, duration_compilation),
+                        CompileResult::NotCached(dist_type, timings),
                         compiler_result,
                     ));
                 }
@@ -677,7 +799,7 @@ where
                         fmt_duration_as_secs(&duration_compilation)
                     );
                     return Ok((
-                        CompileResult::NotCacheable(dist_type, duration_compilation),
+                        CompileResult::NotCacheable(dist_type, timings),
                         compiler_result,
                     ));
                 }
@@ -687,12 +809,28 @@ where
                     fmt_duration_as_secs(&duration_compilation)
                 );
                 let start_create_artifact = Instant::now();
-                let mut entry = CacheWrite::from_objects(outputs, &pool)
-                    .await
-                    .context("failed to zip up compiler outputs")?;
+                let entry = async {
+                    let mut entry = CacheWrite::from_objects(outputs, &pool)
+                        .await
+                        .context("failed to zip up compiler outputs")?;
 
-                entry.put_stdout(&compiler_result.stdout)?;
-                entry.put_stderr(&compiler_result.stderr)?;
+                    entry.put_stdout(&compiler_result.stdout)?;
+                    entry.put_stderr(&compiler_result.stderr[..stderr_len])?;
+                    Ok::<_, Error>(entry)
+                }
+                .await;
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        if let Some(mut stats) = tu_stats.take() {
+                            stats.cache_outcome = crate::tu_stats::CacheOutcome::Error;
+                            stats.cache_write_duration = start_create_artifact.elapsed();
+                            stats.timestamp = std::time::SystemTime::now();
+                            crate::tu_stats::record_stats(stats);
+                        }
+                        return Err(e);
+                    }
+                };
                 debug!(
                     "[{}]: Created cache artifact in {}",
                     out_pretty,
@@ -704,7 +842,13 @@ where
                 // entry. We'll get the result back elsewhere.
                 let future = async move {
                     let start = Instant::now();
-                    match storage.put(&key, entry).await {
+                    let res = storage.put(&key, entry).await;
+                    if let Some(mut stats) = tu_stats.take() {
+                        stats.cache_write_duration = start_create_artifact.elapsed();
+                        stats.timestamp = std::time::SystemTime::now();
+                        crate::tu_stats::record_stats(stats);
+                    }
+                    match res {
                         Ok(_) => {
                             debug!("[{}]: Stored in cache successfully!", out_pretty2);
                             Ok(CacheWriteInfo {
@@ -717,7 +861,7 @@ where
                 };
                 let future = Box::pin(future);
                 Ok((
-                    CompileResult::CacheMiss(miss_type, dist_type, duration_compilation, future),
+                    CompileResult::CacheMiss(miss_type, dist_type, timings, future),
                     compiler_result,
                 ))
             }
@@ -736,6 +880,15 @@ where
     fn language(&self) -> Language;
 }
 
+/// Append warnings about exceeded translation unit budgets to the stderr of
+/// a compilation
+fn append_budget_warnings(output: &mut process::Output, warnings: &[String]) {
+    for warning in warnings {
+        output.stderr.extend_from_slice(warning.as_bytes());
+        output.stderr.push(b'\n');
+    }
+}
+
 #[cfg(not(feature = "dist-client"))]
 async fn dist_or_local_compile<T>(
     service: &server::SccacheService<T>,
@@ -745,7 +898,7 @@ async fn dist_or_local_compile<T>(
     compilation: Box<dyn Compilation<T>>,
     _weak_toolchain_key: String,
     out_pretty: String,
-) -> Result<(Cacheable, DistType, u32, process::Output)>
+) -> Result<(Cacheable, DistType, DistJobStats, process::Output)>
 where
     T: CommandCreatorSync,
 {
@@ -758,7 +911,7 @@ where
     compile_cmd
         .execute(&service, &creator)
         .await
-        .map(move |o| (cacheable, DistType::NoDist, 0, o))
+        .map(move |o| (cacheable, DistType::NoDist, DistJobStats::default(), o))
 }
 
 #[cfg(feature = "dist-client")]
@@ -770,7 +923,7 @@ async fn dist_or_local_compile<T>(
     compilation: Box<dyn Compilation<T>>,
     weak_toolchain_key: String,
     out_pretty: String,
-) -> Result<(Cacheable, DistType, u32, process::Output)>
+) -> Result<(Cacheable, DistType, DistJobStats, process::Output)>
 where
     T: CommandCreatorSync,
 {
@@ -797,7 +950,7 @@ where
             return compile_cmd
                 .execute(service, &creator)
                 .await
-                .map(move |o| (cacheable, DistType::NoDist, 0, o));
+                .map(move |o| (cacheable, DistType::NoDist, DistJobStats::default(), o));
         }
     };
 
@@ -808,6 +961,7 @@ where
     let local_executable2 = compile_cmd.get_executable();
 
     let do_dist_compile = async move {
+        let start = Instant::now();
         let mut dist_compile_cmd =
             dist_compile_cmd.context("Could not create distributed compile command")?;
         debug!("[{}]: Creating distributed compile request", out_pretty);
@@ -823,21 +977,23 @@ where
             "[{}]: Identifying dist toolchain for {:?}",
             out_pretty, local_executable
         );
+        let mut job_stats = DistJobStats::default();
         let (dist_toolchain, maybe_dist_compile_executable) = dist_client
             .put_toolchain(local_executable, weak_toolchain_key, toolchain_packager)
             .await?;
+        job_stats.toolchain_duration = start.elapsed();
         let mut tc_archive = None;
         if let Some((dist_compile_executable, archive_path)) = maybe_dist_compile_executable {
             dist_compile_cmd.executable = dist_compile_executable;
             tc_archive = Some(archive_path);
         }
 
-        let mut retry_count = 0u32;
-
         let job_alloc = loop {
             debug!("[{}]: Requesting allocation", out_pretty);
-            let jares = dist_client.do_alloc_job(dist_toolchain.clone()).await?;
-            match jares {
+            let start_alloc = Instant::now();
+            let jares = dist_client.do_alloc_job(dist_toolchain.clone()).await;
+            job_stats.alloc_duration += start_alloc.elapsed();
+            match jares? {
                 dist::AllocJobResult::Success {
                     job_alloc,
                     need_toolchain: true,
@@ -847,11 +1003,12 @@ where
                         out_pretty, dist_toolchain.archive_id, job_alloc.job_id
                     );
 
-                    match dist_client
+                    let start_submit = Instant::now();
+                    let submitted = dist_client
                         .do_submit_toolchain(job_alloc.clone(), dist_toolchain.clone())
-                        .await
-                        .map_err(|e| e.context("Could not submit toolchain"))?
-                    {
+                        .await;
+                    job_stats.toolchain_duration += start_submit.elapsed();
+                    match submitted.map_err(|e| e.context("Could not submit toolchain"))? {
                         dist::SubmitToolchainResult::Success => break job_alloc,
                         dist::SubmitToolchainResult::JobNotFound => {
                             bail!("Job {} not found on server", job_alloc.job_id)
@@ -871,13 +1028,15 @@ where
                     // retry_on_busy or remote_only both enable retry behavior
                     if dist_client.retry_on_busy() || dist_client.remote_only() {
                         // Retry with random backoff
-                        retry_count += 1;
+                        job_stats.retry_count += 1;
                         let sleep_millis = rand::thread_rng().gen_range(1000..=10000);
                         debug!(
                             "[{}]: Failed to allocate job: {}. Retrying ({}) in {} ms...",
-                            out_pretty, msg, retry_count, sleep_millis
+                            out_pretty, msg, job_stats.retry_count, sleep_millis
                         );
-                        tokio::time::sleep(Duration::from_millis(sleep_millis)).await;
+                        let sleep = Duration::from_millis(sleep_millis);
+                        tokio::time::sleep(sleep).await;
+                        job_stats.alloc_duration += sleep;
                         // Continue the loop to retry
                     } else {
                         // Fail immediately to trigger local fallback
@@ -890,7 +1049,7 @@ where
         let job_id = job_alloc.job_id;
         let server_id = job_alloc.server_id;
         debug!("[{}]: Running job", out_pretty);
-        let ((job_id, server_id), (jres, path_transformer)) = dist_client
+        let ((job_id, server_id), (jres, path_transformer, timings)) = dist_client
             .do_run_job(
                 job_alloc,
                 dist_compile_cmd,
@@ -906,10 +1065,15 @@ where
                 )
             })?;
 
+        job_stats.inputs_duration = timings.package_inputs;
+        job_stats.upload_duration = timings.upload_inputs;
+        job_stats.execute_duration = timings.execute;
+
         let jc = match jres {
             dist::RunJobResult::Complete(jc) => jc,
             dist::RunJobResult::JobNotFound => bail!("Job {} not found on server", job_id),
         };
+        let start_outputs = Instant::now();
         debug!(
             "fetched {:?}",
             jc.outputs
@@ -970,7 +1134,8 @@ where
                 .handle_outputs(&path_transformer, &output_paths, &extra_inputs)
                 .with_context(|| "failed to rewrite outputs from compile")
         );
-        Ok((DistType::Ok(server_id), retry_count, jc.output.into()))
+        job_stats.outputs_duration = timings.download + start_outputs.elapsed();
+        Ok((DistType::Ok(server_id), job_stats, jc.output.into()))
     };
 
     use futures::TryFutureExt;
@@ -1008,10 +1173,10 @@ where
                 compile_cmd
                     .execute(service, &creator)
                     .await
-                    .map(|o| (DistType::Error, 0, o))
+                    .map(|o| (DistType::Error, DistJobStats::default(), o))
             }
         })
-        .map_ok(move |(dt, rc, o)| (cacheable, dt, rc, o))
+        .map_ok(move |(dt, js, o)| (cacheable, dt, js, o))
         .await
 }
 
@@ -1061,7 +1226,7 @@ where
     }
 
     /// Get the TU stats context for this compilation (for TU stats)
-    fn tu_stats_context(&self) -> Option<(PathBuf, usize, usize, Duration, Vec<crate::tu_stats::IncludeStats>, Vec<crate::tu_stats::IncludeStats>)> {
+    fn tu_stats_context(&self) -> Option<crate::tu_stats::TuStatsContext> {
         None
     }
 }
@@ -1144,6 +1309,25 @@ pub enum DistType {
     Error,
 }
 
+/// Timings and retries of a distributed compile job, zero for local compiles.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DistJobStats {
+    /// Number of times the scheduler was busy and the allocation was retried
+    pub retry_count: u32,
+    /// Packaging the toolchain and submitting it to the server
+    pub toolchain_duration: Duration,
+    /// Waiting for the scheduler to allocate the job, including busy retries
+    pub alloc_duration: Duration,
+    /// Packaging the inputs of the job
+    pub inputs_duration: Duration,
+    /// Uploading the inputs of the job
+    pub upload_duration: Duration,
+    /// Waiting for the server to run the job, once the inputs are uploaded
+    pub execute_duration: Duration,
+    /// Downloading the outputs of the job and unpacking them locally
+    pub outputs_duration: Duration,
+}
+
 /// Specifics about cache misses.
 #[derive(Debug, PartialEq, Eq)]
 pub enum MissType {
@@ -1159,6 +1343,15 @@ pub enum MissType {
     CacheReadError,
 }
 
+/// How long a compile request that missed the cache took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MissTimings {
+    /// The cache lookup, unless the cache was bypassed.
+    pub lookup: Option<Duration>,
+    /// The compilation, local or distributed.
+    pub compile: Duration,
+}
+
 /// Information about a successful cache write.
 pub struct CacheWriteInfo {
     pub object_file_pretty: String,
@@ -1178,15 +1371,15 @@ pub enum CompileResult {
     CacheMiss(
         MissType,
         DistType,
-        Duration, // Compilation time
+        MissTimings,
         Pin<Box<dyn Future<Output = Result<CacheWriteInfo>> + Send>>,
     ),
     /// Not in cache and do not cache the results of the compilation.
-    NotCached(DistType, Duration),
+    NotCached(DistType, MissTimings),
     /// Not in cache, but the compilation result was determined to be not cacheable.
-    NotCacheable(DistType, Duration),
+    NotCacheable(DistType, MissTimings),
     /// Not in cache, but compilation failed.
-    CompileFailed(DistType, Duration),
+    CompileFailed(DistType, MissTimings),
 }
 
 /// The state of `--color` options passed to a compiler.
@@ -2335,6 +2528,62 @@ LLVM version: 6.0",
         assert_ne!(results[0].key, results[2].key);
     }
 
+    #[test]
+    #[cfg(feature = "translation-unit-stats")]
+    fn test_num_includes_without_preprocessor_cache() {
+        let f = TestFixture::new();
+        let clang = f.mk_bin("clang").unwrap();
+        let creator = new_creator();
+        let runtime = single_threaded_runtime();
+        let pool = runtime.handle();
+        let cwd = f.tempdir.path();
+        std::fs::write(cwd.join("foo.c"), "whatever").unwrap();
+
+        let output = "compiler_id=clang\ncompiler_version=\"16.0.0\"";
+        next_command(&creator, Ok(MockChild::new(exit_status(0), output, "")));
+        let c = detect_compiler(creator.clone(), &clang, cwd, &[], &[], pool, None)
+            .wait()
+            .unwrap()
+            .0;
+        // `b.h` is included twice, but only counts once
+        let preprocessed = "# 1 \"foo.c\"\n\
+# 1 \"<built-in>\"\n\
+# 1 \"/usr/include/a.h\" 1\n\
+int a;\n\
+# 1 \"/usr/include/b.h\" 1\n\
+int b;\n\
+# 2 \"/usr/include/a.h\" 2\n\
+# 2 \"foo.c\" 2\n\
+# 1 \"/usr/include/b.h\" 1\n\
+# 3 \"foo.c\" 2\n\
+int main() {}\n";
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), preprocessed, "")),
+        );
+        let mut hasher =
+            match c.parse_arguments(&ovec!["-c", "foo.c", "-o", "foo.o"], ".".as_ref(), &[]) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+        crate::tu_stats::enable_recording_for_tests();
+        let result = hasher
+            .generate_hash_key(
+                &creator,
+                cwd.to_path_buf(),
+                vec![],
+                false,
+                pool,
+                false,
+                Arc::new(MockStorage::new(None, false)),
+                CacheControl::Default,
+            )
+            .wait()
+            .unwrap();
+        let ctx = result.compilation.tu_stats_context().unwrap();
+        assert_eq!(ctx.num_includes, 2);
+    }
+
     #[test]
     fn test_get_compiler_info() {
         let creator = new_creator();
@@ -3130,8 +3379,8 @@ mod test_dist {
     use crate::dist::pkg;
     use crate::dist::{
         self, AllocJobResult, CompileCommand, JobAlloc, JobComplete, JobId, OutputData,
-        PathTransformer, ProcessOutput, RunJobResult, SchedulerStatusResult, ServerId,
-        SubmitToolchainResult, Toolchain,
+        PathTransformer, ProcessOutput, RunJobResult, RunJobTimings, SchedulerStatusResult,
+        ServerId, SubmitToolchainResult, Toolchain,
     };
     use async_trait::async_trait;
     use std::path::{Path, PathBuf};
@@ -3167,7 +3416,7 @@ mod test_dist {
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             unreachable!()
         }
         async fn put_toolchain(
@@ -3227,7 +3476,7 @@ mod test_dist {
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             unreachable!()
         }
         async fn put_toolchain(
@@ -3304,7 +3553,7 @@ mod test_dist {
             _: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             unreachable!("fn do_run_job is not used for this test. qed")
         }
         async fn put_toolchain(
@@ -3381,7 +3630,7 @@ mod test_dist {
             command: CompileCommand,
             _: Vec<String>,
             _: Box<dyn pkg::InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(command.executable, "/overridden/compiler");
             Err(anyhow!("MOCK: run job failure"))
@@ -3471,7 +3720,7 @@ mod test_dist {
             command: CompileCommand,
             outputs: Vec<String>,
             inputs_packager: Box<dyn pkg::InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             assert_eq!(job_alloc.job_id, JobId(0));
             assert_eq!(command.executable, "/overridden/compiler");
 
@@ -3489,7 +3738,7 @@ mod test_dist {
                 output: self.output.clone(),
                 outputs,
             });
-            Ok((result, path_transformer))
+            Ok((result, path_transformer, RunJobTimings::default()))
         }
         async fn put_toolchain(
             &self,
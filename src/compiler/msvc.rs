@@ -55,6 +55,10 @@ impl CCompilerImpl for Msvc {
     fn version(&self) -> Option<String> {
         self.version.clone()
     }
+    #[cfg(feature = "translation-unit-stats")]
+    fn show_includes_prefix(&self) -> Option<&str> {
+        Some(&self.includes_prefix)
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -118,7 +122,7 @@ impl CCompilerImpl for Msvc {
 }
 
 #[cfg(not(windows))]
-fn from_local_codepage(multi_byte_str: &[u8]) -> io::Result<String> {
+pub fn from_local_codepage(multi_byte_str: &[u8]) -> io::Result<String> {
     String::from_utf8(multi_byte_str.to_vec())
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
 }
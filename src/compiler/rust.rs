@@ -26,6 +26,9 @@ use crate::dist::pkg;
 #[cfg(feature = "dist-client")]
 use crate::lru_disk_cache::{LruCache, Meter};
 use crate::mock_command::{CommandCreatorSync, RunCommand};
+#[cfg(feature = "translation-unit-stats")]
+use crate::tu_stats::RustCrateStats;
+use crate::tu_stats::TuStatsContext;
 use crate::util::{Digest, fmt_duration_as_secs, hash_all, hash_all_archives, run_input_output};
 use crate::util::{HashToDigest, OsStrExt};
 use crate::{counted_array, dist};
@@ -147,6 +150,8 @@ pub struct RustupProxy {
 pub struct ParsedArguments {
     /// The full commandline, with all parsed arguments
     arguments: Vec<Argument<ArgData>>,
+    /// The crate root source file.
+    input: PathBuf,
     /// The location of compiler outputs.
     output_dir: PathBuf,
     /// Paths to extern crates used in the compile.
@@ -222,6 +227,8 @@ pub struct RustCompilation {
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// Translation unit statistics context (if TU stats are enabled)
+    tu_stats_context: Option<TuStatsContext>,
 }
 
 // The selection of crate types for this compilation
@@ -231,6 +238,18 @@ pub struct CrateTypes {
     staticlib: bool,
 }
 
+impl CrateTypes {
+    /// The `--crate-type` names of the selected crate types
+    #[cfg(feature = "translation-unit-stats")]
+    fn names(&self) -> Vec<String> {
+        [(self.rlib, "rlib"), (self.staticlib, "staticlib")]
+            .into_iter()
+            .filter(|&(selected, _)| selected)
+            .map(|(_, name)| name.to_owned())
+            .collect()
+    }
+}
+
 /// Emit types that we will cache.
 static ALLOWED_EMIT: Lazy<HashSet<&'static str>> =
     Lazy::new(|| ["link", "metadata", "dep-info"].iter().copied().collect());
@@ -1222,9 +1241,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             };
         };
     }
-    // We don't actually save the input value, but there needs to be one.
     req!(input);
-    drop(input);
     req!(output_dir);
     req!(emit);
     req!(crate_name);
@@ -1304,6 +1321,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     externs.sort();
     CompilerArguments::Ok(ParsedArguments {
         arguments: args,
+        input: input.into(),
         output_dir,
         crate_types,
         externs,
@@ -1657,6 +1675,27 @@ where
             ));
         }
 
+        // Collect translation unit statistics context, if they are recorded
+        #[cfg(feature = "translation-unit-stats")]
+        let tu_stats_context = crate::tu_stats::recording_enabled().then(|| TuStatsContext {
+            input_file: cwd.join(&self.parsed_args.input),
+            rust_crate: Some(RustCrateStats {
+                crate_name: self.parsed_args.crate_name.clone(),
+                crate_types: self.parsed_args.crate_types.names(),
+                num_externs: abs_externs.len(),
+                extern_bytes: abs_externs
+                    .iter()
+                    .filter_map(|path| fs::metadata(path).ok())
+                    .map(|metadata| metadata.len())
+                    .sum(),
+                num_source_files: source_files.len(),
+            }),
+            ..Default::default()
+        });
+
+        #[cfg(not(feature = "translation-unit-stats"))]
+        let tu_stats_context = None;
+
         let inputs = source_files
             .into_iter()
             .chain(abs_externs)
@@ -1678,6 +1717,7 @@ where
                 dep_info,
                 cwd,
                 env_vars,
+                tu_stats_context,
                 #[cfg(feature = "dist-client")]
                 rlib_dep_reader: self.rlib_dep_reader.clone(),
             }),
@@ -1689,6 +1729,10 @@ where
         self.parsed_args.color_mode
     }
 
+    fn tu_stats_input_file(&self, cwd: &Path) -> Option<PathBuf> {
+        Some(cwd.join(&self.parsed_args.input))
+    }
+
     fn output_pretty(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
@@ -1892,6 +1936,10 @@ impl<T: CommandCreatorSync> Compilation<T> for RustCompilation {
             optional: v.optional,
         }))
     }
+
+    fn tu_stats_context(&self) -> Option<TuStatsContext> {
+        self.tu_stats_context.clone()
+    }
 }
 
 // TODO: we do end up with slashes facing the wrong way, but Windows is agnostic so it's
@@ -3463,6 +3511,7 @@ proc_macro false
                         ArgDisposition::Separated,
                     ),
                 ],
+                input: "foo.rs".into(),
                 output_dir: "foo/".into(),
                 externs: vec!["bar.rlib".into()],
                 crate_link_paths: vec![],
@@ -3484,6 +3533,7 @@ proc_macro false
         let creator = new_creator();
         mock_dep_info(&creator, &["foo.rs", "bar.rs"]);
         mock_file_names(&creator, &["foo.rlib", "foo.a"]);
+        crate::tu_stats::enable_recording_for_tests();
         let runtime = single_threaded_runtime();
         let pool = runtime.handle().clone();
         let res = hasher
@@ -3545,6 +3595,22 @@ proc_macro false
         let mut out = res.compilation.outputs().map(|k| k.key).collect::<Vec<_>>();
         out.sort();
         assert_eq!(out, vec!["foo.a", "foo.rlib", "foo.rmeta"]);
+
+        #[cfg(feature = "translation-unit-stats")]
+        {
+            let context = res.compilation.tu_stats_context().unwrap();
+            assert_eq!(context.input_file, f.tempdir.path().join("foo.rs"));
+            assert_eq!(
+                context.rust_crate,
+                Some(RustCrateStats {
+                    crate_name: "foo".into(),
+                    crate_types: vec!["rlib".into()],
+                    num_externs: 1,
+                    extern_bytes: 0,
+                    num_source_files: 2,
+                })
+            );
+        }
     }
 
     fn hash_key<F>(
@@ -111,6 +111,13 @@ where
     deserializer.deserialize_any(StringOrU64Visitor)
 }
 
+fn deserialize_opt_size_from_str<'de, D>(deserializer: D) -> StdResult<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(StringOrU64Visitor).map(Some)
+}
+
 pub fn parse_size(val: &str) -> Option<u64> {
     let multiplier = match val.chars().last().map(|v| v.to_ascii_uppercase()) {
         Some('K') => 1024,
@@ -196,6 +203,9 @@ pub struct DiskCacheConfig {
     pub size: u64,
     pub preprocessor_cache_mode: PreprocessorCacheModeConfig,
     pub rw_mode: CacheModeConfig,
+    /// Keep the disk cache in front of the remote cache, if one is
+    /// configured, instead of only using it in its absence
+    pub tiered: bool,
 }
 
 impl Default for DiskCacheConfig {
@@ -205,6 +215,7 @@ impl Default for DiskCacheConfig {
             size: default_disk_cache_size(),
             preprocessor_cache_mode: PreprocessorCacheModeConfig::activated(),
             rw_mode: CacheModeConfig::ReadWrite,
+            tiered: false,
         }
     }
 }
@@ -327,6 +338,45 @@ pub struct RedisCacheConfig {
     pub key_prefix: String,
 }
 
+/// The time `sccache --cache-gc` removes the entries of the shared cache
+/// by, oldest first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GcTime {
+    /// The last access time. Filesystems mounted with `noatime` don't
+    /// update it.
+    #[default]
+    Atime,
+    /// The last modification time, that is when the entry was written.
+    Mtime,
+}
+
+impl FromStr for GcTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "atime" => Ok(GcTime::Atime),
+            "mtime" => Ok(GcTime::Mtime),
+            _ => bail!("Unknown time `{}`, expected `atime` or `mtime`", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedCacheConfig {
+    pub dir: PathBuf,
+    /// The size `sccache --cache-gc` trims the cache to
+    #[serde(
+        default = "default_disk_cache_size",
+        deserialize_with = "deserialize_size_from_str"
+    )]
+    pub size: u64,
+    #[serde(default)]
+    pub gc_time: GcTime,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct WebdavCacheConfig {
@@ -372,11 +422,76 @@ pub enum CacheType {
     S3(S3CacheConfig),
     Webdav(WebdavCacheConfig),
     OSS(OSSCacheConfig),
+    Shared(SharedCacheConfig),
+}
+
+/// The name of a remote cache, as used in the `order` and `write` lists of
+/// the `[cache]` section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    Azure,
+    Gcs,
+    Gha,
+    Memcached,
+    Redis,
+    S3,
+    Webdav,
+    Oss,
+    Shared,
+}
+
+impl FromStr for CacheBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "azure" => CacheBackend::Azure,
+            "gcs" => CacheBackend::Gcs,
+            "gha" => CacheBackend::Gha,
+            "memcached" => CacheBackend::Memcached,
+            "redis" => CacheBackend::Redis,
+            "s3" => CacheBackend::S3,
+            "webdav" => CacheBackend::Webdav,
+            "oss" => CacheBackend::Oss,
+            "shared" => CacheBackend::Shared,
+            _ => bail!("Unknown cache backend `{}`", s),
+        })
+    }
+}
+
+impl fmt::Display for CacheBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheBackend::Azure => "azure",
+            CacheBackend::Gcs => "gcs",
+            CacheBackend::Gha => "gha",
+            CacheBackend::Memcached => "memcached",
+            CacheBackend::Redis => "redis",
+            CacheBackend::S3 => "s3",
+            CacheBackend::Webdav => "webdav",
+            CacheBackend::Oss => "oss",
+            CacheBackend::Shared => "shared",
+        })
+    }
+}
+
+/// A remote cache, and whether new entries are written to it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RemoteCacheConfig {
+    pub cache: CacheType,
+    pub write: bool,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct CacheConfigs {
+    /// The remote caches to use, in the order they are read from. Only the
+    /// first configured one is used when unset.
+    pub order: Option<Vec<CacheBackend>>,
+    /// The remote caches of `order` new entries are written to. All of them
+    /// when unset.
+    pub write: Option<Vec<CacheBackend>>,
     pub azure: Option<AzureCacheConfig>,
     pub disk: Option<DiskCacheConfig>,
     pub gcs: Option<GCSCacheConfig>,
@@ -386,13 +501,16 @@ pub struct CacheConfigs {
     pub s3: Option<S3CacheConfig>,
     pub webdav: Option<WebdavCacheConfig>,
     pub oss: Option<OSSCacheConfig>,
+    pub shared: Option<SharedCacheConfig>,
 }
 
 impl CacheConfigs {
-    /// Return cache type in an arbitrary but
-    /// consistent ordering
-    fn into_fallback(self) -> (Option<CacheType>, DiskCacheConfig) {
+    /// Return the remote caches in the configured order, or the first
+    /// configured one in an arbitrary but consistent ordering
+    fn into_fallback(self) -> Result<(Vec<RemoteCacheConfig>, DiskCacheConfig)> {
         let CacheConfigs {
+            order,
+            write,
             azure,
             disk,
             gcs,
@@ -402,26 +520,72 @@ impl CacheConfigs {
             s3,
             webdav,
             oss,
+            shared,
         } = self;
 
-        let cache_type = s3
-            .map(CacheType::S3)
-            .or_else(|| redis.map(CacheType::Redis))
-            .or_else(|| memcached.map(CacheType::Memcached))
-            .or_else(|| gcs.map(CacheType::GCS))
-            .or_else(|| gha.map(CacheType::GHA))
-            .or_else(|| azure.map(CacheType::Azure))
-            .or_else(|| webdav.map(CacheType::Webdav))
-            .or_else(|| oss.map(CacheType::OSS));
-
         let fallback = disk.unwrap_or_default();
 
-        (cache_type, fallback)
+        let Some(order) = order else {
+            if write.is_some() {
+                bail!("`cache.write` requires `cache.order` to be set");
+            }
+            let cache_type = s3
+                .map(CacheType::S3)
+                .or_else(|| redis.map(CacheType::Redis))
+                .or_else(|| memcached.map(CacheType::Memcached))
+                .or_else(|| gcs.map(CacheType::GCS))
+                .or_else(|| gha.map(CacheType::GHA))
+                .or_else(|| azure.map(CacheType::Azure))
+                .or_else(|| webdav.map(CacheType::Webdav))
+                .or_else(|| oss.map(CacheType::OSS))
+                .or_else(|| shared.map(CacheType::Shared));
+            let caches = cache_type
+                .map(|cache| RemoteCacheConfig { cache, write: true })
+                .into_iter()
+                .collect();
+            return Ok((caches, fallback));
+        };
+
+        if let Some(backend) = write.iter().flatten().find(|b| !order.contains(b)) {
+            bail!(
+                "Cache backend `{}` is in `cache.write` but not in `cache.order`",
+                backend
+            );
+        }
+        let (mut azure, mut gcs, mut gha, mut memcached) = (azure, gcs, gha, memcached);
+        let (mut redis, mut s3, mut webdav, mut oss) = (redis, s3, webdav, oss);
+        let mut shared = shared;
+        let mut caches = vec![];
+        for backend in order {
+            let cache = match backend {
+                CacheBackend::Azure => azure.take().map(CacheType::Azure),
+                CacheBackend::Gcs => gcs.take().map(CacheType::GCS),
+                CacheBackend::Gha => gha.take().map(CacheType::GHA),
+                CacheBackend::Memcached => memcached.take().map(CacheType::Memcached),
+                CacheBackend::Redis => redis.take().map(CacheType::Redis),
+                CacheBackend::S3 => s3.take().map(CacheType::S3),
+                CacheBackend::Webdav => webdav.take().map(CacheType::Webdav),
+                CacheBackend::Oss => oss.take().map(CacheType::OSS),
+                CacheBackend::Shared => shared.take().map(CacheType::Shared),
+            };
+            let cache = cache.with_context(|| {
+                format!(
+                    "Cache backend `{}` is in `cache.order` but is not configured, or is listed twice",
+                    backend
+                )
+            })?;
+            let write = write.as_ref().is_none_or(|write| write.contains(&backend));
+            caches.push(RemoteCacheConfig { cache, write });
+        }
+
+        Ok((caches, fallback))
     }
 
     /// Override self with any existing fields from other
     fn merge(&mut self, other: Self) {
         let CacheConfigs {
+            order,
+            write,
             azure,
             disk,
             gcs,
@@ -431,8 +595,15 @@ impl CacheConfigs {
             s3,
             webdav,
             oss,
+            shared,
         } = other;
 
+        if order.is_some() {
+            self.order = order
+        }
+        if write.is_some() {
+            self.write = write
+        }
         if azure.is_some() {
             self.azure = azure
         }
@@ -461,6 +632,10 @@ impl CacheConfigs {
         if oss.is_some() {
             self.oss = oss
         }
+
+        if shared.is_some() {
+            self.shared = shared
+        }
     }
 }
 
@@ -606,6 +781,69 @@ pub struct TranslationUnitStatsConfig {
     pub enabled: bool,
     /// Path to the statistics database file
     pub stats_file: Option<PathBuf>,
+    /// Delete records older than this many seconds
+    pub max_age_secs: Option<u64>,
+    /// Keep at most this many records, deleting the oldest ones first
+    pub max_records: Option<u64>,
+    /// Keep at most this many bytes of records, deleting the oldest ones first
+    #[serde(deserialize_with = "deserialize_opt_size_from_str")]
+    pub max_size: Option<u64>,
+    /// Record the lines contributed by every included header, not just the
+    /// top path prefixes
+    pub per_header: bool,
+    /// Record the include tree of every translation unit
+    pub include_graph: bool,
+    /// Ordered rules deciding which bucket an include file is counted in for
+    /// the top include path prefixes. The first matching rule wins.
+    pub include_groups: Vec<IncludeGroupRule>,
+    /// Limits every translation unit is checked against
+    pub budgets: TuBudgetConfig,
+}
+
+/// Buckets include files matching `glob` or `regex` either under a fixed
+/// `name`, or by the first `depth` components of their path
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct IncludeGroupRule {
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+    pub name: Option<String>,
+    pub depth: Option<usize>,
+}
+
+/// Limits on the size of a translation unit. Compilations exceeding one get a
+/// warning appended to their stderr, or fail if `action` is `fail`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct TuBudgetConfig {
+    /// Maximum size of the preprocessed translation unit
+    #[serde(deserialize_with = "deserialize_opt_size_from_str")]
+    pub max_preprocessed_size: Option<u64>,
+    /// Maximum number of files included by the translation unit
+    pub max_includes: Option<usize>,
+    /// Maximum lines contributed by include path prefixes
+    pub prefixes: Vec<PrefixBudgetRule>,
+    /// What to do with compilations exceeding a budget
+    pub action: BudgetAction,
+}
+
+/// Limits the lines contributed by the include files under `prefix`, a path
+/// relative to the compiler's working directory
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixBudgetRule {
+    pub prefix: String,
+    pub max_lines: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetAction {
+    #[default]
+    Warn,
+    Fail,
 }
 
 impl Default for TranslationUnitStatsConfig {
@@ -613,6 +851,13 @@ impl Default for TranslationUnitStatsConfig {
         Self {
             enabled: false,
             stats_file: None,
+            max_age_secs: None,
+            max_records: None,
+            max_size: None,
+            per_header: false,
+            include_graph: false,
+            include_groups: Vec::new(),
+            budgets: TuBudgetConfig::default(),
         }
     }
 }
@@ -625,6 +870,7 @@ pub struct FileConfig {
     pub dist: DistConfig,
     pub server_startup_timeout_ms: Option<u64>,
     pub translation_unit_stats: TranslationUnitStatsConfig,
+    pub persist_stats: bool,
 }
 
 // If the file doesn't exist or we can't read it, log the issue and proceed. If the
@@ -662,6 +908,7 @@ pub fn try_read_config_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T
 #[derive(Debug)]
 pub struct EnvConfig {
     cache: CacheConfigs,
+    persist_stats: Option<bool>,
 }
 
 fn key_prefix_from_env_var(env_var_name: &str) -> String {
@@ -700,6 +947,20 @@ fn bool_from_env_var(env_var_name: &str) -> Result<Option<bool>> {
         .transpose()
 }
 
+/// Parse a comma separated list of cache backends, like `redis,s3`
+fn cache_backends_from_env_var(env_var_name: &str) -> Result<Option<Vec<CacheBackend>>> {
+    env::var(env_var_name)
+        .ok()
+        .map(|value| {
+            value
+                .split(',')
+                .map(|backend| backend.trim().parse())
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Invalid {}", env_var_name))
+        })
+        .transpose()
+}
+
 fn config_from_env() -> Result<EnvConfig> {
     // ======= AWS =======
     let s3 = if let Ok(bucket) = env::var("SCCACHE_BUCKET") {
@@ -933,6 +1194,25 @@ fn config_from_env() -> Result<EnvConfig> {
         bail!("If setting OSS credentials, SCCACHE_OSS_NO_CREDENTIALS must not be set.");
     }
 
+    // ======= Shared filesystem =======
+    let shared = if let Some(dir) = env::var_os("SCCACHE_SHARED_DIR") {
+        let size = env::var("SCCACHE_SHARED_CACHE_SIZE")
+            .ok()
+            .and_then(|v| parse_size(&v))
+            .unwrap_or_else(default_disk_cache_size);
+        let gc_time = match env::var("SCCACHE_SHARED_GC_TIME") {
+            Ok(v) => v.parse().context("Invalid SCCACHE_SHARED_GC_TIME")?,
+            Err(_) => GcTime::default(),
+        };
+        Some(SharedCacheConfig {
+            dir: dir.into(),
+            size,
+            gc_time,
+        })
+    } else {
+        None
+    };
+
     // ======= Local =======
     let disk_dir = env::var_os("SCCACHE_DIR").map(PathBuf::from);
     let disk_sz = env::var("SCCACHE_CACHE_SIZE")
@@ -960,22 +1240,32 @@ fn config_from_env() -> Result<EnvConfig> {
         _ => (CacheModeConfig::ReadWrite, false),
     };
 
+    let disk_tiered = bool_from_env_var("SCCACHE_LOCAL_TIER")?;
+
     let any_overridden = disk_dir.is_some()
         || disk_sz.is_some()
         || preprocessor_mode_overridden
-        || disk_rw_mode_overridden;
+        || disk_rw_mode_overridden
+        || disk_tiered.is_some();
     let disk = if any_overridden {
         Some(DiskCacheConfig {
             dir: disk_dir.unwrap_or_else(default_disk_cache_dir),
             size: disk_sz.unwrap_or_else(default_disk_cache_size),
             preprocessor_cache_mode: preprocessor_mode_config,
             rw_mode: disk_rw_mode,
+            tiered: disk_tiered.unwrap_or(false),
         })
     } else {
         None
     };
 
+    // ======= Order =======
+    let order = cache_backends_from_env_var("SCCACHE_CACHE_ORDER")?;
+    let write = cache_backends_from_env_var("SCCACHE_CACHE_WRITE")?;
+
     let cache = CacheConfigs {
+        order,
+        write,
         azure,
         disk,
         gcs,
@@ -985,9 +1275,15 @@ fn config_from_env() -> Result<EnvConfig> {
         s3,
         webdav,
         oss,
+        shared,
     };
 
-    Ok(EnvConfig { cache })
+    let persist_stats = bool_from_env_var("SCCACHE_PERSIST_STATS")?;
+
+    Ok(EnvConfig {
+        cache,
+        persist_stats,
+    })
 }
 
 // The directories crate changed the location of `config_dir` on macos in version 3,
@@ -1015,11 +1311,14 @@ fn config_file(env_var: &str, leaf: &str) -> PathBuf {
 
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct Config {
-    pub cache: Option<CacheType>,
+    /// The remote caches, in the order they are read from
+    pub caches: Vec<RemoteCacheConfig>,
     pub fallback_cache: DiskCacheConfig,
     pub dist: DistConfig,
     pub server_startup_timeout: Option<std::time::Duration>,
     pub translation_unit_stats: TranslationUnitStatsConfig,
+    /// Keep the server statistics across restarts, in `stats_file()`
+    pub persist_stats: bool,
 }
 
 impl Config {
@@ -1031,10 +1330,10 @@ impl Config {
             .context("Failed to load config file")?
             .unwrap_or_default();
 
-        Ok(Self::from_env_and_file_configs(env_conf, file_conf))
+        Self::from_env_and_file_configs(env_conf, file_conf)
     }
 
-    fn from_env_and_file_configs(env_conf: EnvConfig, file_conf: FileConfig) -> Self {
+    fn from_env_and_file_configs(env_conf: EnvConfig, file_conf: FileConfig) -> Result<Self> {
         let mut conf_caches: CacheConfigs = Default::default();
 
         let FileConfig {
@@ -1042,23 +1341,38 @@ impl Config {
             dist,
             server_startup_timeout_ms,
             translation_unit_stats,
+            persist_stats,
         } = file_conf;
         conf_caches.merge(cache);
 
         let server_startup_timeout =
             server_startup_timeout_ms.map(std::time::Duration::from_millis);
 
-        let EnvConfig { cache } = env_conf;
+        let EnvConfig {
+            cache,
+            persist_stats: env_persist_stats,
+        } = env_conf;
         conf_caches.merge(cache);
 
-        let (caches, fallback_cache) = conf_caches.into_fallback();
-        Self {
-            cache: caches,
+        let (caches, fallback_cache) = conf_caches.into_fallback()?;
+        Ok(Self {
+            caches,
             fallback_cache,
             dist,
             server_startup_timeout,
             translation_unit_stats,
-        }
+            persist_stats: env_persist_stats.unwrap_or(persist_stats),
+        })
+    }
+
+    /// The file the server statistics are kept in across restarts, if
+    /// `persist_stats` is set.
+    pub fn stats_file(&self) -> Option<PathBuf> {
+        self.persist_stats.then(|| {
+            self.fallback_cache
+                .dir
+                .join(crate::lru_disk_cache::STATS_FILE)
+        })
     }
 }
 
@@ -1319,6 +1633,7 @@ fn config_overrides() {
                 size: 5,
                 preprocessor_cache_mode: Default::default(),
                 rw_mode: CacheModeConfig::ReadWrite,
+                tiered: false,
             }),
             redis: Some(RedisCacheConfig {
                 endpoint: Some("myotherredisurl".to_owned()),
@@ -1331,6 +1646,7 @@ fn config_overrides() {
             }),
             ..Default::default()
         },
+        persist_stats: None,
     };
 
     let file_conf = FileConfig {
@@ -1340,6 +1656,7 @@ fn config_overrides() {
                 size: 15,
                 preprocessor_cache_mode: Default::default(),
                 rw_mode: CacheModeConfig::ReadWrite,
+                tiered: false,
             }),
             memcached: Some(MemcachedCacheConfig {
                 url: "memurl".to_owned(),
@@ -1358,33 +1675,107 @@ fn config_overrides() {
         dist: Default::default(),
         server_startup_timeout_ms: None,
         translation_unit_stats: Default::default(),
+        persist_stats: false,
     };
 
     assert_eq!(
-        Config::from_env_and_file_configs(env_conf, file_conf),
+        Config::from_env_and_file_configs(env_conf, file_conf).unwrap(),
         Config {
-            cache: Some(CacheType::Redis(RedisCacheConfig {
-                endpoint: Some("myotherredisurl".to_owned()),
-                ttl: 24 * 3600,
-                key_prefix: "/redis/prefix".into(),
-                db: 10,
-                username: Some("user".to_owned()),
-                password: Some("secret".to_owned()),
-                ..Default::default()
-            }),),
+            caches: vec![RemoteCacheConfig {
+                cache: CacheType::Redis(RedisCacheConfig {
+                    endpoint: Some("myotherredisurl".to_owned()),
+                    ttl: 24 * 3600,
+                    key_prefix: "/redis/prefix".into(),
+                    db: 10,
+                    username: Some("user".to_owned()),
+                    password: Some("secret".to_owned()),
+                    ..Default::default()
+                }),
+                write: true,
+            }],
             fallback_cache: DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
                 preprocessor_cache_mode: Default::default(),
                 rw_mode: CacheModeConfig::ReadWrite,
+                tiered: false,
             },
             dist: Default::default(),
             server_startup_timeout: None,
             translation_unit_stats: Default::default(),
+            persist_stats: false,
         }
     );
 }
 
+#[test]
+fn config_cache_order() {
+    let caches = || CacheConfigs {
+        gha: Some(GHACacheConfig {
+            enabled: true,
+            version: "mirror".to_owned(),
+        }),
+        memcached: Some(MemcachedCacheConfig {
+            url: "memurl".to_owned(),
+            ..Default::default()
+        }),
+        redis: Some(RedisCacheConfig {
+            endpoint: Some("redisurl".to_owned()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let remote_caches = |cache: CacheConfigs| -> Result<Vec<(String, bool)>> {
+        let (caches, _) = cache.into_fallback()?;
+        Ok(caches
+            .into_iter()
+            .map(|c| (format!("{:?}", c.cache), c.write))
+            .collect())
+    };
+
+    // Only the first cache is used by default
+    let default = remote_caches(caches()).unwrap();
+    assert_eq!(default.len(), 1);
+    assert!(default[0].0.starts_with("Redis("));
+
+    let ordered = remote_caches(CacheConfigs {
+        order: Some(vec![CacheBackend::Memcached, CacheBackend::Gha]),
+        write: Some(vec![CacheBackend::Memcached]),
+        ..caches()
+    })
+    .unwrap();
+    assert_eq!(ordered.len(), 2);
+    assert!(ordered[0].0.starts_with("Memcached("));
+    assert!(ordered[0].1);
+    assert!(ordered[1].0.starts_with("GHA("));
+    assert!(!ordered[1].1);
+
+    for (order, write) in [
+        (None, Some(vec![CacheBackend::Redis])),
+        (
+            Some(vec![CacheBackend::Redis]),
+            Some(vec![CacheBackend::Gha]),
+        ),
+        (Some(vec![CacheBackend::S3]), None),
+        (Some(vec![CacheBackend::Redis, CacheBackend::Redis]), None),
+    ] {
+        assert!(
+            remote_caches(CacheConfigs {
+                order,
+                write,
+                ..caches()
+            })
+            .is_err()
+        );
+    }
+
+    assert_eq!(
+        "redis".parse::<CacheBackend>().unwrap(),
+        CacheBackend::Redis
+    );
+    assert!("disk".parse::<CacheBackend>().is_err());
+}
+
 #[test]
 #[serial]
 #[cfg(feature = "s3")]
@@ -1530,6 +1921,7 @@ fn test_gcs_service_account() {
 fn full_toml_parse() {
     const CONFIG_STR: &str = r#"
 server_startup_timeout_ms = 10000
+persist_stats = true
 
 [dist]
 # where to find the scheduler
@@ -1545,12 +1937,17 @@ type = "token"
 token = "secrettoken"
 
 
+[cache]
+order = ["redis", "s3", "gcs"]
+write = ["redis", "s3"]
+
 #[cache.azure]
 # does not work as it appears
 
 [cache.disk]
 dir = "/tmp/.cache/sccache"
 size = 7516192768 # 7 GiBytes
+tiered = true
 
 [cache.gcs]
 rw_mode = "READ_ONLY"
@@ -1605,6 +2002,37 @@ bucket = "name"
 endpoint = "oss-us-east-1.aliyuncs.com"
 key_prefix = "ossprefix"
 no_credentials = true
+
+[cache.shared]
+dir = "/mnt/nfs/sccache"
+size = "50G"
+gc_time = "mtime"
+
+[translation_unit_stats]
+enabled = true
+stats_file = "/var/lib/sccache/tu_stats.db"
+max_age_secs = 604800
+max_records = 100000
+max_size = "2G"
+per_header = true
+include_graph = true
+
+[[translation_unit_stats.include_groups]]
+regex = "^/usr/include/c\\+\\+/"
+name = "libstdc++"
+
+[[translation_unit_stats.include_groups]]
+glob = "fboss/**"
+depth = 2
+
+[translation_unit_stats.budgets]
+max_preprocessed_size = "8M"
+max_includes = 2000
+action = "fail"
+
+[[translation_unit_stats.budgets.prefixes]]
+prefix = "usr/include/boost"
+max_lines = 100000
 "#;
 
     let file_config: FileConfig = toml::from_str(CONFIG_STR).expect("Is valid toml.");
@@ -1612,12 +2040,19 @@ no_credentials = true
         file_config,
         FileConfig {
             cache: CacheConfigs {
+                order: Some(vec![
+                    CacheBackend::Redis,
+                    CacheBackend::S3,
+                    CacheBackend::Gcs
+                ]),
+                write: Some(vec![CacheBackend::Redis, CacheBackend::S3]),
                 azure: None, // TODO not sure how to represent a unit struct in TOML Some(AzureCacheConfig),
                 disk: Some(DiskCacheConfig {
                     dir: PathBuf::from("/tmp/.cache/sccache"),
                     size: 7 * 1024 * 1024 * 1024,
                     preprocessor_cache_mode: PreprocessorCacheModeConfig::activated(),
                     rw_mode: CacheModeConfig::ReadWrite,
+                    tiered: true,
                 }),
                 gcs: Some(GCSCacheConfig {
                     bucket: "bucket".to_owned(),
@@ -1671,6 +2106,11 @@ no_credentials = true
                     key_prefix: "ossprefix".into(),
                     no_credentials: true,
                 }),
+                shared: Some(SharedCacheConfig {
+                    dir: PathBuf::from("/mnt/nfs/sccache"),
+                    size: 50 * 1024 * 1024 * 1024,
+                    gc_time: GcTime::Mtime,
+                }),
             },
             dist: DistConfig {
                 auth: DistAuth::Token {
@@ -1692,7 +2132,37 @@ no_credentials = true
                 remote_only: false,
             },
             server_startup_timeout_ms: Some(10000),
-            translation_unit_stats: Default::default(),
+            translation_unit_stats: TranslationUnitStatsConfig {
+                enabled: true,
+                stats_file: Some(PathBuf::from("/var/lib/sccache/tu_stats.db")),
+                max_age_secs: Some(7 * 24 * 3600),
+                max_records: Some(100000),
+                max_size: Some(2 * 1024 * 1024 * 1024),
+                per_header: true,
+                include_graph: true,
+                include_groups: vec![
+                    IncludeGroupRule {
+                        regex: Some("^/usr/include/c\\+\\+/".to_owned()),
+                        name: Some("libstdc++".to_owned()),
+                        ..Default::default()
+                    },
+                    IncludeGroupRule {
+                        glob: Some("fboss/**".to_owned()),
+                        depth: Some(2),
+                        ..Default::default()
+                    },
+                ],
+                budgets: TuBudgetConfig {
+                    max_preprocessed_size: Some(8 * 1024 * 1024),
+                    max_includes: Some(2000),
+                    prefixes: vec![PrefixBudgetRule {
+                        prefix: "usr/include/boost".to_owned(),
+                        max_lines: 100000,
+                    }],
+                    action: BudgetAction::Fail,
+                },
+            },
+            persist_stats: true,
         }
     )
 }
@@ -1786,6 +2256,7 @@ size = "7g"
             },
             server_startup_timeout_ms: None,
             translation_unit_stats: Default::default(),
+            persist_stats: false,
         }
     );
 }
@@ -26,6 +26,8 @@ mod common {
     #[cfg(feature = "dist-server")]
     use std::collections::HashMap;
     use std::fmt;
+    #[cfg(feature = "dist-client")]
+    use std::time::{Duration, Instant};
 
     use crate::dist;
 
@@ -71,12 +73,25 @@ mod common {
     pub async fn bincode_req_fut<T: serde::de::DeserializeOwned + 'static>(
         req: reqwest::RequestBuilder,
     ) -> Result<T> {
+        bincode_req_fut_timed(req).await.map(|(res, _, _)| res)
+    }
+
+    /// Like `bincode_req_fut`, also returning how long it took until the
+    /// response headers arrived and then to download the response body
+    #[cfg(feature = "dist-client")]
+    pub async fn bincode_req_fut_timed<T: serde::de::DeserializeOwned + 'static>(
+        req: reqwest::RequestBuilder,
+    ) -> Result<(T, Duration, Duration)> {
         // Work around tiny_http issue #151 by disabling HTTP pipeline with
         // `Connection: close`.
+        let start = Instant::now();
         let res = req.header(header::CONNECTION, "close").send().await?;
+        let response_duration = start.elapsed();
 
         let status = res.status();
+        let start = Instant::now();
         let bytes = res.bytes().await?;
+        let download_duration = start.elapsed();
         if !status.is_success() {
             let errmsg = format!(
                 "Error {}: {}",
@@ -89,7 +104,11 @@ mod common {
                 anyhow::bail!(errmsg);
             }
         } else {
-            Ok(bincode::deserialize(&bytes)?)
+            Ok((
+                bincode::deserialize(&bytes)?,
+                response_duration,
+                download_duration,
+            ))
         }
     }
 
@@ -1068,31 +1087,52 @@ mod client {
     use crate::dist::pkg::{InputsPackager, ToolchainPackager};
     use crate::dist::{
         self, AllocJobResult, CompileCommand, JobAlloc, PathTransformer, RunJobResult,
-        SchedulerStatusResult, SubmitToolchainResult, Toolchain,
+        RunJobTimings, SchedulerStatusResult, SubmitToolchainResult, Toolchain,
     };
 
     use async_trait::async_trait;
     use byteorder::{BigEndian, WriteBytesExt};
+    use bytes::Bytes;
     use flate2::Compression;
     use flate2::write::ZlibEncoder as ZlibWriteEncoder;
     use futures::TryFutureExt;
-    use reqwest::Body;
+    use reqwest::{Body, header};
     use std::collections::HashMap;
     use std::env;
-    use std::io::Write;
+    use std::io::{self, Write};
     use std::path::{Path, PathBuf};
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::{Duration, Instant};
 
     use super::common::{
         AllocJobHttpResponse, ReqwestRequestBuilderExt, RunJobHttpRequest,
-        ServerCertificateHttpResponse, bincode_req_fut,
+        ServerCertificateHttpResponse, bincode_req_fut, bincode_req_fut_timed,
     };
     use super::urls;
     use crate::errors::*;
 
     const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 1200;
     const CONNECT_TIMEOUT_SECS: u64 = 5;
+    const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// A request body sending `data` in chunks, which records in `sent` when
+    /// the last chunk is handed over to the connection.
+    fn timed_body(data: Vec<u8>, sent: Arc<OnceLock<Instant>>) -> Body {
+        let stream = futures::stream::unfold(Bytes::from(data), move |mut rest| {
+            let sent = sent.clone();
+            async move {
+                if rest.is_empty() {
+                    return None;
+                }
+                let chunk = rest.split_to(rest.len().min(UPLOAD_CHUNK_SIZE));
+                if rest.is_empty() {
+                    let _ = sent.set(Instant::now());
+                }
+                Some((Ok::<_, io::Error>(chunk), rest))
+            }
+        });
+        Body::wrap_stream(stream)
+    }
 
     /// Get the request timeout in seconds from the environment variable or use the default.
     fn get_request_timeout_secs() -> u64 {
@@ -1285,9 +1325,10 @@ mod client {
             command: CompileCommand,
             outputs: Vec<String>,
             inputs_packager: Box<dyn InputsPackager>,
-        ) -> Result<(RunJobResult, PathTransformer)> {
+        ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)> {
             let url = urls::server_run_job(job_alloc.server_id, job_alloc.job_id);
 
+            let start = Instant::now();
             let (body, path_transformer) = self
                 .pool
                 .spawn_blocking(move || -> Result<_> {
@@ -1318,10 +1359,32 @@ mod client {
                     Ok((body, path_transformer))
                 })
                 .await??;
+            let package_inputs = start.elapsed();
             let mut req = self.client.lock().unwrap().post(url);
-            req = req.bearer_auth(job_alloc.auth.clone()).bytes(body);
-            bincode_req_fut(req)
-                .map_ok(|res| (res, path_transformer))
+            let sent = Arc::new(OnceLock::new());
+            req = req
+                .bearer_auth(job_alloc.auth.clone())
+                .header(
+                    header::CONTENT_TYPE,
+                    mime::APPLICATION_OCTET_STREAM.to_string(),
+                )
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(timed_body(body, sent.clone()));
+            let start = Instant::now();
+            bincode_req_fut_timed(req)
+                .map_ok(move |(res, response, download)| {
+                    // The server only answers once the job ran
+                    let upload_inputs = sent
+                        .get()
+                        .map_or(Duration::ZERO, |sent| sent.duration_since(start));
+                    let timings = RunJobTimings {
+                        package_inputs,
+                        upload_inputs,
+                        execute: response.saturating_sub(upload_inputs),
+                        download,
+                    };
+                    (res, path_transformer, timings)
+                })
                 .await
         }
 
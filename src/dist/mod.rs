@@ -25,6 +25,7 @@ use std::process;
 use std::str::FromStr;
 #[cfg(feature = "dist-server")]
 use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::errors::*;
 
@@ -577,6 +578,18 @@ pub enum RunJobResult {
     JobNotFound,
     Complete(JobComplete),
 }
+/// How long the phases of running a job took, as seen by the client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunJobTimings {
+    /// Packaging and compressing the inputs of the job
+    pub package_inputs: Duration,
+    /// Uploading the inputs of the job
+    pub upload_inputs: Duration,
+    /// Waiting for the server to run the job, once the inputs are uploaded
+    pub execute: Duration,
+    /// Downloading the results of the job
+    pub download: Duration,
+}
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct JobComplete {
@@ -744,7 +757,7 @@ pub trait Client: Send + Sync {
         command: CompileCommand,
         outputs: Vec<String>,
         inputs_packager: Box<dyn pkg::InputsPackager>,
-    ) -> Result<(RunJobResult, PathTransformer)>;
+    ) -> Result<(RunJobResult, PathTransformer, RunJobTimings)>;
     async fn put_toolchain(
         &self,
         compiler_path: PathBuf,
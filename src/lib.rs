@@ -42,6 +42,8 @@ pub mod config;
 pub mod dist;
 mod jobserver;
 pub mod lru_disk_cache;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod mock_command;
 mod net;
 mod protocol;
@@ -0,0 +1,202 @@
+//! A persisted copy of the entries of an `LruDiskCache`, so that it can be
+//! created without looking at every file in the cache.
+//!
+//! The index lists the files in LRU order, along with the directories they
+//! are in and the modification time of those directories. Adding, removing
+//! or renaming a file changes the modification time of its directory, so
+//! when the index is loaded, only the directories that changed since it was
+//! saved are scanned again.
+
+use fs_err as fs;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::{TEMPFILE_PREFIX, is_entry};
+
+/// Name of the index file, in the root directory of the cache.
+pub const INDEX_FILE: &str = ".sccacheindex";
+
+const INDEX_VERSION: u32 = 1;
+
+/// Directories modified this close to the time the index was saved are
+/// scanned again, since a change right after the index was saved may leave
+/// their modification time unchanged on filesystems with coarse timestamps.
+const MTIME_SLACK: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    saved: SystemTime,
+    /// Directories holding files, relative to the root of the cache, with
+    /// their modification time
+    dirs: Vec<(String, SystemTime)>,
+    /// Files relative to the root of the cache, with their size, least
+    /// recently used first
+    files: Vec<(String, u64)>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Save the index of the cache in `root`, which held `files` with their
+/// size, least recently used first, at time `taken`. Directories modified
+/// since then are scanned again by the next load.
+pub fn save(root: &Path, taken: SystemTime, files: &[(OsString, u64)]) -> io::Result<()> {
+    let mut dirs = HashSet::new();
+    dirs.insert(PathBuf::new());
+    let mut entries = vec![];
+    for (file, size) in files {
+        let name = file
+            .to_str()
+            .ok_or_else(|| invalid_data("cache entry path is not UTF-8"))?;
+        let mut dir = Path::new(file).parent();
+        while let Some(d) = dir {
+            if !dirs.insert(d.to_owned()) {
+                break;
+            }
+            dir = d.parent();
+        }
+        entries.push((name.to_owned(), *size));
+    }
+    let dirs = dirs
+        .into_iter()
+        .map(|dir| {
+            let mtime = fs::metadata(root.join(&dir))?.modified()?;
+            // Made of the UTF-8 paths of the files
+            Ok((dir.to_str().unwrap().to_owned(), mtime))
+        })
+        .collect::<io::Result<_>>()?;
+    let index = Index {
+        version: INDEX_VERSION,
+        saved: taken,
+        dirs,
+        files: entries,
+    };
+
+    let mut file = tempfile::Builder::new()
+        .prefix(TEMPFILE_PREFIX)
+        .tempfile_in(root)?;
+    {
+        let mut writer = BufWriter::new(file.as_file_mut());
+        bincode::serialize_into(&mut writer, &index).map_err(io::Error::other)?;
+        writer.flush()?;
+    }
+    file.persist(root.join(INDEX_FILE)).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// The files in a cache, as found by `load`.
+pub struct Loaded {
+    /// The files with their size, least recently used first
+    pub files: Vec<(PathBuf, u64)>,
+    /// Whether the files are exactly the ones in the index, which then
+    /// doesn't need to be saved again
+    pub unchanged: bool,
+}
+
+/// Load the index of the cache in `root`, and return the files in the cache,
+/// or `None` if there is no usable index.
+pub fn load(root: &Path) -> Option<Loaded> {
+    match try_load(root) {
+        Ok(files) => Some(files),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Ignoring the index of the cache in {:?}: {}", root, e);
+            None
+        }
+    }
+}
+
+fn try_load(root: &Path) -> io::Result<Loaded> {
+    let file = fs::File::open(root.join(INDEX_FILE))?;
+    let index: Index = bincode::deserialize_from(BufReader::new(file))
+        .map_err(|e| invalid_data(&e.to_string()))?;
+    if index.version != INDEX_VERSION {
+        return Err(invalid_data("unsupported version"));
+    }
+
+    // Whether each directory is unchanged since the index was saved. The
+    // root is always scanned again, as saving the index changes it.
+    let mut unchanged = HashMap::new();
+    for (dir, mtime) in &index.dirs {
+        let same = !dir.is_empty()
+            && mtime
+                .checked_add(MTIME_SLACK)
+                .is_some_and(|t| t < index.saved)
+            && match fs::metadata(root.join(dir)) {
+                Ok(m) => m.is_dir() && m.modified()? == *mtime,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e),
+            };
+        unchanged.insert(Path::new(dir), same);
+    }
+
+    // The files of the changed directories, with their modification time
+    // and size
+    let mut scanned = HashMap::new();
+    for (dir, _) in unchanged.iter().filter(|(_, same)| !**same) {
+        let entries = match fs::read_dir(root.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_file() {
+                if is_entry(&path) {
+                    let m = entry.metadata()?;
+                    scanned.insert(path, (m.modified()?, m.len()));
+                }
+            } else if file_type.is_dir() && !unchanged.contains_key(path.as_path()) {
+                // A new directory, scan all of it
+                for entry in WalkDir::new(root.join(&path)) {
+                    let entry = entry?;
+                    // Not in the root, so only entries
+                    if entry.file_type().is_file() {
+                        let m = entry.metadata()?;
+                        let path = entry.path().strip_prefix(root).unwrap().to_owned();
+                        scanned.insert(path, (m.modified()?, m.len()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Keep the order of the index for the files it has, and add the new
+    // files as the most recently used ones
+    let mut files = Vec::with_capacity(index.files.len());
+    let mut same_files = true;
+    for (file, size) in &index.files {
+        let file = Path::new(file);
+        let dir = file.parent().unwrap_or(Path::new(""));
+        if unchanged.get(dir).copied().unwrap_or(false) {
+            files.push((root.join(file), *size));
+        } else if let Some((_, scanned_size)) = scanned.remove(file) {
+            same_files &= scanned_size == *size;
+            files.push((root.join(file), scanned_size));
+        } else {
+            same_files = false;
+        }
+    }
+    same_files &= scanned.is_empty();
+    let mut new_files: Vec<_> = scanned.into_iter().collect();
+    new_files.sort_by_key(|(_, (mtime, _))| *mtime);
+    files.extend(
+        new_files
+            .into_iter()
+            .map(|(file, (_, size))| (root.join(file), size)),
+    );
+    Ok(Loaded {
+        files,
+        unchanged: same_files,
+    })
+}
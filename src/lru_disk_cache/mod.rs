@@ -1,3 +1,4 @@
+mod index;
 pub mod lru_cache;
 
 use fs::File;
@@ -12,6 +13,8 @@ use std::hash::BuildHasher;
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 use filetime::{FileTime, set_file_times};
 pub use lru_cache::{LruCache, Meter};
@@ -20,7 +23,22 @@ use walkdir::WalkDir;
 
 use crate::util::OsStrExt;
 
-const TEMPFILE_PREFIX: &str = ".sccachetmp";
+/// Prefix of temporary files, which are removed when the cache is created.
+pub const TEMPFILE_PREFIX: &str = ".sccachetmp";
+
+/// Name of the file the server keeps its statistics in across restarts, in the root of the
+/// cache, see `Config::stats_file`.
+pub const STATS_FILE: &str = "stats.json";
+
+/// Whether the file at `rel_path` in the cache is an entry, rather than one of the files kept
+/// next to the entries in the root of the cache.
+fn is_entry(rel_path: &Path) -> bool {
+    rel_path != Path::new(index::INDEX_FILE) && rel_path != Path::new(STATS_FILE)
+}
+
+/// Save the index of the cache this often when it changes, so that little is
+/// scanned again at startup after a crash.
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 struct FileSize;
 
@@ -42,8 +60,9 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<dyn Iterator<Item = (PathBuf, u
         .into_iter()
         .filter_map(|e| {
             e.ok().and_then(|f| {
-                // Only look at files
-                if f.file_type().is_file() {
+                // Only look at files, but not the index or the stats
+                let rel_path = f.path().strip_prefix(path.as_ref()).ok()?;
+                if f.file_type().is_file() && is_entry(rel_path) {
                     // Get the last-modified time, size, and the full path.
                     f.metadata().ok().and_then(|m| {
                         m.modified()
@@ -67,6 +86,11 @@ pub struct LruDiskCache<S: BuildHasher = RandomState> {
     root: PathBuf,
     pending: Vec<OsString>,
     pending_size: u64,
+    /// Whether the entries changed since the index was last saved
+    dirty: bool,
+    index_saved: Instant,
+    /// The thread saving the index in the background, if any
+    index_saver: Option<JoinHandle<()>>,
 }
 
 /// Errors returned by this crate.
@@ -135,8 +159,9 @@ impl LruDiskCache {
     /// Create an `LruDiskCache` that stores files in `path`, limited to `size` bytes.
     ///
     /// Existing files in `path` will be stored with their last-modified time from the filesystem
-    /// used as the order for the recency of their use. Any files that are individually larger
-    /// than `size` bytes will be removed.
+    /// used as the order for the recency of their use, unless the index saved by a previous
+    /// `LruDiskCache` has it. Any files that are individually larger than `size` bytes will be
+    /// removed.
     ///
     /// The cache is not observant of changes to files under `path` from external sources, it
     /// expects to have sole maintence of the contents.
@@ -149,6 +174,9 @@ impl LruDiskCache {
             root: PathBuf::from(path),
             pending: vec![],
             pending_size: 0,
+            dirty: false,
+            index_saved: Instant::now(),
+            index_saver: None,
         }
         .init()
     }
@@ -182,10 +210,20 @@ impl LruDiskCache {
         self.root.join(rel_path)
     }
 
-    /// Scan `self.root` for existing files and store them.
+    /// Load the index of `self.root`, or scan it for existing files, and store them.
     fn init(mut self) -> Result<Self> {
         fs::create_dir_all(&self.root)?;
-        for (file, size) in get_all_files(&self.root) {
+        let (files, unchanged): (Box<dyn Iterator<Item = (PathBuf, u64)>>, _) =
+            match index::load(&self.root) {
+                Some(loaded) => (Box::new(loaded.files.into_iter()), loaded.unchanged),
+                None => {
+                    debug!("Scanning the cache in {:?}", self.root);
+                    (get_all_files(&self.root), false)
+                }
+            };
+        let mut count = 0;
+        for (file, size) in files {
+            count += 1;
             if file
                 .file_name()
                 .expect("Bad path?")
@@ -206,6 +244,10 @@ impl LruDiskCache {
                     .unwrap_or_else(|e| error!("Error adding file: {}", e));
             }
         }
+        // Only save the index again if it doesn't describe the cache as it is
+        if !unchanged || self.lru.len() != count {
+            self.save_index();
+        }
         Ok(self)
     }
 
@@ -218,10 +260,7 @@ impl LruDiskCache {
         if !self.can_store(size) {
             return Err(Error::FileTooLarge);
         }
-        //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
-        while self.size() + size > self.capacity() {
-            let (rel_path, _) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
-            let remove_path = self.rel_to_abs_path(rel_path);
+        for remove_path in self.evict(size)? {
             //TODO: check that files are removable during `init`, so that this is only
             // due to outside interference.
             fs::remove_file(&remove_path).unwrap_or_else(|e| {
@@ -246,6 +285,27 @@ impl LruDiskCache {
         Ok(())
     }
 
+    /// Remove the least recently used entries until there is room for `size` more bytes, and
+    /// return the paths of their files, which are left for the caller to remove.
+    fn evict(&mut self, size: u64) -> Result<Vec<PathBuf>> {
+        if !self.can_store(size) {
+            return Err(Error::FileTooLarge);
+        }
+        //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
+        let mut evicted = vec![];
+        while self.size() + size > self.capacity() {
+            // The rest of the space is reserved by files still being written
+            let Some((rel_path, _)) = self.lru.remove_lru() else {
+                break;
+            };
+            evicted.push(self.rel_to_abs_path(rel_path));
+        }
+        if !evicted.is_empty() {
+            self.changed();
+        }
+        Ok(evicted)
+    }
+
     /// Add the file at `path` of size `size` to the cache.
     fn add_file(&mut self, addfile_path: AddFile<'_>, size: u64) -> Result<()> {
         let rel_path = match addfile_path {
@@ -286,7 +346,9 @@ impl LruDiskCache {
                 fs::remove_file(self.rel_to_abs_path(rel_path))
                     .expect("Failed to remove file we just created!");
                 e
-            })
+            })?;
+        self.changed();
+        Ok(())
     }
 
     /// Add a file by calling `with` with the open `File` corresponding to the cache at path `key`.
@@ -363,9 +425,39 @@ impl LruDiskCache {
         fs::create_dir_all(path.parent().unwrap())?;
         file.persist(path).map_err(|e| e.error)?;
         self.lru.insert(key, real_size);
+        self.changed();
         Ok(())
     }
 
+    /// Reserve `size` bytes for a file at path `key`, which the caller writes without holding
+    /// on to the cache, with `write_reserved`. Room is made by evicting the least recently
+    /// used entries, whose paths are returned so that the caller can remove their files with
+    /// `remove_evicted`, again without holding on to the cache. The reservation must be ended
+    /// with `LruDiskCache::insert_reserved` once the file is in place, or with
+    /// `LruDiskCache::release`.
+    pub fn reserve<K: AsRef<OsStr>>(&mut self, key: K, size: u64) -> Result<Vec<PathBuf>> {
+        let evicted = self.evict(size)?;
+        self.pending.push(key.as_ref().to_owned());
+        self.pending_size += size;
+        Ok(evicted)
+    }
+
+    /// Add the file at path `key`, written after `LruDiskCache::reserve`, to the cache.
+    pub fn insert_reserved<K: AsRef<OsStr>>(&mut self, key: K, size: u64) {
+        self.release(&key, size);
+        self.lru.insert(key.as_ref().to_owned(), size);
+        self.changed();
+    }
+
+    /// Give back the space reserved by `LruDiskCache::reserve` for a file that couldn't be
+    /// written.
+    pub fn release<K: AsRef<OsStr>>(&mut self, key: K, size: u64) {
+        if let Some(i) = self.pending.iter().position(|k| k == key.as_ref()) {
+            self.pending.remove(i);
+            self.pending_size -= size;
+        }
+    }
+
     /// Return `true` if a file with path `key` is in the cache. Entries created
     /// by `LruDiskCache::prepare_add` but not yet committed return `false`.
     pub fn contains_key<K: AsRef<OsStr>>(&self, key: K) -> bool {
@@ -379,14 +471,21 @@ impl LruDiskCache {
     pub fn get_file<K: AsRef<OsStr>>(&mut self, key: K) -> Result<File> {
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
-        self.lru
-            .get(rel_path)
-            .ok_or(Error::FileNotInCache)
-            .and_then(|_| {
-                let t = FileTime::now();
-                set_file_times(&path, t, t)?;
-                File::open(path).map_err(Into::into)
-            })
+        self.lru.get(rel_path).ok_or(Error::FileNotInCache)?;
+        self.changed();
+        let t = FileTime::now();
+        set_file_times(&path, t, t)?;
+        File::open(path).map_err(Into::into)
+    }
+
+    /// Return the path of the file at `key` if it is in the cache, and update its LRU state,
+    /// without touching the file, so that it can be opened without holding on to the cache.
+    /// The file may be gone by then, if it was evicted in the meantime.
+    pub fn lookup<K: AsRef<OsStr>>(&mut self, key: K) -> Option<PathBuf> {
+        let rel_path = key.as_ref();
+        self.lru.get(rel_path)?;
+        self.changed();
+        Some(self.rel_to_abs_path(rel_path))
     }
 
     /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
@@ -397,10 +496,23 @@ impl LruDiskCache {
         self.get_file(key).map(|f| Box::new(f) as Box<dyn ReadSeek>)
     }
 
+    /// Remove the given key from the cache if its file is gone, e.g. because it was evicted or
+    /// removed from outside after `LruDiskCache::lookup`. The file is checked again here, so that
+    /// an entry added back in the meantime is kept.
+    pub fn forget_missing<K: AsRef<OsStr>>(&mut self, key: K) {
+        let rel_path = key.as_ref();
+        if !self.lru.contains_key(rel_path) || self.rel_to_abs_path(rel_path).exists() {
+            return;
+        }
+        self.lru.remove(rel_path);
+        self.changed();
+    }
+
     /// Remove the given key from the cache.
     pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
         match self.lru.remove(key.as_ref()) {
             Some(_) => {
+                self.changed();
                 let path = self.rel_to_abs_path(key.as_ref());
                 fs::remove_file(&path).map_err(|e| {
                     error!("Error removing file from cache: `{:?}`: {}", path, e);
@@ -412,14 +524,124 @@ impl LruDiskCache {
     }
 }
 
+/// Write `data` to the file at path `key` of the cache in `root`, for which space was reserved
+/// with `LruDiskCache::reserve`. The data goes to a temporary file that is renamed into place, so
+/// that readers never see a partial file.
+pub fn write_reserved<K: AsRef<OsStr>>(root: &Path, key: K, data: &[u8]) -> io::Result<()> {
+    let mut file = tempfile::Builder::new()
+        .prefix(TEMPFILE_PREFIX)
+        .tempfile_in(root)?;
+    file.write_all(data)?;
+    let path = root.join(key.as_ref());
+    fs::create_dir_all(path.parent().expect("Bad path?"))?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Remove the files of the entries evicted by `LruDiskCache::reserve`.
+pub fn remove_evicted(paths: Vec<PathBuf>) {
+    for path in paths {
+        if let Err(e) = fs::remove_file(&path) {
+            // Other users of the cache may have removed it already
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Error removing file from cache: `{:?}`: {}", path, e);
+            }
+        }
+    }
+}
+
+impl<S: BuildHasher> LruDiskCache<S> {
+    /// Save the index of the cache, so that the next `LruDiskCache` for this path doesn't
+    /// need to scan it.
+    fn save_index(&mut self) {
+        self.wait_for_index_saver();
+        let (taken, files) = self.index_snapshot();
+        save_index(&self.root, taken, &files);
+        self.dirty = false;
+        self.index_saved = Instant::now();
+    }
+
+    /// Save the index of the cache from a background thread. Only the copy of the entries is
+    /// made here, so that users of the cache don't wait for the whole index to be written.
+    fn save_index_in_background(&mut self) {
+        if self
+            .index_saver
+            .as_ref()
+            .is_some_and(|saver| !saver.is_finished())
+        {
+            // Try again on the next change
+            return;
+        }
+        let root = self.root.clone();
+        let (taken, files) = self.index_snapshot();
+        let saver = thread::Builder::new()
+            .name("sccache-cache-index".to_owned())
+            .spawn(move || save_index(&root, taken, &files));
+        match saver {
+            Ok(saver) => self.index_saver = Some(saver),
+            Err(e) => warn!("Failed to start saving the index of the cache: {}", e),
+        }
+        self.dirty = false;
+        self.index_saved = Instant::now();
+    }
+
+    /// The entries of the cache with their size, least recently used first, along with the
+    /// time they were copied. Files added later are only found by the next load if they
+    /// changed their directory after that time.
+    fn index_snapshot(&self) -> (SystemTime, Vec<(OsString, u64)>) {
+        let taken = SystemTime::now();
+        let files = self
+            .lru
+            .iter()
+            .map(|(file, size)| (file.clone(), *size))
+            .collect();
+        (taken, files)
+    }
+
+    fn wait_for_index_saver(&mut self) {
+        if let Some(saver) = self.index_saver.take() {
+            let _ = saver.join();
+        }
+    }
+
+    /// Note that the entries changed, and save the index if it was saved long enough ago.
+    fn changed(&mut self) {
+        self.dirty = true;
+        if self.index_saved.elapsed() >= INDEX_SAVE_INTERVAL {
+            self.save_index_in_background();
+        }
+    }
+}
+
+fn save_index(root: &Path, taken: SystemTime, files: &[(OsString, u64)]) {
+    if let Err(e) = index::save(root, taken, files) {
+        warn!("Failed to save the index of the cache in {:?}: {}", root, e);
+    }
+}
+
+impl<S: BuildHasher> Drop for LruDiskCache<S> {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.save_index();
+        } else {
+            self.wait_for_index_saver();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::fs::{self, File};
-    use super::{Error, LruDiskCache, LruDiskCacheAddEntry, get_all_files};
+    use super::index::INDEX_FILE;
+    use super::{
+        Error, INDEX_SAVE_INTERVAL, LruDiskCache, LruDiskCacheAddEntry, STATS_FILE,
+        TEMPFILE_PREFIX, get_all_files, remove_evicted, save_index, write_reserved,
+    };
 
     use filetime::{FileTime, set_file_times};
     use std::io::{self, Read, Write};
     use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
     use tempfile::TempDir;
 
     struct TestFixture {
@@ -697,6 +919,49 @@ mod tests {
         assert!(get_all_files(cache_dir).all(|(file, _)| file != path));
     }
 
+    #[test]
+    fn test_reserve_and_insert() {
+        let f = TestFixture::new();
+        let cache_dir = f.tmp();
+        let mut c = LruDiskCache::new(cache_dir, 25).unwrap();
+        c.insert_bytes("a/b/c", &[0; 10]).unwrap();
+        c.insert_bytes("a/b/d", &[0; 10]).unwrap();
+
+        // Making room evicts the least recently used entry, but leaves its
+        // file for the caller to remove.
+        let evicted = c.reserve("x/y/z", 10).unwrap();
+        assert_eq!(evicted, vec![cache_dir.join("a/b/c")]);
+        assert!(!c.contains_key("a/b/c"));
+        assert!(evicted[0].exists());
+        remove_evicted(evicted);
+        assert!(!f.tmp().join("a/b/c").exists());
+        assert_eq!(c.size(), 20);
+        assert_eq!(c.lru.size(), 10);
+
+        // The reserved entry only appears once inserted.
+        assert_eq!(c.lookup("x/y/z"), None);
+        write_reserved(cache_dir, "x/y/z", &[1; 10]).unwrap();
+        c.insert_reserved("x/y/z", 10);
+        assert_eq!(c.size(), 20);
+        assert_eq!(c.lru.size(), 20);
+        let path = c.lookup("x/y/z").unwrap();
+        assert_eq!(fs::read(path).unwrap(), vec![1; 10]);
+
+        // Releasing a reservation gives back its space.
+        assert_eq!(c.reserve("x/y/w", 5).unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(c.size(), 25);
+        c.release("x/y/w", 5);
+        assert_eq!(c.size(), 20);
+
+        // Only entries whose file is gone are forgotten.
+        c.forget_missing("x/y/z");
+        assert!(c.contains_key("x/y/z"));
+        fs::remove_file(cache_dir.join("x/y/z")).unwrap();
+        c.forget_missing("x/y/z");
+        assert!(!c.contains_key("x/y/z"));
+        assert_eq!(c.size(), 10);
+    }
+
     #[test]
     fn test_remove() {
         let f = TestFixture::new();
@@ -729,4 +994,137 @@ mod tests {
         assert!(!f.tmp().join("cache").join("file2").exists());
         assert!(!p4.exists());
     }
+
+    #[test]
+    fn test_index_keeps_lru_order() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        c.insert_bytes("a/file2", &[0; 10]).unwrap();
+        c.get("a/file1").unwrap();
+        drop(c);
+        assert!(f.tmp().join(INDEX_FILE).exists());
+
+        // The order of the index wins over the modification times
+        set_mtime_back(f.tmp().join("a/file1"), 1000);
+        let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+        assert_eq!(c.len(), 2);
+        c.insert_bytes("a/file3", &[0; 10]).unwrap();
+        c.insert_bytes("a/file4", &[0; 10]).unwrap();
+        assert!(c.contains_key("a/file1"));
+        assert!(!c.contains_key("a/file2"));
+    }
+
+    #[test]
+    fn test_index_saves() {
+        let f = TestFixture::new();
+        let index = f.tmp().join(INDEX_FILE);
+        let index_mtime = || FileTime::from_last_modification_time(&fs::metadata(&index).unwrap());
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        drop(c);
+
+        // An index that matches the cache is not saved again
+        set_mtime_back(&index, 100);
+        let saved = index_mtime();
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert_eq!(index_mtime(), saved);
+
+        // Changes are saved from the background once the interval elapsed
+        c.index_saved = Instant::now() - INDEX_SAVE_INTERVAL;
+        c.insert_bytes("a/file2", &[0; 10]).unwrap();
+        assert!(!c.dirty);
+        c.wait_for_index_saver();
+        assert_ne!(index_mtime(), saved);
+        // Not dirty, so the index read back is the one saved in the background
+        drop(c);
+        let c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert!(c.contains_key("a/file2"));
+    }
+
+    #[test]
+    fn test_index_saved_at_snapshot() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        let (taken, files) = c.index_snapshot();
+        c.dirty = false;
+        drop(c);
+
+        // A file added after the snapshot, while a slow save was still
+        // running: its directory changed after the snapshot, but well before
+        // the index got written.
+        let taken = taken - Duration::from_secs(10);
+        f.create_file("a/late", 10);
+        let late = FileTime::from_system_time(taken + Duration::from_secs(5));
+        filetime::set_file_mtime(f.tmp().join("a"), late).unwrap();
+        save_index(f.tmp(), taken, &files);
+
+        let c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert!(c.contains_key("a/late"));
+        assert_eq!(c.size(), 20);
+    }
+
+    #[test]
+    fn test_index_rescans_changed_dirs() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        c.insert_bytes("b/file2", &[0; 10]).unwrap();
+        set_mtime_back(f.tmp().join("a"), 100);
+        set_mtime_back(f.tmp().join("b"), 100);
+        let b_mtime =
+            FileTime::from_last_modification_time(&fs::metadata(f.tmp().join("b")).unwrap());
+        drop(c);
+
+        // A file the index doesn't know of, in a directory that looks unchanged
+        f.create_file("b/hidden", 10);
+        filetime::set_file_mtime(f.tmp().join("b"), b_mtime).unwrap();
+        fs::remove_file(f.tmp().join("a/file1")).unwrap();
+        f.create_file("c/file3", 10);
+
+        let c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert!(!c.contains_key("a/file1"));
+        assert!(c.contains_key("b/file2"));
+        assert!(!c.contains_key("b/hidden"));
+        assert!(c.contains_key("c/file3"));
+        assert_eq!(c.size(), 20);
+    }
+
+    #[test]
+    fn test_stats_file_not_an_entry() {
+        let f = TestFixture::new();
+        f.create_file("a/file1", 10);
+        f.create_file(STATS_FILE, 10);
+        f.create_file(format!("{TEMPFILE_PREFIX}stats"), 10);
+        let mut c = LruDiskCache::new(f.tmp(), 20).unwrap();
+        assert!(!c.contains_key(STATS_FILE));
+        assert!(!f.tmp().join(format!("{TEMPFILE_PREFIX}stats")).exists());
+        assert_eq!(c.size(), 10);
+        c.insert_bytes("a/file2", &[0; 10]).unwrap();
+        drop(c);
+
+        // Neither counted when the root is rescanned from the index, so the
+        // stats are never evicted
+        fs::write(f.tmp().join(STATS_FILE), [0; 20]).unwrap();
+        let mut c = LruDiskCache::new(f.tmp(), 20).unwrap();
+        assert_eq!(c.size(), 20);
+        c.insert_bytes("a/file3", &[0; 10]).unwrap();
+        assert!(f.tmp().join(STATS_FILE).exists());
+    }
+
+    #[test]
+    fn test_corrupt_index() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.insert_bytes("a/file1", &[0; 10]).unwrap();
+        drop(c);
+        fs::write(f.tmp().join(INDEX_FILE), b"garbage").unwrap();
+        f.create_file("b/file2", 10);
+
+        let c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert!(c.contains_key("a/file1"));
+        assert!(c.contains_key("b/file2"));
+        assert_eq!(c.size(), 20);
+    }
 }
@@ -0,0 +1,375 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP endpoint exposing the server statistics in the OpenMetrics text
+//! format, for Prometheus and compatible scrapers.
+
+use crate::errors::*;
+use crate::server::{MetricsSource, ServerInfo};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Method, Request, Response, StatusCode};
+use std::fmt::{Display, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Environment variable holding the address the endpoint listens on. The
+/// endpoint is disabled when it is unset.
+pub const METRICS_ADDR_ENV_VAR: &str = "SCCACHE_METRICS_ADDR";
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The address from `SCCACHE_METRICS_ADDR`, if it is set and valid
+pub fn addr_from_env() -> Option<SocketAddr> {
+    let addr = std::env::var(METRICS_ADDR_ENV_VAR).ok()?;
+    match addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(e) => {
+            warn!("Invalid {}: {:?}: {}", METRICS_ADDR_ENV_VAR, addr, e);
+            None
+        }
+    }
+}
+
+/// Serve `/metrics` on `addr` until the server shuts down
+pub async fn serve(addr: SocketAddr, source: MetricsSource) -> Result<()> {
+    use hyper::server::conn::http1;
+    use hyper_util::rt::tokio::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind the metrics endpoint to {}", addr))?;
+    info!("serving metrics on http://{}/metrics", addr);
+    loop {
+        let tcp = match listener.accept().await {
+            Ok((tcp, _)) => tcp,
+            // e.g. out of file descriptors, which may not last
+            Err(e) => {
+                warn!("Failed to accept a metrics connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let source = source.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |req| {
+                let source = source.clone();
+                async move { Ok::<_, hyper::Error>(handle(req, &source).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(tcp), service)
+                .await
+            {
+                debug!("metrics connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle<B>(req: Request<B>, source: &MetricsSource) -> Response<Full<Bytes>> {
+    let (status, content_type, body) = if req.uri().path() != "/metrics" {
+        (
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "not found\n".to_owned(),
+        )
+    } else if req.method() != Method::GET {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "method not allowed\n".to_owned(),
+        )
+    } else {
+        match source.server_info().await {
+            Ok(info) => (
+                StatusCode::OK,
+                CONTENT_TYPE,
+                render(&info, source.active_compiles()),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                format!("failed to read server statistics: {}\n", e),
+            ),
+        }
+    };
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+/// Writes metric families in the OpenMetrics text format
+#[derive(Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, unit: Option<&str>, help: &str) {
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        if let Some(unit) = unit {
+            let _ = writeln!(self.out, "# UNIT {} {}", name, unit);
+        }
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+    }
+
+    fn sample(&mut self, name: &str, label: Option<(&str, &str)>, value: impl Display) {
+        match label {
+            Some((label, label_value)) => {
+                let label_value = label_value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = writeln!(
+                    self.out,
+                    "{}{{{}=\"{}\"}} {}",
+                    name, label, label_value, value
+                );
+            }
+            None => {
+                let _ = writeln!(self.out, "{} {}", name, value);
+            }
+        }
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", None, help);
+        self.sample(&format!("{}_total", name), None, value);
+    }
+
+    fn seconds_counter(&mut self, name: &str, help: &str, value: Duration) {
+        self.family(name, "counter", Some("seconds"), help);
+        self.sample(&format!("{}_total", name), None, value.as_secs_f64());
+    }
+
+    /// A counter with one sample per `label` value, in a stable order
+    fn labelled_counter<'a, T: Copy + Display + 'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        values: impl IntoIterator<Item = (&'a String, &'a T)>,
+    ) {
+        self.family(name, "counter", None, help);
+        let mut values: Vec<_> = values.into_iter().collect();
+        values.sort_by_key(|(label_value, _)| *label_value);
+        let total = format!("{}_total", name);
+        for (label_value, value) in values {
+            self.sample(&total, Some((label, label_value)), *value);
+        }
+    }
+
+    fn gauge(&mut self, name: &str, unit: Option<&str>, help: &str, value: impl Display) {
+        self.family(name, "gauge", unit, help);
+        self.sample(name, None, value);
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+/// Render the server statistics in the OpenMetrics text format
+pub fn render(info: &ServerInfo, active_compiles: usize) -> String {
+    let stats = &info.stats;
+    let mut w = MetricsWriter::default();
+    w.counter(
+        "sccache_compile_requests",
+        "Compile requests received from clients.",
+        stats.compile_requests,
+    );
+    w.counter(
+        "sccache_requests_executed",
+        "Compile requests executed.",
+        stats.requests_executed,
+    );
+    w.counter(
+        "sccache_requests_unsupported_compiler",
+        "Requests for an unsupported compiler.",
+        stats.requests_unsupported_compiler,
+    );
+    w.counter(
+        "sccache_requests_not_compile",
+        "Requests that were not compilations.",
+        stats.requests_not_compile,
+    );
+    w.counter(
+        "sccache_requests_not_cacheable",
+        "Requests that were not cacheable.",
+        stats.requests_not_cacheable,
+    );
+    w.labelled_counter(
+        "sccache_cache_hits",
+        "Cache hits, per language.",
+        "language",
+        stats.cache_hits.counts(),
+    );
+    w.labelled_counter(
+        "sccache_cache_misses",
+        "Cache misses, per language.",
+        "language",
+        stats.cache_misses.counts(),
+    );
+    w.labelled_counter(
+        "sccache_cache_errors",
+        "Errors handling compile requests, per language.",
+        "language",
+        stats.cache_errors.counts(),
+    );
+    w.counter(
+        "sccache_cache_timeouts",
+        "Cache lookups that timed out.",
+        stats.cache_timeouts,
+    );
+    w.counter(
+        "sccache_cache_read_errors",
+        "Errors reading cache entries.",
+        stats.cache_read_errors,
+    );
+    w.counter(
+        "sccache_forced_recaches",
+        "Compilations that ignored the cache.",
+        stats.forced_recaches,
+    );
+    w.counter(
+        "sccache_cache_writes",
+        "Successful cache writes.",
+        stats.cache_writes,
+    );
+    w.counter(
+        "sccache_cache_write_errors",
+        "Errors writing cache entries.",
+        stats.cache_write_errors,
+    );
+    w.seconds_counter(
+        "sccache_cache_write_seconds",
+        "Time spent writing cache entries.",
+        stats.cache_write_duration,
+    );
+    w.seconds_counter(
+        "sccache_cache_read_hit_seconds",
+        "Time spent reading cache hits.",
+        stats.cache_read_hit_duration,
+    );
+    w.counter(
+        "sccache_compilations",
+        "Compilations performed.",
+        stats.compilations,
+    );
+    w.seconds_counter(
+        "sccache_compilation_seconds",
+        "Time spent compiling.",
+        stats.compiler_write_duration,
+    );
+    w.counter(
+        "sccache_compile_fails",
+        "Failed compilations.",
+        stats.compile_fails,
+    );
+    w.counter(
+        "sccache_non_cacheable_compilations",
+        "Successful compilations that could not be cached.",
+        stats.non_cacheable_compilations,
+    );
+    w.labelled_counter(
+        "sccache_not_cached",
+        "Compilations not cached, per reason.",
+        "reason",
+        &stats.not_cached,
+    );
+    w.labelled_counter(
+        "sccache_dist_compiles",
+        "Distributed compilations, per server.",
+        "server",
+        &stats.dist_compiles,
+    );
+    w.counter(
+        "sccache_dist_errors",
+        "Distributed compilations that failed and were run locally.",
+        stats.dist_errors,
+    );
+    w.gauge(
+        "sccache_active_compiles",
+        None,
+        "Compilations in progress.",
+        active_compiles,
+    );
+    if let Some(size) = info.cache_size {
+        w.gauge(
+            "sccache_cache_size_bytes",
+            Some("bytes"),
+            "Size of the cache.",
+            size,
+        );
+    }
+    if let Some(size) = info.max_cache_size {
+        w.gauge(
+            "sccache_cache_max_size_bytes",
+            Some("bytes"),
+            "Maximum size of the cache.",
+            size,
+        );
+    }
+    w.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::ServerStats;
+
+    #[test]
+    fn test_render() {
+        let mut stats = ServerStats {
+            compile_requests: 3,
+            cache_write_duration: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        stats.dist_compiles.insert("10.0.0.2:10501".to_owned(), 2);
+        stats.not_cached.insert("say \"hi\"".to_owned(), 1);
+        let info = ServerInfo {
+            stats,
+            cache_location: String::new(),
+            cache_size: Some(1024),
+            max_cache_size: None,
+            use_preprocessor_cache_mode: false,
+            version: String::new(),
+        };
+
+        let metrics = render(&info, 4);
+        let lines: Vec<_> = metrics.lines().collect();
+        for expected in [
+            "# TYPE sccache_compile_requests counter",
+            "sccache_compile_requests_total 3",
+            "# UNIT sccache_cache_write_seconds seconds",
+            "sccache_cache_write_seconds_total 1.5",
+            "sccache_dist_compiles_total{server=\"10.0.0.2:10501\"} 2",
+            "sccache_not_cached_total{reason=\"say \\\"hi\\\"\"} 1",
+            "sccache_active_compiles 4",
+            "sccache_cache_size_bytes 1024",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "missing {:?} in\n{}",
+                expected,
+                metrics
+            );
+        }
+        assert!(!metrics.contains("sccache_cache_max_size_bytes"));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
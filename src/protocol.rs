@@ -1,7 +1,9 @@
 use crate::compiler::ColorMode;
 use crate::server::{DistInfo, ServerInfo};
+use crate::tu_stats::{RetentionPolicy, TuStatsFilter};
 use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
+use std::path::PathBuf;
 
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,6 +18,14 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Get the statistics of a single session.
+    GetSessionStats(String),
+    /// Get a page of the translation unit statistics the server recorded.
+    GetTuStats(TuStatsQuery),
+    /// Get the statistics accumulated over the runs of the server.
+    GetLifetimeStats,
+    /// Prune the translation unit statistics the server records.
+    PruneTuStats(TuStatsPrune),
 }
 
 /// A server response.
@@ -33,6 +43,19 @@ pub enum Response {
     ShuttingDown(Box<ServerInfo>),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::GetSessionStats`, containing the session's statistics,
+    /// or `None` if the server has none for it.
+    SessionStats(Option<Box<ServerInfo>>),
+    /// Response for `Request::GetTuStats`, or `None` if the server does not
+    /// record to the requested database.
+    TuStats(Option<TuStatsPage>),
+    /// Response for `Request::GetLifetimeStats`, or `None` if the server does
+    /// not keep its statistics across restarts.
+    LifetimeStats(Option<Box<ServerInfo>>),
+    /// Response for `Request::PruneTuStats`, with the number of deleted
+    /// records, or `None` if the server does not record to the requested
+    /// database.
+    TuStatsPruned(Option<u64>),
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -78,3 +101,37 @@ pub struct Compile {
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
 }
+
+/// A query of the translation unit statistics recorded by the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TuStatsQuery {
+    /// The database to query, or the default one if `None`.
+    pub stats_file: Option<PathBuf>,
+    /// The records to return.
+    pub filter: TuStatsFilter,
+    /// The key of the last record of the previous page, since large results
+    /// are returned over several requests.
+    pub after: Option<Vec<u8>>,
+}
+
+/// A page of the results of a `TuStatsQuery`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TuStatsPage {
+    /// The records, as a JSON array, since include trees leave out empty
+    /// lists of children with `skip_serializing_if`, which doesn't round-trip
+    /// through bincode.
+    pub records: Vec<u8>,
+    /// The key of the last record of the page, to query the next one from,
+    /// if there is one.
+    pub next: Option<Vec<u8>>,
+}
+
+/// A request to apply retention limits to the translation unit statistics
+/// recorded by the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TuStatsPrune {
+    /// The database to prune, or the default one if `None`.
+    pub stats_file: Option<PathBuf>,
+    /// The limits to apply.
+    pub policy: RetentionPolicy,
+}
@@ -16,17 +16,20 @@ use crate::cache::readonly::ReadOnlyStorage;
 use crate::cache::{CacheMode, Storage, storage_from_config};
 use crate::compiler::{
     CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher, CompilerKind,
-    CompilerProxy, DistType, Language, MissType, get_compiler_info,
+    CompilerProxy, DistType, Language, MissTimings, MissType, get_compiler_info,
 };
 #[cfg(feature = "dist-client")]
 use crate::config;
 use crate::config::Config;
 use crate::dist;
 use crate::jobserver::Client;
+use crate::lru_disk_cache::LruCache;
 use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use crate::protocol::{
+    Compile, CompileFinished, CompileResponse, Request, Response, TuStatsPage, TuStatsPrune,
+    TuStatsQuery,
+};
 use crate::util;
-#[cfg(feature = "dist-client")]
 use anyhow::Context as _;
 use bytes::{Bytes, BytesMut, buf::BufMut};
 use filetime::FileTime;
@@ -38,7 +41,7 @@ use futures::{Sink, SinkExt, Stream, StreamExt, TryFutureExt, future, stream};
 use number_prefix::NumberPrefix;
 use serde::{Deserialize, Serialize};
 use std::cell::Cell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsString;
 use std::future::Future;
@@ -54,6 +57,7 @@ use std::path::PathBuf;
 use std::pin::Pin;
 use std::process::{ExitStatus, Output};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::task::{Context, Poll, Waker};
 use std::time::Duration;
 #[cfg(feature = "dist-client")]
@@ -79,6 +83,17 @@ const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 #[cfg(feature = "dist-client")]
 const DIST_CLIENT_RECREATE_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// Environment variable a client sets to tag its compile requests with a
+/// session, such as a CI job, whose stats are then also tracked separately.
+pub const SESSION_ENV_VAR: &str = "SCCACHE_SESSION";
+
+/// Keep the stats of this many of the most recently active sessions.
+const MAX_SESSIONS: u64 = 256;
+
+/// Stop filling a page of TU stats past this many bytes, well under the
+/// default maximum frame length.
+const TU_STATS_PAGE_SIZE: usize = 4 * 1024 * 1024;
+
 /// Result of background server startup.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerStartup {
@@ -92,6 +107,102 @@ pub enum ServerStartup {
     Err { reason: String },
 }
 
+/// Save the persisted stats this often, so that little is lost if the
+/// server does not shut down cleanly.
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The statistics of the previous runs of the server, which are kept in a
+/// file along with those of the current run.
+#[derive(Clone)]
+pub struct PersistedStats {
+    path: PathBuf,
+    previous: Arc<Mutex<ServerStats>>,
+}
+
+impl PersistedStats {
+    /// Load the statistics saved in `path`. Starts from zero if the file
+    /// is missing or unreadable, rather than keeping the server from starting.
+    pub fn load(path: PathBuf) -> Self {
+        let previous = match Self::read(&path) {
+            Ok(stats) => stats.unwrap_or_default(),
+            Err(e) => {
+                warn!("Ignoring the saved stats in {}: {:#}", path.display(), e);
+                ServerStats::default()
+            }
+        };
+        PersistedStats {
+            path,
+            previous: Arc::new(Mutex::new(previous)),
+        }
+    }
+
+    /// Read the statistics saved in `path`, if there are any.
+    pub fn read(path: &std::path::Path) -> Result<Option<ServerStats>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .context("failed to parse the saved stats")
+    }
+
+    /// The statistics of the previous runs added to `current`.
+    async fn lifetime(&self, current: &ServerStats) -> ServerStats {
+        let mut stats = self.previous.lock().await.clone();
+        stats.merge(current);
+        stats
+    }
+
+    /// Save the lifetime statistics, replacing the file atomically. The
+    /// previous statistics stay locked until the file is written, so that a
+    /// `zero` in the meantime is not undone by the save.
+    async fn save(&self, current: &Mutex<ServerStats>) -> Result<()> {
+        let previous = self.previous.lock().await;
+        let mut stats = previous.clone();
+        stats.merge(&*current.lock().await);
+        let data = serde_json::to_vec(&stats)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let dir = path
+                .parent()
+                .context("stats file has no parent directory")?;
+            fs::create_dir_all(dir)?;
+            // Left behind by a crash, it is removed along with the other
+            // temporary files of the disk cache
+            let mut tmp = tempfile::Builder::new()
+                .prefix(crate::lru_disk_cache::TEMPFILE_PREFIX)
+                .tempfile_in(dir)?;
+            tmp.write_all(&data)?;
+            tmp.persist(&path)?;
+            Ok::<_, Error>(())
+        })
+        .await?
+        .with_context(|| format!("failed to save the stats to {}", self.path.display()))
+    }
+
+    /// Forget the statistics of the previous runs, and zero `current`.
+    async fn zero(&self, current: &Mutex<ServerStats>) -> Result<()> {
+        let mut previous = self.previous.lock().await;
+        *current.lock().await = ServerStats::default();
+        *previous = ServerStats::default();
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Get the session a compile request was tagged with, if any.
+pub fn session_from_env(env_vars: &[(OsString, OsString)]) -> Option<String> {
+    env_vars
+        .iter()
+        .find(|(k, _)| k == SESSION_ENV_VAR)
+        .map(|(_, v)| v.to_string_lossy().into_owned())
+        .filter(|session| !session.is_empty())
+}
+
 /// Get the time the server should idle for before shutting down, in seconds.
 fn get_idle_timeout() -> u64 {
     // A value of 0 disables idle shutdown entirely.
@@ -493,6 +604,7 @@ pub fn start_server(config: &Config, addr: &crate::net::SocketAddr) -> Result<()
         .build()?;
     let pool = runtime.handle().clone();
     let dist_client = DistClientContainer::new(config, &pool);
+    crate::tu_stats::spawn_retention_task(&pool, &config.translation_unit_stats);
 
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
 
@@ -536,13 +648,18 @@ pub fn start_server(config: &Config, addr: &crate::net::SocketAddr) -> Result<()
         _ => raw_storage,
     };
 
+    let persisted = config.stats_file().map(PersistedStats::load);
+
     let res: io::Result<(crate::net::SocketAddr, Box<dyn FnOnce(_) -> io::Result<()>>)> = (|| {
         match addr {
             crate::net::SocketAddr::Net(addr) => {
                 trace!("binding TCP {addr}");
                 let l = runtime.block_on(tokio::net::TcpListener::bind(addr))?;
-                let srv =
+                let mut srv =
                     SccacheServer::<_>::with_listener(l, runtime, client, dist_client, storage);
+                if let Some(persisted) = persisted {
+                    srv.set_persisted_stats(persisted);
+                }
                 Ok((
                     srv.local_addr().unwrap(),
                     Box::new(move |f| srv.run(f)) as Box<dyn FnOnce(_) -> _>,
@@ -557,8 +674,11 @@ pub fn start_server(config: &Config, addr: &crate::net::SocketAddr) -> Result<()
                     let _guard = runtime.enter();
                     tokio::net::UnixListener::bind(path)?
                 };
-                let srv =
+                let mut srv =
                     SccacheServer::<_>::with_listener(l, runtime, client, dist_client, storage);
+                if let Some(persisted) = persisted {
+                    srv.set_persisted_stats(persisted);
+                }
                 Ok((
                     srv.local_addr().unwrap(),
                     Box::new(move |f| srv.run(f)) as Box<dyn FnOnce(_) -> _>,
@@ -574,8 +694,11 @@ pub fn start_server(config: &Config, addr: &crate::net::SocketAddr) -> Result<()
                     let _guard = runtime.enter();
                     tokio::net::UnixListener::from_std(l)?
                 };
-                let srv =
+                let mut srv =
                     SccacheServer::<_>::with_listener(l, runtime, client, dist_client, storage);
+                if let Some(persisted) = persisted {
+                    srv.set_persisted_stats(persisted);
+                }
                 Ok((
                     srv.local_addr()
                         .unwrap_or_else(|| crate::net::SocketAddr::UnixAbstract(p.to_vec())),
@@ -593,7 +716,10 @@ pub fn start_server(config: &Config, addr: &crate::net::SocketAddr) -> Result<()
                     addr: addr.to_string(),
                 },
             )?;
-            run(future::pending::<()>()).map_err(anyhow::Error::from)?;
+            let result = run(future::pending::<()>()).map_err(anyhow::Error::from);
+            // Make sure queued TU stats reach the disk before the process exits
+            crate::tu_stats::shutdown_recorder();
+            result?;
             Ok(())
         }
         Err(e) => {
@@ -676,6 +802,11 @@ impl<A: crate::net::Acceptor, C: CommandCreatorSync> SccacheServer<A, C> {
         self.timeout = timeout;
     }
 
+    /// Keep the statistics across restarts of the server.
+    pub fn set_persisted_stats(&mut self, persisted: PersistedStats) {
+        self.service.persisted = Some(persisted);
+    }
+
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
@@ -720,6 +851,33 @@ impl<A: crate::net::Acceptor, C: CommandCreatorSync> SccacheServer<A, C> {
             wait,
         } = self;
 
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = crate::metrics::addr_from_env() {
+            let source = service.metrics_source();
+            runtime.spawn(async move {
+                if let Err(e) = crate::metrics::serve(addr, source).await {
+                    warn!("Metrics endpoint stopped: {:#}", e);
+                }
+            });
+        }
+
+        let persisted = service
+            .persisted
+            .clone()
+            .map(|persisted| (persisted, service.stats.clone()));
+        if let Some((persisted, stats)) = persisted.clone() {
+            runtime.spawn(async move {
+                let mut interval = time::interval(STATS_SAVE_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = persisted.save(&stats).await {
+                        warn!("{:#}", e);
+                    }
+                }
+            });
+        }
+
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
         let server = async move {
@@ -791,6 +949,14 @@ impl<A: crate::net::Acceptor, C: CommandCreatorSync> SccacheServer<A, C> {
         // don't want to wait *too* long.
         runtime.block_on(async { time::timeout(SHUTDOWN_TIMEOUT, wait).await })?;
 
+        if let Some((persisted, stats)) = persisted {
+            runtime.block_on(async {
+                if let Err(e) = persisted.save(&stats).await {
+                    warn!("{:#}", e);
+                }
+            });
+        }
+
         info!("ok, fully shutting down now");
 
         Ok(())
@@ -833,6 +999,15 @@ where
     /// Server statistics.
     stats: Arc<Mutex<ServerStats>>,
 
+    /// Statistics of the most recently active sessions, see `session_from_env`.
+    sessions: Arc<Mutex<LruCache<String, ServerStats>>>,
+
+    /// Number of compilations in progress.
+    active_compiles: Arc<AtomicUsize>,
+
+    /// Statistics of the previous runs, if they are kept across restarts.
+    persisted: Option<PersistedStats>,
+
     /// Distributed sccache client
     dist_client: Arc<DistClientContainer>,
 
@@ -872,6 +1047,45 @@ where
     info: ActiveInfo,
 }
 
+/// What the metrics endpoint reads, kept apart from `SccacheService` so that
+/// the endpoint does not keep the server from shutting down
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct MetricsSource {
+    stats: Arc<Mutex<ServerStats>>,
+    storage: Arc<dyn Storage>,
+    active_compiles: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsSource {
+    pub async fn server_info(&self) -> Result<ServerInfo> {
+        let stats = self.stats.lock().await.clone();
+        ServerInfo::new(stats, Some(&*self.storage)).await
+    }
+
+    pub fn active_compiles(&self) -> usize {
+        self.active_compiles.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts a compilation in progress until dropped, so that compiles that are
+/// cancelled or panic are not counted forever.
+struct ActiveCompile(Arc<AtomicUsize>);
+
+impl ActiveCompile {
+    fn new(active_compiles: &Arc<AtomicUsize>) -> Self {
+        active_compiles.fetch_add(1, Ordering::Relaxed);
+        ActiveCompile(active_compiles.clone())
+    }
+}
+
+impl Drop for ActiveCompile {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 type SccacheRequest = Message<Request, Body<()>>;
 type SccacheResponse = Message<Response, Pin<Box<dyn Future<Output = Result<Response>> + Send>>>;
 
@@ -908,7 +1122,9 @@ where
             match req.into_inner() {
                 Request::Compile(compile) => {
                     debug!("handle_client: compile");
-                    me.stats.lock().await.compile_requests += 1;
+                    let session = session_from_env(&compile.env_vars);
+                    me.update_stats(session.as_deref(), |stats| stats.compile_requests += 1)
+                        .await;
                     me.handle_compile(compile).await
                 }
                 Request::GetStats => {
@@ -930,6 +1146,34 @@ where
                     me.zero_stats().await;
                     Ok(Message::WithoutBody(Response::ZeroStats))
                 }
+                Request::GetSessionStats(session) => {
+                    debug!("handle_client: get_session_stats");
+                    me.get_session_info(&session)
+                        .await
+                        .map(|i| Response::SessionStats(i.map(Box::new)))
+                        .map(Message::WithoutBody)
+                }
+                Request::GetLifetimeStats => {
+                    debug!("handle_client: get_lifetime_stats");
+                    me.get_lifetime_info()
+                        .await
+                        .map(|i| Response::LifetimeStats(i.map(Box::new)))
+                        .map(Message::WithoutBody)
+                }
+                Request::GetTuStats(query) => {
+                    debug!("handle_client: get_tu_stats");
+                    me.get_tu_stats(query)
+                        .await
+                        .map(Response::TuStats)
+                        .map(Message::WithoutBody)
+                }
+                Request::PruneTuStats(prune) => {
+                    debug!("handle_client: prune_tu_stats");
+                    me.prune_tu_stats(prune)
+                        .await
+                        .map(Response::TuStatsPruned)
+                        .map(Message::WithoutBody)
+                }
                 Request::Shutdown => {
                     debug!("handle_client: shutdown");
                     let mut tx = me.tx.clone();
@@ -971,6 +1215,9 @@ where
     ) -> SccacheService<C> {
         SccacheService {
             stats: Arc::default(),
+            sessions: Arc::new(Mutex::new(LruCache::new(MAX_SESSIONS))),
+            active_compiles: Arc::default(),
+            persisted: None,
             dist_client: Arc::new(dist_client),
             storage,
             compilers: Arc::default(),
@@ -992,6 +1239,9 @@ where
         let dist_client = DistClientContainer::new_disabled();
         SccacheService {
             stats: Arc::default(),
+            sessions: Arc::new(Mutex::new(LruCache::new(MAX_SESSIONS))),
+            active_compiles: Arc::default(),
+            persisted: None,
             dist_client: Arc::new(dist_client),
             storage,
             compilers: Arc::default(),
@@ -1014,6 +1264,9 @@ where
         let client = Client::new_num(1);
         SccacheService {
             stats: Arc::default(),
+            sessions: Arc::new(Mutex::new(LruCache::new(MAX_SESSIONS))),
+            active_compiles: Arc::default(),
+            persisted: None,
             dist_client: Arc::new(DistClientContainer::new_with_state(DistClientState::Some(
                 Box::new(DistClientConfig {
                     pool: rt.clone(),
@@ -1095,9 +1348,94 @@ where
         ServerInfo::new(stats, Some(&*self.storage)).await
     }
 
+    /// Get info and stats about the cache, with the stats of `session` only.
+    /// Returns `None` if the session is unknown.
+    async fn get_session_info(&self, session: &str) -> Result<Option<ServerInfo>> {
+        let stats = match self.sessions.lock().await.get_mut(session) {
+            Some(stats) => stats.clone(),
+            None => return Ok(None),
+        };
+        ServerInfo::new(stats, Some(&*self.storage)).await.map(Some)
+    }
+
+    /// Get info and stats about the cache, with the stats of the previous
+    /// runs of the server added. Returns `None` if they are not kept.
+    async fn get_lifetime_info(&self) -> Result<Option<ServerInfo>> {
+        let Some(persisted) = &self.persisted else {
+            return Ok(None);
+        };
+        let current = self.stats.lock().await.clone();
+        let stats = persisted.lifetime(&current).await;
+        ServerInfo::new(stats, Some(&*self.storage)).await.map(Some)
+    }
+
+    /// Get a page of the translation unit statistics matching `query`, or
+    /// `None` if the server does not record to the requested database.
+    async fn get_tu_stats(&self, query: TuStatsQuery) -> Result<Option<TuStatsPage>> {
+        tokio::task::spawn_blocking(move || {
+            let Some(storage) = crate::tu_stats::recorder_storage(query.stats_file.as_deref())
+            else {
+                return Ok(None);
+            };
+            let (records, next) =
+                storage.query_page(&query.filter, query.after.as_deref(), TU_STATS_PAGE_SIZE)?;
+            Ok(Some(TuStatsPage { records, next }))
+        })
+        .await?
+    }
+
+    /// Prune the translation unit statistics as `prune` asks, or return
+    /// `None` if the server does not record to the requested database.
+    async fn prune_tu_stats(&self, prune: TuStatsPrune) -> Result<Option<u64>> {
+        tokio::task::spawn_blocking(move || {
+            let Some(storage) = crate::tu_stats::recorder_storage(prune.stats_file.as_deref())
+            else {
+                return Ok(None);
+            };
+            crate::tu_stats::prune_storage(&storage, &prune.policy).map(Some)
+        })
+        .await?
+    }
+
+    /// The state read by the metrics endpoint
+    #[cfg(feature = "metrics")]
+    fn metrics_source(&self) -> MetricsSource {
+        MetricsSource {
+            stats: self.stats.clone(),
+            storage: self.storage.clone(),
+            active_compiles: self.active_compiles.clone(),
+        }
+    }
+
     /// Zero stats about the cache.
     async fn zero_stats(&self) {
-        *self.stats.lock().await = ServerStats::default();
+        match &self.persisted {
+            // Zeroes both, so that a save in between can't bring them back
+            Some(persisted) => {
+                if let Err(e) = persisted.zero(&self.stats).await {
+                    warn!("Failed to zero the saved stats: {:#}", e);
+                }
+            }
+            None => *self.stats.lock().await = ServerStats::default(),
+        }
+        self.sessions.lock().await.clear();
+    }
+
+    /// Apply `update` to the server stats, and to those of `session` if the
+    /// request was tagged with one.
+    async fn update_stats(&self, session: Option<&str>, update: impl Fn(&mut ServerStats)) {
+        update(&mut *self.stats.lock().await);
+        if let Some(session) = session {
+            let mut sessions = self.sessions.lock().await;
+            match sessions.get_mut(session) {
+                Some(stats) => update(stats),
+                None => {
+                    let mut stats = ServerStats::default();
+                    update(&mut stats);
+                    sessions.insert(session.to_owned(), stats);
+                }
+            }
+        }
     }
 
     /// Handle a compile request from a client.
@@ -1284,10 +1622,14 @@ where
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
     ) -> SccacheResponse {
+        let session = session_from_env(&env_vars);
         match compiler {
             Err(e) => {
                 debug!("check_compiler: Unsupported compiler: {}", e.to_string());
-                self.stats.lock().await.requests_unsupported_compiler += 1;
+                self.update_stats(session.as_deref(), |stats| {
+                    stats.requests_unsupported_compiler += 1
+                })
+                .await;
                 return Message::WithoutBody(Response::Compile(
                     CompileResponse::UnsupportedCompiler(OsString::from(e.to_string())),
                 ));
@@ -1322,13 +1664,18 @@ where
                         } else {
                             debug!("parse_arguments: CannotCache({}): {:?}", why, cmd)
                         }
-                        let mut stats = self.stats.lock().await;
-                        stats.requests_not_cacheable += 1;
-                        *stats.not_cached.entry(why.to_string()).or_insert(0) += 1;
+                        self.update_stats(session.as_deref(), |stats| {
+                            stats.requests_not_cacheable += 1;
+                            *stats.not_cached.entry(why.to_string()).or_insert(0) += 1;
+                        })
+                        .await;
                     }
                     CompilerArguments::NotCompilation => {
                         debug!("parse_arguments: NotCompilation: {:?}", cmd);
-                        self.stats.lock().await.requests_not_compile += 1;
+                        self.update_stats(session.as_deref(), |stats| {
+                            stats.requests_not_compile += 1
+                        })
+                        .await;
                     }
                 }
             }
@@ -1349,7 +1696,9 @@ where
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
     ) -> Result<CompileFinished> {
-        self.stats.lock().await.requests_executed += 1;
+        let session = session_from_env(&env_vars);
+        self.update_stats(session.as_deref(), |stats| stats.requests_executed += 1)
+            .await;
 
         let force_recache = env_vars.iter().any(|(k, _v)| k == "SCCACHE_RECACHE");
         let force_no_cache = env_vars.iter().any(|(k, _v)| k == "SCCACHE_NO_CACHE");
@@ -1384,6 +1733,7 @@ where
 
         self.rt
             .spawn(async move {
+                let active_compile = ActiveCompile::new(&me.active_compiles);
                 let result = match me.dist_client.get_client_and_config().await {
                     Ok((client, remote_only)) => {
                         // If remote_only is set but no client is available, fail immediately
@@ -1424,6 +1774,7 @@ where
                     }
                     Err(e) => Err(e),
                 };
+                drop(active_compile);
 
                 let mut cache_write = None;
                 let mut res = CompileFinished {
@@ -1431,7 +1782,8 @@ where
                     ..Default::default()
                 };
 
-                let mut stats = me.stats.lock().await;
+                // Collected here and added to the server and session stats at once
+                let mut stats = ServerStats::default();
 
                 match result {
                     Ok((compiled, out)) => {
@@ -1448,9 +1800,13 @@ where
 
                                 stats.cache_hits.increment(&kind, &lang);
                                 stats.cache_read_hit_duration += duration;
+                                let latencies = stats.latencies_mut(&kind, &lang);
+                                latencies.cache_lookup.record(duration);
+                                latencies.cache_read_hit.record(duration);
                             }
-                            CompileResult::CacheMiss(miss_type, dt, duration, future) => {
+                            CompileResult::CacheMiss(miss_type, dt, timings, future) => {
                                 debug!("[{}]: compile result: cache miss", out_pretty);
+                                stats.record_miss_timings(&kind, &lang, &dt, &timings);
                                 dist_type = dt;
 
                                 match miss_type {
@@ -1468,28 +1824,31 @@ where
                                 }
                                 stats.compilations += 1;
                                 stats.cache_misses.increment(&kind, &lang);
-                                stats.compiler_write_duration += duration;
+                                stats.compiler_write_duration += timings.compile;
                                 debug!("stats after compile result: {stats:?}");
                                 cache_write = Some(future);
                             }
-                            CompileResult::NotCached(dt, duration) => {
+                            CompileResult::NotCached(dt, timings) => {
                                 debug!("[{}]: compile result: not cached", out_pretty);
+                                stats.record_miss_timings(&kind, &lang, &dt, &timings);
                                 dist_type = dt;
                                 stats.compilations += 1;
-                                stats.compiler_write_duration += duration;
+                                stats.compiler_write_duration += timings.compile;
                             }
-                            CompileResult::NotCacheable(dt, duration) => {
+                            CompileResult::NotCacheable(dt, timings) => {
                                 debug!("[{}]: compile result: not cacheable", out_pretty);
+                                stats.record_miss_timings(&kind, &lang, &dt, &timings);
                                 dist_type = dt;
                                 stats.compilations += 1;
-                                stats.compiler_write_duration += duration;
+                                stats.compiler_write_duration += timings.compile;
                                 stats.non_cacheable_compilations += 1;
                             }
-                            CompileResult::CompileFailed(dt, duration) => {
+                            CompileResult::CompileFailed(dt, timings) => {
                                 debug!("[{}]: compile result: compile failed", out_pretty);
+                                stats.record_miss_timings(&kind, &lang, &dt, &timings);
                                 dist_type = dt;
                                 stats.compilations += 1;
-                                stats.compiler_write_duration += duration;
+                                stats.compiler_write_duration += timings.compile;
                                 stats.compile_fails += 1;
                             }
                         };
@@ -1504,9 +1863,6 @@ where
                             DistType::Error => stats.dist_errors += 1,
                         }
 
-                        // Make sure the write guard has been dropped ASAP.
-                        drop(stats);
-
                         let Output {
                             status,
                             stdout,
@@ -1528,8 +1884,6 @@ where
                             Ok(ProcessError(output)) => {
                                 debug!("Compilation failed: {:?}", output);
                                 stats.compile_fails += 1;
-                                // Make sure the write guard has been dropped ASAP.
-                                drop(stats);
 
                                 match output.status.code() {
                                     Some(code) => res.retcode = Some(code),
@@ -1540,8 +1894,6 @@ where
                             }
                             Err(err) => match err.downcast::<HttpClientError>() {
                                 Ok(HttpClientError(msg)) => {
-                                    // Make sure the write guard has been dropped ASAP.
-                                    drop(stats);
                                     me.dist_client.reset_state().await;
                                     let errmsg =
                                         format!("[{:?}] http error status: {}", out_pretty, msg);
@@ -1551,8 +1903,6 @@ where
                                 }
                                 Err(err) => {
                                     stats.cache_errors.increment(&kind, &lang);
-                                    // Make sure the write guard has been dropped ASAP.
-                                    drop(stats);
 
                                     use std::fmt::Write;
 
@@ -1573,12 +1923,17 @@ where
                         }
                     }
                 };
+                me.update_stats(session.as_deref(), |s| s.merge(&stats))
+                    .await;
 
                 if let Some(cache_write) = cache_write {
                     match cache_write.await {
                         Err(e) => {
                             debug!("Error executing cache write: {}", e);
-                            me.stats.lock().await.cache_write_errors += 1;
+                            me.update_stats(session.as_deref(), |stats| {
+                                stats.cache_write_errors += 1
+                            })
+                            .await;
                         }
                         //TODO: save cache stats!
                         Ok(info) => {
@@ -1587,9 +1942,15 @@ where
                                 info.object_file_pretty,
                                 util::fmt_duration_as_secs(&info.duration)
                             );
-                            let mut stats = me.stats.lock().await;
-                            stats.cache_writes += 1;
-                            stats.cache_write_duration += info.duration;
+                            me.update_stats(session.as_deref(), |stats| {
+                                stats.cache_writes += 1;
+                                stats.cache_write_duration += info.duration;
+                                stats
+                                    .latencies_mut(&kind, &lang)
+                                    .cache_write
+                                    .record(info.duration);
+                            })
+                            .await;
                         }
                     }
                 }
@@ -1630,13 +1991,179 @@ impl PerLanguageCount {
         self.adv_counts.get(key)
     }
 
+    /// Counts per language
+    pub fn counts(&self) -> &HashMap<String, u64> {
+        &self.counts
+    }
+
     pub fn new() -> PerLanguageCount {
         Self::default()
     }
+
+    fn merge(&mut self, other: &PerLanguageCount) {
+        for (key, count) in &other.counts {
+            *self.counts.entry(key.clone()).or_insert(0) += count;
+        }
+        for (key, count) in &other.adv_counts {
+            *self.adv_counts.entry(key.clone()).or_insert(0) += count;
+        }
+    }
+}
+
+/// Buckets per power of two microseconds in a `LatencyHistogram`, which
+/// bounds the error of its percentiles to an eighth of the value.
+const LATENCY_SUB_BUCKETS: u64 = 8;
+
+/// A histogram of durations, with logarithmic buckets so that it stays
+/// small and can be merged.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(from = "LatencyHistogramRepr", into = "LatencyHistogramRepr")]
+pub struct LatencyHistogram {
+    /// Sample counts, by bucket index
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    max: Duration,
+}
+
+/// The serialized form of a `LatencyHistogram`, which also carries its
+/// percentiles for the consumers of the JSON statistics.
+#[derive(Serialize, Deserialize)]
+struct LatencyHistogramRepr {
+    count: u64,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+    buckets: BTreeMap<u32, u64>,
+}
+
+impl From<LatencyHistogram> for LatencyHistogramRepr {
+    fn from(histogram: LatencyHistogram) -> Self {
+        LatencyHistogramRepr {
+            count: histogram.count,
+            p50: histogram.percentile(50.0),
+            p90: histogram.percentile(90.0),
+            p99: histogram.percentile(99.0),
+            max: histogram.max,
+            buckets: histogram.buckets,
+        }
+    }
+}
+
+impl From<LatencyHistogramRepr> for LatencyHistogram {
+    fn from(repr: LatencyHistogramRepr) -> Self {
+        LatencyHistogram {
+            buckets: repr.buckets,
+            count: repr.count,
+            max: repr.max,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn bucket(micros: u64) -> u32 {
+        if micros < LATENCY_SUB_BUCKETS {
+            return micros as u32;
+        }
+        let shift = micros.ilog2() - LATENCY_SUB_BUCKETS.ilog2();
+        let sub = (micros >> shift) - LATENCY_SUB_BUCKETS;
+        ((shift as u64 + 1) * LATENCY_SUB_BUCKETS + sub) as u32
+    }
+
+    /// The largest number of microseconds in `bucket`
+    fn bucket_limit(bucket: u32) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < LATENCY_SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / LATENCY_SUB_BUCKETS - 1;
+        let sub = bucket % LATENCY_SUB_BUCKETS;
+        ((LATENCY_SUB_BUCKETS + sub + 1) << shift) - 1
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        *self.buckets.entry(Self::bucket(micros)).or_insert(0) += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+    }
+
+    /// The number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The longest recorded duration
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// An upper bound of the `percent`th percentile, or zero when nothing
+    /// was recorded.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        let rank = ((percent / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(Self::bucket_limit(bucket)).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn merge(&mut self, other: &LatencyHistogram) {
+        for (&bucket, &count) in &other.buckets {
+            *self.buckets.entry(bucket).or_insert(0) += count;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// Latency histograms of the steps of compile requests.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Latencies {
+    /// Cache lookups, whether they hit or not.
+    pub cache_lookup: LatencyHistogram,
+    /// Cache lookups that hit.
+    pub cache_read_hit: LatencyHistogram,
+    /// Successful cache writes.
+    pub cache_write: LatencyHistogram,
+    /// Compilations run locally, including the distributed ones that
+    /// failed and were run again.
+    pub compile: LatencyHistogram,
+    /// Successful distributed compilations.
+    pub dist_compile: LatencyHistogram,
+}
+
+impl Latencies {
+    fn merge(&mut self, other: &Latencies) {
+        self.cache_lookup.merge(&other.cache_lookup);
+        self.cache_read_hit.merge(&other.cache_read_hit);
+        self.cache_write.merge(&other.cache_write);
+        self.compile.merge(&other.compile);
+        self.dist_compile.merge(&other.dist_compile);
+    }
+
+    /// The histograms, with the names under which they are printed
+    fn histograms(&self) -> [(&'static str, &LatencyHistogram); 5] {
+        [
+            ("Cache lookup", &self.cache_lookup),
+            ("Cache read hit", &self.cache_read_hit),
+            ("Cache write", &self.cache_write),
+            ("Local compile", &self.compile),
+            ("Distributed compile", &self.dist_compile),
+        ]
+    }
 }
 
 /// Statistics about the server.
+///
+/// Fields missing from saved stats, such as those of an older version, are
+/// zero.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
@@ -1683,6 +2210,8 @@ pub struct ServerStats {
     pub dist_compiles: HashMap<String, usize>,
     /// The count of compilations that were distributed but failed and had to be re-run locally
     pub dist_errors: u64,
+    /// Latency histograms (per language).
+    pub latencies: HashMap<String, Latencies>,
 }
 
 /// Info and stats about the server.
@@ -1731,6 +2260,7 @@ impl Default for ServerStats {
             not_cached: HashMap::new(),
             dist_compiles: HashMap::new(),
             dist_errors: u64::default(),
+            latencies: HashMap::new(),
         }
     }
 }
@@ -1748,6 +2278,65 @@ impl ServerStatsWriter for StdoutServerStatsWriter {
 }
 
 impl ServerStats {
+    /// Add the counters of `other` to these.
+    pub fn merge(&mut self, other: &ServerStats) {
+        self.compile_requests += other.compile_requests;
+        self.requests_unsupported_compiler += other.requests_unsupported_compiler;
+        self.requests_not_compile += other.requests_not_compile;
+        self.requests_not_cacheable += other.requests_not_cacheable;
+        self.requests_executed += other.requests_executed;
+        self.cache_errors.merge(&other.cache_errors);
+        self.cache_hits.merge(&other.cache_hits);
+        self.cache_misses.merge(&other.cache_misses);
+        self.cache_timeouts += other.cache_timeouts;
+        self.cache_read_errors += other.cache_read_errors;
+        self.non_cacheable_compilations += other.non_cacheable_compilations;
+        self.forced_recaches += other.forced_recaches;
+        self.cache_write_errors += other.cache_write_errors;
+        self.cache_writes += other.cache_writes;
+        self.cache_write_duration += other.cache_write_duration;
+        self.cache_read_hit_duration += other.cache_read_hit_duration;
+        self.compilations += other.compilations;
+        self.compiler_write_duration += other.compiler_write_duration;
+        self.compile_fails += other.compile_fails;
+        for (reason, count) in &other.not_cached {
+            *self.not_cached.entry(reason.clone()).or_insert(0) += count;
+        }
+        for (server, count) in &other.dist_compiles {
+            *self.dist_compiles.entry(server.clone()).or_insert(0) += count;
+        }
+        self.dist_errors += other.dist_errors;
+        for (lang, latencies) in &other.latencies {
+            self.latencies
+                .entry(lang.clone())
+                .or_default()
+                .merge(latencies);
+        }
+    }
+
+    fn latencies_mut(&mut self, kind: &CompilerKind, lang: &Language) -> &mut Latencies {
+        self.latencies.entry(kind.lang_kind(lang)).or_default()
+    }
+
+    /// Record the lookup and the compilation of a request that missed the
+    /// cache.
+    fn record_miss_timings(
+        &mut self,
+        kind: &CompilerKind,
+        lang: &Language,
+        dist_type: &DistType,
+        timings: &MissTimings,
+    ) {
+        let latencies = self.latencies_mut(kind, lang);
+        if let Some(lookup) = timings.lookup {
+            latencies.cache_lookup.record(lookup);
+        }
+        match dist_type {
+            DistType::Ok(_) => latencies.dist_compile.record(timings.compile),
+            DistType::NoDist | DistType::Error => latencies.compile.record(timings.compile),
+        }
+    }
+
     /// Print stats in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
@@ -1886,6 +2475,8 @@ impl ServerStats {
                 other => other,
             };
 
+        self.print_latencies(writer, name_width);
+
         if !self.dist_compiles.is_empty() {
             writer.write("\nSuccessful distributed compiles");
             let mut counts: Vec<_> = self.dist_compiles.iter().collect();
@@ -1918,6 +2509,62 @@ impl ServerStats {
         (name_width, stat_width)
     }
 
+    /// Print the percentiles of the latency histograms, across languages
+    /// and then per language.
+    fn print_latencies<T: ServerStatsWriter>(&self, writer: &mut T, name_width: usize) {
+        let mut total = Latencies::default();
+        for latencies in self.latencies.values() {
+            total.merge(latencies);
+        }
+        let mut langs: Vec<_> = self.latencies.iter().collect();
+        langs.sort_by_key(|(lang, _)| *lang);
+
+        let mut rows = vec![];
+        for (i, (name, histogram)) in total.histograms().into_iter().enumerate() {
+            if histogram.count() == 0 {
+                continue;
+            }
+            rows.push((name.to_string(), histogram.clone()));
+            for (lang, latencies) in &langs {
+                let histogram = latencies.histograms()[i].1;
+                if histogram.count() > 0 {
+                    rows.push((format!("{} ({})", name, lang), histogram.clone()));
+                }
+            }
+        }
+        if rows.is_empty() {
+            return;
+        }
+
+        let name_width = rows
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max(name_width);
+        let fmt = |d: Duration| util::fmt_duration_as_secs(&d);
+        writer.write(&format!(
+            "\n{:<name_width$} {:>9} {:>9} {:>9} {:>9}",
+            "Latency",
+            "p50",
+            "p90",
+            "p99",
+            "max",
+            name_width = name_width
+        ));
+        for (name, histogram) in rows {
+            writer.write(&format!(
+                "{:<name_width$} {:>9} {:>9} {:>9} {:>9}",
+                name,
+                fmt(histogram.percentile(50.0)),
+                fmt(histogram.percentile(90.0)),
+                fmt(histogram.percentile(99.0)),
+                fmt(histogram.max()),
+                name_width = name_width
+            ));
+        }
+    }
+
     fn set_percentage_stats(&self, stats_vec: &mut Vec<(String, String, usize)>, advanced: bool) {
         set_percentage_stat(
             stats_vec,
@@ -2046,12 +2693,7 @@ impl ServerInfo {
             ("Max cache size", &self.max_cache_size),
         ] {
             if let Some(val) = *val {
-                let (val, suffix) = match NumberPrefix::binary(val as f64) {
-                    NumberPrefix::Standalone(bytes) => (bytes.to_string(), "bytes".to_string()),
-                    NumberPrefix::Prefixed(prefix, n) => {
-                        (format!("{:.0}", n), format!("{}B", prefix))
-                    }
-                };
+                let [val, suffix] = format_size(val);
                 println!(
                     "{:<name_width$} {:>stat_width$} {}",
                     name,
@@ -2065,6 +2707,14 @@ impl ServerInfo {
     }
 }
 
+/// Format a size in bytes as a value and its unit, e.g. `["12", "MiB"]`.
+pub fn format_size(size: u64) -> [String; 2] {
+    match NumberPrefix::binary(size as f64) {
+        NumberPrefix::Standalone(bytes) => [bytes.to_string(), "bytes".to_string()],
+        NumberPrefix::Prefixed(prefix, n) => [format!("{:.0}", n), format!("{}B", prefix)],
+    }
+}
+
 enum Frame<R, R1> {
     Body { chunk: Option<R1> },
     Message { message: R },
@@ -2314,6 +2964,167 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_session_from_env() {
+        let env = |value: &str| vec![(OsString::from(SESSION_ENV_VAR), OsString::from(value))];
+        assert_eq!(session_from_env(&env("ci-42")), Some("ci-42".to_owned()));
+        assert_eq!(session_from_env(&env("")), None);
+        assert_eq!(session_from_env(&[]), None);
+    }
+
+    #[test]
+    fn test_merge_server_stats() {
+        let mut stats = ServerStats::default();
+        stats.compile_requests = 2;
+        stats
+            .cache_hits
+            .increment(&CompilerKind::Rust, &Language::Rust);
+        stats.dist_compiles.insert("server1".to_string(), 1);
+
+        let mut other = ServerStats::default();
+        other.compile_requests = 3;
+        other
+            .cache_hits
+            .increment(&CompilerKind::Rust, &Language::Rust);
+        other.dist_compiles.insert("server1".to_string(), 2);
+        other.dist_compiles.insert("server2".to_string(), 1);
+        other.cache_write_duration = Duration::from_secs(1);
+
+        stats.merge(&other);
+        assert_eq!(stats.compile_requests, 5);
+        assert_eq!(stats.cache_hits.all(), 2);
+        assert_eq!(stats.dist_compiles["server1"], 3);
+        assert_eq!(stats.dist_compiles["server2"], 1);
+        assert_eq!(stats.cache_write_duration, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_persisted_stats() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        let current = Mutex::new(ServerStats {
+            compile_requests: 2,
+            ..Default::default()
+        });
+
+        runtime.block_on(async {
+            let persisted = PersistedStats::load(path.clone());
+            let lifetime = persisted.lifetime(&*current.lock().await).await;
+            assert_eq!(lifetime.compile_requests, 2);
+            // Saving twice must not count the current stats twice
+            persisted.save(&current).await.unwrap();
+            persisted.save(&current).await.unwrap();
+
+            let persisted = PersistedStats::load(path.clone());
+            let lifetime = persisted.lifetime(&*current.lock().await).await;
+            assert_eq!(lifetime.compile_requests, 4);
+
+            persisted.zero(&current).await.unwrap();
+            assert!(!path.exists());
+            assert_eq!(current.lock().await.compile_requests, 0);
+            // Saving after zeroing doesn't bring the old stats back
+            persisted.save(&current).await.unwrap();
+            let stats = PersistedStats::read(&path).unwrap().unwrap();
+            assert_eq!(stats.compile_requests, 0);
+        });
+
+        // Unreadable stats are dropped rather than failing the server start
+        fs::write(&path, "{").unwrap();
+        let persisted = PersistedStats::load(path.clone());
+        let lifetime = runtime.block_on(persisted.lifetime(&ServerStats::default()));
+        assert_eq!(lifetime.compile_requests, 0);
+
+        // Stats saved by an older version lack the newer fields
+        fs::write(&path, r#"{"compile_requests": 3}"#).unwrap();
+        let stats = PersistedStats::read(&path).unwrap().unwrap();
+        assert_eq!(stats.compile_requests, 3);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), Duration::ZERO);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+        // Percentiles are bucket limits, at most an eighth above the value
+        for (percent, ms) in [(50.0, 50), (90.0, 90), (99.0, 99), (100.0, 100)] {
+            let p = histogram.percentile(percent);
+            let expected = Duration::from_millis(ms);
+            assert!(p >= expected && p <= expected + expected / 8, "{:?}", p);
+        }
+
+        // Merging is the same as recording into one histogram
+        let mut other = LatencyHistogram::default();
+        other.record(Duration::from_secs(2));
+        histogram.merge(&other);
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.max(), Duration::from_secs(2));
+        assert_eq!(histogram.percentile(100.0), Duration::from_secs(2));
+
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["count"], 101);
+        assert!(json["p99"]["secs"].is_u64());
+        let roundtrip: LatencyHistogram = serde_json::from_value(json).unwrap();
+        assert_eq!(roundtrip, histogram);
+
+        for micros in [0, 7, 8, 15, 16, 1000, 123_456_789] {
+            let bucket = LatencyHistogram::bucket(micros);
+            assert!(LatencyHistogram::bucket_limit(bucket) >= micros);
+            assert_eq!(
+                LatencyHistogram::bucket(LatencyHistogram::bucket_limit(bucket)),
+                bucket
+            );
+        }
+    }
+
+    #[test]
+    fn test_print_latencies() {
+        let mut stats = ServerStats::default();
+        let lookup = |ms| MissTimings {
+            lookup: Some(Duration::from_millis(ms)),
+            compile: Duration::from_secs(1),
+        };
+        stats.record_miss_timings(
+            &CompilerKind::Rust,
+            &Language::Rust,
+            &DistType::NoDist,
+            &lookup(10),
+        );
+        stats.record_miss_timings(
+            &CompilerKind::C(crate::compiler::CCompilerKind::Clang),
+            &Language::C,
+            &DistType::NoDist,
+            &lookup(20),
+        );
+
+        let mut writer = StringWriter::new();
+        stats.print(&mut writer, false);
+        let output = writer.get_output();
+        let line = |name: &str| {
+            output
+                .lines()
+                .find(|line| line.starts_with(&format!("{} ", name)))
+                .unwrap_or_else(|| panic!("missing {:?} in\n{}", name, output))
+                .split_whitespace()
+                .skip(name.split_whitespace().count())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(line("Cache lookup"), "0.010 s 0.020 s 0.020 s 0.020 s");
+        assert_eq!(
+            line("Cache lookup (Rust)"),
+            "0.010 s 0.010 s 0.010 s 0.010 s"
+        );
+        assert_eq!(line("Local compile"), "1.000 s 1.000 s 1.000 s 1.000 s");
+        assert!(!output.contains("Distributed compile"));
+    }
+
     #[test]
     fn test_print_cache_hits_rate_default_server_stats() {
         let stats = ServerStats::default();
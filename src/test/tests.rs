@@ -15,10 +15,10 @@
 use crate::cache::disk::DiskCache;
 use crate::cache::{CacheMode, PreprocessorCacheModeConfig};
 use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+use crate::commands::{do_compile, request_session_stats, request_shutdown, request_stats};
 use crate::jobserver::Client;
 use crate::mock_command::*;
-use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
+use crate::server::{DistClientContainer, SESSION_ENV_VAR, SccacheServer, ServerMessage};
 use crate::test::utils::*;
 use fs::File;
 use fs_err as fs;
@@ -220,6 +220,61 @@ fn test_server_unsupported_compiler() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_session_stats() {
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let conn = connect_to_server(&addr).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // fail rust driver check
+        c.next_command_spawns(Ok(MockChild::new(exit_status(1), "hello", "error")));
+        // Pretend to be an unsupported compiler
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "hello", "error")));
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut runtime = Runtime::new().unwrap();
+    let env_vars = vec![(SESSION_ENV_VAR.into(), "ci-42".into())];
+    do_compile(
+        client_creator,
+        &mut runtime,
+        conn,
+        exe,
+        cmdline,
+        cwd,
+        path,
+        env_vars,
+        &mut stdout,
+        &mut stderr,
+    )
+    .unwrap_err();
+
+    let conn = connect_to_server(&addr).unwrap();
+    let info = request_session_stats(conn, "ci-42".to_owned())
+        .unwrap()
+        .unwrap();
+    assert_eq!(1, info.stats.compile_requests);
+    assert_eq!(1, info.stats.requests_unsupported_compiler);
+    let conn = connect_to_server(&addr).unwrap();
+    assert!(
+        request_session_stats(conn, "ci-43".to_owned())
+            .unwrap()
+            .is_none()
+    );
+    // The server stats count requests of every session
+    let conn = connect_to_server(&addr).unwrap();
+    assert_eq!(1, request_stats(conn).unwrap().stats.compile_requests);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_compile() {
     let _ = env_logger::try_init();
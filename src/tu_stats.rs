@@ -15,11 +15,16 @@
 //! Translation unit statistics collection and storage
 
 use crate::errors::*;
+use crate::util::fmt_duration_as_secs;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 /// Statistics about include path contributions
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +37,57 @@ pub struct IncludeStats {
     pub lines: usize,
 }
 
+/// Preprocessed output contributed by a single included header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderStats {
+    /// Path to the header, made absolute against the compiler's working directory
+    pub path: String,
+    /// Lines of preprocessed output contributed by this header
+    pub lines: usize,
+}
+
+/// A file in the include tree of a translation unit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncludeNode {
+    /// Path to the file, made absolute against the compiler's working directory
+    pub path: String,
+    /// Lines of preprocessed output contributed by this file itself
+    pub lines: usize,
+    /// Files included from this file, in inclusion order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<IncludeNode>,
+}
+
+impl IncludeNode {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            lines: 0,
+            children: Vec::new(),
+        }
+    }
+
+    /// Lines contributed by this file and everything it includes
+    pub fn total_lines(&self) -> usize {
+        self.lines + self.children.iter().map(Self::total_lines).sum::<usize>()
+    }
+}
+
+/// What a rustc invocation compiled
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RustCrateStats {
+    /// The `--crate-name` of the crate
+    pub crate_name: String,
+    /// The `--crate-type`s of the crate
+    pub crate_types: Vec<String>,
+    /// Number of `--extern` dependencies
+    pub num_externs: usize,
+    /// Total size of the `--extern` dependency files in bytes
+    pub extern_bytes: u64,
+    /// Number of source files listed in the crate's dep-info
+    pub num_source_files: usize,
+}
+
 /// Statistics about a translation unit compilation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TranslationUnitStats {
@@ -55,77 +111,866 @@ pub struct TranslationUnitStats {
     pub top_includes_by_size: Vec<IncludeStats>,
     /// Timestamp when the compilation occurred
     pub timestamp: std::time::SystemTime,
+    /// How the request was served
+    #[serde(default)]
+    pub cache_outcome: CacheOutcome,
+    /// Why the cache did not serve the request (`None` for hits)
+    #[serde(default)]
+    pub miss_reason: Option<MissReason>,
+    /// Cache key of the compilation
+    #[serde(default)]
+    pub cache_key: String,
+    /// Time taken to generate the cache key, including preprocessing
+    #[serde(default)]
+    pub hash_duration: Duration,
+    /// Time taken by the cache storage to answer the lookup
+    #[serde(default)]
+    pub cache_lookup_duration: Duration,
+    /// Time taken to extract the outputs of a cache hit
+    #[serde(default)]
+    pub cache_read_duration: Duration,
+    /// Time taken to package and store the outputs of a cache miss
+    #[serde(default)]
+    pub cache_write_duration: Duration,
+    /// Time taken to package the toolchain and submit it to the server
+    #[serde(default)]
+    pub dist_toolchain_duration: Duration,
+    /// Time spent waiting for the scheduler to allocate the job, including
+    /// busy retries
+    #[serde(default)]
+    pub dist_alloc_duration: Duration,
+    /// Time taken to package the inputs of a distributed job
+    #[serde(default)]
+    pub dist_inputs_duration: Duration,
+    /// Time taken to upload the inputs of a distributed job
+    #[serde(default)]
+    pub dist_upload_duration: Duration,
+    /// Time taken by the server to run a distributed job, once the inputs
+    /// are uploaded
+    #[serde(default)]
+    pub dist_execute_duration: Duration,
+    /// Time taken to download and unpack the outputs of a distributed job
+    #[serde(default)]
+    pub dist_outputs_duration: Duration,
+    /// Server that ran the distributed job
+    #[serde(default)]
+    pub dist_server_id: Option<crate::dist::ServerId>,
+    /// Session the compile request was tagged with
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Lines contributed by every included header, only recorded when
+    /// `per_header` is enabled in the configuration
+    #[serde(default)]
+    pub headers: Vec<HeaderStats>,
+    /// Include tree rooted at the input file, only recorded when
+    /// `include_graph` is enabled in the configuration
+    #[serde(default)]
+    pub include_tree: Option<IncludeNode>,
+    /// Crate details for rustc compilations (`None` for C/C++)
+    #[serde(default)]
+    pub rust_crate: Option<RustCrateStats>,
+}
+
+/// How a compile request was served
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheOutcome {
+    /// Recorded before outcomes were tracked
+    #[default]
+    Unknown,
+    /// Served from the cache
+    Hit,
+    /// Compiled and stored in the cache
+    Miss,
+    /// Compiled, but caching was disabled for this request
+    NotCached,
+    /// Compiled, but the result could not be cached
+    NotCacheable,
+    /// Compiled, but the compilation failed
+    CompileFailed,
+    /// The request failed: its hash key could not be generated, the compiler
+    /// could not be run, or its outputs could not be packaged for the cache
+    Error,
+}
+
+impl CacheOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+            Self::NotCached => "not cached",
+            Self::NotCacheable => "not cacheable",
+            Self::CompileFailed => "compile failed",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Why the cache did not serve a compile request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissReason {
+    /// The result was not in the cache
+    Normal,
+    /// Caching was disabled for this request
+    ForcedNoCache,
+    /// The cache lookup was skipped to force recompilation
+    ForcedRecache,
+    /// The cache took too long to respond
+    TimedOut,
+    /// Reading from the cache failed
+    CacheReadError,
+}
+
+impl MissReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::ForcedNoCache => "forced no cache",
+            Self::ForcedRecache => "forced recache",
+            Self::TimedOut => "timed out",
+            Self::CacheReadError => "cache read error",
+        }
+    }
+}
+
+impl From<&crate::compiler::MissType> for MissReason {
+    fn from(miss_type: &crate::compiler::MissType) -> Self {
+        use crate::compiler::MissType;
+        match miss_type {
+            MissType::Normal => Self::Normal,
+            MissType::ForcedNoCache => Self::ForcedNoCache,
+            MissType::ForcedRecache => Self::ForcedRecache,
+            MissType::TimedOut => Self::TimedOut,
+            MissType::CacheReadError => Self::CacheReadError,
+        }
+    }
+}
+
+/// What a compilation knows about its translation unit once its hash key
+/// has been generated
+#[derive(Debug, Clone, Default)]
+pub struct TuStatsContext {
+    /// Path to the input source file
+    pub input_file: PathBuf,
+    /// Size of the preprocessed translation unit in bytes (0 if preprocessing was skipped)
+    pub preprocessed_size: usize,
+    /// Number of files included in the translation unit
+    pub num_includes: usize,
+    /// Time taken to preprocess the file
+    pub preprocess_duration: Duration,
+    /// Top 10 include path prefixes by frequency
+    pub top_includes_by_count: Vec<IncludeStats>,
+    /// Top 10 include path prefixes by size contribution
+    pub top_includes_by_size: Vec<IncludeStats>,
+    /// Lines contributed by every included header (empty unless `per_header` is enabled)
+    pub headers: Vec<HeaderStats>,
+    /// Include tree rooted at the input file (`None` unless `include_graph` is enabled)
+    pub include_tree: Option<IncludeNode>,
+    /// Crate details for rustc compilations
+    pub rust_crate: Option<RustCrateStats>,
+    /// Warnings about exceeded budgets, appended to the compiler's stderr
+    pub budget_warnings: Vec<String>,
+}
+
+impl TuStatsContext {
+    /// Start a record for this translation unit. The outcome, the remaining
+    /// timings and the timestamp are filled in once the request completes.
+    pub fn into_stats(self, cache_key: String, hash_duration: Duration) -> TranslationUnitStats {
+        TranslationUnitStats {
+            input_file: self.input_file,
+            preprocessed_size: self.preprocessed_size,
+            num_includes: self.num_includes,
+            preprocess_duration: self.preprocess_duration,
+            compile_duration: Duration::ZERO,
+            dist_retry_count: 0,
+            is_distributed: false,
+            top_includes_by_count: self.top_includes_by_count,
+            top_includes_by_size: self.top_includes_by_size,
+            timestamp: SystemTime::now(),
+            cache_outcome: CacheOutcome::Unknown,
+            miss_reason: None,
+            cache_key,
+            hash_duration,
+            cache_lookup_duration: Duration::ZERO,
+            cache_read_duration: Duration::ZERO,
+            cache_write_duration: Duration::ZERO,
+            dist_toolchain_duration: Duration::ZERO,
+            dist_alloc_duration: Duration::ZERO,
+            dist_inputs_duration: Duration::ZERO,
+            dist_upload_duration: Duration::ZERO,
+            dist_execute_duration: Duration::ZERO,
+            dist_outputs_duration: Duration::ZERO,
+            dist_server_id: None,
+            session: None,
+            headers: self.headers,
+            include_tree: self.include_tree,
+            rust_crate: self.rust_crate,
+        }
+    }
+}
+
+/// Filters applied when querying translation unit statistics
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TuStatsFilter {
+    /// Only include records at or after this time
+    pub since: Option<SystemTime>,
+    /// Only include records before this time
+    pub until: Option<SystemTime>,
+    /// Only include records whose input file path matches this glob
+    pub file_glob: Option<String>,
+    /// Only include distributed (`Some(true)`) or local (`Some(false)`) compilations
+    pub distributed: Option<bool>,
+    /// Only include records of compile requests tagged with this session
+    pub session: Option<String>,
+}
+
+impl TuStatsFilter {
+    /// Check the filters that no index serves
+    #[cfg(feature = "translation-unit-stats")]
+    fn matches_record(&self, stats: &TranslationUnitStats) -> bool {
+        self.distributed
+            .is_none_or(|distributed| stats.is_distributed == distributed)
+            && self
+                .session
+                .as_ref()
+                .is_none_or(|session| stats.session.as_ref() == Some(session))
+    }
+}
+
+/// A shell-style glob matched against the full input file path.
+///
+/// `*` and `?` match within a single path component, `**` matches across
+/// components, and `**/` also matches no directory at all.
+#[derive(Debug, Clone)]
+pub struct FileGlob {
+    regex: regex::bytes::Regex,
+    literal_prefix: String,
+}
+
+impl FileGlob {
+    pub fn new(glob: &str) -> Result<Self> {
+        let literal_prefix: String = glob
+            .chars()
+            .take_while(|c| !matches!(c, '*' | '?'))
+            .collect();
+
+        let mut pattern = String::from("^");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        pattern.push_str("(?:.*/)?");
+                    } else {
+                        pattern.push_str(".*");
+                    }
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        pattern.push('$');
+
+        let regex = regex::bytes::Regex::new(&pattern)
+            .with_context(|| format!("Invalid input file glob: {:?}", glob))?;
+        Ok(Self {
+            regex,
+            literal_prefix,
+        })
+    }
+
+    /// The part of the glob before the first wildcard, usable for prefix scans
+    pub fn literal_prefix(&self) -> &str {
+        &self.literal_prefix
+    }
+
+    pub fn is_match(&self, path: &[u8]) -> bool {
+        self.regex.is_match(path)
+    }
+}
+
+/// How the include files matching a grouping rule are bucketed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncludeBucket<'a> {
+    /// Count the file under a fixed name
+    Named(&'a str),
+    /// Count the file under the first N components of its path
+    Depth(usize),
+}
+
+/// Number of path components used when no grouping rule matches
+const DEFAULT_PREFIX_DEPTH: usize = 3;
+
+#[derive(Debug)]
+enum IncludeMatcher {
+    Glob(FileGlob),
+    Regex(regex::Regex),
+}
+
+#[derive(Debug)]
+struct IncludeGroup {
+    matcher: IncludeMatcher,
+    name: Option<String>,
+    depth: usize,
+}
+
+/// Ordered rules assigning include files to the buckets reported as the top
+/// include path prefixes of a translation unit
+#[derive(Debug, Default)]
+pub struct IncludeGrouping {
+    groups: Vec<IncludeGroup>,
+}
+
+impl IncludeGrouping {
+    pub fn new(rules: &[crate::config::IncludeGroupRule]) -> Result<Self> {
+        let groups = rules
+            .iter()
+            .map(|rule| {
+                let matcher = match (&rule.glob, &rule.regex) {
+                    (Some(glob), None) => IncludeMatcher::Glob(FileGlob::new(glob)?),
+                    (None, Some(regex)) => IncludeMatcher::Regex(
+                        regex::Regex::new(regex)
+                            .with_context(|| format!("Invalid include group regex: {:?}", regex))?,
+                    ),
+                    _ => bail!("Include group rules need exactly one of `glob` or `regex`"),
+                };
+                let depth = match (&rule.name, rule.depth) {
+                    (Some(_), Some(_)) => {
+                        bail!("Include group rules cannot have both a `name` and a `depth`")
+                    }
+                    (_, Some(0)) => bail!("Include group rule depth must be at least 1"),
+                    (_, depth) => depth.unwrap_or(DEFAULT_PREFIX_DEPTH),
+                };
+                Ok(IncludeGroup {
+                    matcher,
+                    name: rule.name.clone(),
+                    depth,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { groups })
+    }
+
+    /// Put every include file in a bucket of its own, named after its path
+    pub fn ungrouped() -> Self {
+        Self {
+            groups: vec![IncludeGroup {
+                matcher: IncludeMatcher::Regex(regex::Regex::new("").unwrap()),
+                name: None,
+                depth: usize::MAX,
+            }],
+        }
+    }
+
+    /// The bucket of an include file, given its path relative to the
+    /// compiler's working directory (or its absolute path if outside of it)
+    pub fn bucket(&self, path: &str) -> IncludeBucket<'_> {
+        let group = self.groups.iter().find(|group| match group.matcher {
+            IncludeMatcher::Glob(ref glob) => glob.is_match(path.as_bytes()),
+            IncludeMatcher::Regex(ref regex) => regex.is_match(path),
+        });
+        match group {
+            Some(IncludeGroup {
+                name: Some(name), ..
+            }) => IncludeBucket::Named(name),
+            Some(group) => IncludeBucket::Depth(group.depth),
+            None => IncludeBucket::Depth(DEFAULT_PREFIX_DEPTH),
+        }
+    }
+}
+
+/// Limits every translation unit is checked against, see
+/// `crate::config::TuBudgetConfig`
+#[derive(Debug, Default)]
+pub struct TuBudgets {
+    config: crate::config::TuBudgetConfig,
+}
+
+impl TuBudgets {
+    pub fn new(config: &crate::config::TuBudgetConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Whether no budget is configured, so that there is nothing to check
+    pub fn is_empty(&self) -> bool {
+        self.config.max_preprocessed_size.is_none()
+            && self.config.max_includes.is_none()
+            && self.config.prefixes.is_empty()
+    }
+
+    /// Whether compilations exceeding a budget should fail
+    pub fn fail(&self) -> bool {
+        self.config.action == crate::config::BudgetAction::Fail
+    }
+
+    /// Whether there are prefix budgets, which need the lines of every
+    /// include file
+    pub fn has_prefixes(&self) -> bool {
+        !self.config.prefixes.is_empty()
+    }
+
+    /// Describe every budget exceeded by a translation unit. `includes` must
+    /// hold every include file on its own, as grouped by
+    /// `IncludeGrouping::ungrouped`, so that prefixes deeper than the
+    /// configured buckets or under a named bucket match too.
+    pub fn check(
+        &self,
+        preprocessed_size: usize,
+        num_includes: usize,
+        includes: &[IncludeStats],
+    ) -> Vec<String> {
+        let mut violations = Vec::new();
+        if let Some(max) = self.config.max_preprocessed_size {
+            if preprocessed_size as u64 > max {
+                violations.push(format!(
+                    "preprocessed size of {} bytes exceeds the budget of {} bytes",
+                    preprocessed_size, max
+                ));
+            }
+        }
+        if let Some(max) = self.config.max_includes {
+            if num_includes > max {
+                violations.push(format!(
+                    "{} included files exceed the budget of {}",
+                    num_includes, max
+                ));
+            }
+        }
+        for rule in &self.config.prefixes {
+            let prefix = rule.prefix.trim_end_matches('/');
+            let lines: usize = includes
+                .iter()
+                .filter(|include| {
+                    include
+                        .path_prefix
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                })
+                .map(|include| include.lines)
+                .sum();
+            if lines > rule.max_lines {
+                violations.push(format!(
+                    "{} lines included from `{}` exceed the budget of {}",
+                    lines, rule.prefix, rule.max_lines
+                ));
+            }
+        }
+        violations
+    }
+}
+
+/// Limits on how much translation unit statistics history to keep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Records older than this are deleted
+    pub max_age: Option<Duration>,
+    /// Only the newest this many records are kept
+    pub max_records: Option<u64>,
+    /// Only the newest records up to this many bytes (keys and values) are kept
+    pub max_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &crate::config::TranslationUnitStatsConfig) -> Self {
+        Self {
+            max_age: config.max_age_secs.map(Duration::from_secs),
+            max_records: config.max_records,
+            max_size: config.max_size,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[cfg(feature = "translation-unit-stats")]
 mod storage {
     use super::*;
-    use fjall::{Config, Keyspace, PartitionCreateOptions};
-    use std::sync::Arc;
+    use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle, Slice};
+    use std::ops::Bound;
+    use std::time::UNIX_EPOCH;
+
+    /// Records keyed by `timestamp ++ input file`, so that iteration is in time order
+    const RECORDS_PARTITION: &str = "records";
+    /// Index keyed by `input file ++ NUL ++ timestamp`, holding the record key
+    const BY_FILE_PARTITION: &str = "records_by_file";
+    /// Partition used by older versions, keyed by the `Debug` format of the timestamp
+    const LEGACY_PARTITION: &str = "tu_stats";
+
+    const TIMESTAMP_KEY_LEN: usize = 8;
+    /// Number of writes per batch when migrating or pruning many records
+    const WRITE_BATCH_SIZE: usize = 1000;
+
+    /// Encode a timestamp as big-endian nanoseconds since the UNIX epoch, so
+    /// that byte order and time order agree.
+    pub(super) fn timestamp_key(timestamp: SystemTime) -> [u8; TIMESTAMP_KEY_LEN] {
+        let nanos = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        u64::try_from(nanos).unwrap_or(u64::MAX).to_be_bytes()
+    }
+
+    fn path_bytes(path: &Path) -> &[u8] {
+        path.as_os_str().as_encoded_bytes()
+    }
+
+    pub(super) fn record_key(stats: &TranslationUnitStats) -> Vec<u8> {
+        let path = path_bytes(&stats.input_file);
+        let mut key = Vec::with_capacity(TIMESTAMP_KEY_LEN + path.len());
+        key.extend_from_slice(&timestamp_key(stats.timestamp));
+        key.extend_from_slice(path);
+        key
+    }
+
+    pub(super) fn file_index_key(stats: &TranslationUnitStats) -> Vec<u8> {
+        file_index_key_for_record(&record_key(stats))
+    }
+
+    /// Build the by-file index key pointing at the record stored under `record_key`
+    fn file_index_key_for_record(record_key: &[u8]) -> Vec<u8> {
+        let (timestamp, path) = record_key.split_at(TIMESTAMP_KEY_LEN.min(record_key.len()));
+        let mut key = Vec::with_capacity(path.len() + 1 + TIMESTAMP_KEY_LEN);
+        key.extend_from_slice(path);
+        key.push(0);
+        key.extend_from_slice(timestamp);
+        key
+    }
+
+    /// Split a `file_index_key` back into the path and timestamp parts
+    pub(super) fn split_file_index_key(key: &[u8]) -> Option<(&[u8], &[u8])> {
+        let split = key.len().checked_sub(TIMESTAMP_KEY_LEN + 1)?;
+        Some((&key[..split], &key[split + 1..]))
+    }
 
     /// Storage backend for translation unit statistics using fjall
+    #[derive(Clone)]
     pub struct TuStatsStorage {
         keyspace: Arc<Keyspace>,
-        partition_name: &'static str,
+        records: PartitionHandle,
+        by_file: PartitionHandle,
+        /// The sorted record keys found by the last by-file scan, with its
+        /// filter, so that the next pages of a query don't scan the index again
+        last_file_scan: Arc<Mutex<Option<(TuStatsFilter, Arc<[Slice]>)>>>,
     }
 
     impl TuStatsStorage {
-        /// Create a new statistics storage at the given path
+        /// Create a new statistics storage at the given path, migrating
+        /// records written by older versions if necessary
         pub fn new(path: &Path) -> Result<Self> {
             let keyspace = Config::new(path)
                 .open()
                 .context("Failed to open fjall keyspace for TU stats")?;
+            let records = keyspace
+                .open_partition(RECORDS_PARTITION, PartitionCreateOptions::default())
+                .context("Failed to open partition for TU stats")?;
+            let by_file = keyspace
+                .open_partition(BY_FILE_PARTITION, PartitionCreateOptions::default())
+                .context("Failed to open index partition for TU stats")?;
 
-            Ok(Self {
+            let storage = Self {
                 keyspace: Arc::new(keyspace),
-                partition_name: "tu_stats",
-            })
+                records,
+                by_file,
+                last_file_scan: Arc::default(),
+            };
+            storage.migrate_legacy()?;
+            Ok(storage)
         }
 
-        /// Record statistics for a translation unit
-        pub fn record(&self, stats: &TranslationUnitStats) -> Result<()> {
-            let partition = self
+        /// Move records from the legacy partition into the time-ordered one
+        fn migrate_legacy(&self) -> Result<()> {
+            if !self.keyspace.partition_exists(LEGACY_PARTITION) {
+                return Ok(());
+            }
+            let legacy = self
                 .keyspace
-                .open_partition(self.partition_name, PartitionCreateOptions::default())
-                .context("Failed to open partition for TU stats")?;
+                .open_partition(LEGACY_PARTITION, PartitionCreateOptions::default())
+                .context("Failed to open legacy partition for TU stats")?;
 
-            // Use timestamp + input file as key to allow multiple compilations of the same file
-            let key = format!(
-                "{:?}:{}",
-                stats.timestamp,
-                stats.input_file.display()
-            );
+            let mut migrated = 0;
+            let mut batch = self.keyspace.batch();
+            for item in legacy.iter() {
+                let (_key, value) = item.context("Failed to read legacy TU stats entry")?;
+                let stats: TranslationUnitStats = match serde_json::from_slice(&value) {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        warn!("Dropping unreadable legacy TU stats entry: {}", e);
+                        continue;
+                    }
+                };
+                let key = record_key(&stats);
+                batch.insert(&self.by_file, file_index_key(&stats), key.clone());
+                batch.insert(&self.records, key, value);
+                migrated += 1;
+                if migrated % WRITE_BATCH_SIZE == 0 {
+                    std::mem::replace(&mut batch, self.keyspace.batch())
+                        .commit()
+                        .context("Failed to migrate legacy TU stats")?;
+                }
+            }
+            batch
+                .commit()
+                .context("Failed to migrate legacy TU stats")?;
+
+            self.keyspace
+                .delete_partition(legacy)
+                .context("Failed to delete legacy TU stats partition")?;
+            self.persist()?;
+            info!("Migrated {} legacy TU stats entries", migrated);
+            Ok(())
+        }
+
+        /// Record statistics for a translation unit and persist them to disk
+        pub fn record(&self, stats: &TranslationUnitStats) -> Result<()> {
+            self.insert_batch(std::slice::from_ref(stats))?;
+            self.persist()
+        }
+
+        /// Insert statistics for several translation units at once, without
+        /// waiting for them to reach the disk
+        pub fn insert_batch(&self, stats: &[TranslationUnitStats]) -> Result<()> {
+            let mut batch = self.keyspace.batch();
+            for stats in stats {
+                let key = record_key(stats);
+                let value = serde_json::to_vec(stats).context("Failed to serialize TU stats")?;
+                batch.insert(&self.by_file, file_index_key(stats), key.clone());
+                batch.insert(&self.records, key, value);
+            }
+            batch.commit().context("Failed to insert TU stats")
+        }
+
+        /// Flush all inserted statistics to disk
+        pub fn persist(&self) -> Result<()> {
+            self.keyspace
+                .persist(fjall::PersistMode::SyncAll)
+                .context("Failed to persist TU stats")
+        }
+
+        /// Delete the records that `policy` does not allow to keep, oldest
+        /// first. Returns the number of deleted records.
+        pub fn prune(&self, policy: &RetentionPolicy, now: SystemTime) -> Result<u64> {
+            let cutoff = policy
+                .max_age
+                .and_then(|max_age| now.checked_sub(max_age))
+                .map(timestamp_key);
 
-            let value = serde_json::to_vec(stats)
-                .context("Failed to serialize TU stats")?;
+            // Walk from the newest record until the first one over a limit;
+            // every limit is monotonic, so it and everything older must go.
+            let mut bytes = 0;
+            let mut newest_pruned = None;
+            for (kept, item) in (1..).zip(self.records.iter().rev()) {
+                let (key, value) = item.context("Failed to read TU stats entry")?;
+                bytes += (key.len() + value.len()) as u64;
+                if cutoff.is_some_and(|cutoff| key[..] < cutoff[..])
+                    || policy.max_records.is_some_and(|max| kept > max)
+                    || policy.max_size.is_some_and(|max| bytes > max)
+                {
+                    newest_pruned = Some(key);
+                    break;
+                }
+            }
+            let Some(newest_pruned) = newest_pruned else {
+                return Ok(0);
+            };
 
-            partition
-                .insert(key.as_bytes(), &value)
-                .context("Failed to insert TU stats")?;
+            let mut removed = 0;
+            let mut batch = self.keyspace.batch();
+            for item in self.records.range(..=newest_pruned) {
+                let (key, _value) = item.context("Failed to read TU stats entry")?;
+                batch.remove(&self.by_file, file_index_key_for_record(&key));
+                batch.remove(&self.records, key);
+                removed += 1;
+                if removed % WRITE_BATCH_SIZE as u64 == 0 {
+                    std::mem::replace(&mut batch, self.keyspace.batch())
+                        .commit()
+                        .context("Failed to delete TU stats")?;
+                }
+            }
+            batch.commit().context("Failed to delete TU stats")?;
+            self.persist()?;
 
-            // Flush to ensure data is persisted
-            self.keyspace.persist(fjall::PersistMode::SyncAll)
-                .context("Failed to persist TU stats")?;
+            Ok(removed)
+        }
 
+        /// Compact the on-disk data, reclaiming the space of deleted records
+        pub fn compact(&self) -> Result<()> {
+            self.records
+                .major_compact()
+                .context("Failed to compact TU stats")?;
+            self.by_file
+                .major_compact()
+                .context("Failed to compact TU stats index")?;
             Ok(())
         }
 
-        /// Get all statistics (for querying/analysis)
+        /// Get all statistics (for querying/analysis), oldest first
         pub fn get_all(&self) -> Result<Vec<TranslationUnitStats>> {
-            let partition = self
-                .keyspace
-                .open_partition(self.partition_name, PartitionCreateOptions::default())
-                .context("Failed to open partition for TU stats")?;
+            self.query(&TuStatsFilter::default())
+        }
 
+        /// Get the statistics matching `filter`, oldest first.
+        pub fn query(&self, filter: &TuStatsFilter) -> Result<Vec<TranslationUnitStats>> {
             let mut stats = Vec::new();
-            for item in partition.iter() {
-                let (_key, value) = item.context("Failed to read TU stats entry")?;
-                let stat: TranslationUnitStats = serde_json::from_slice(&value)
-                    .context("Failed to deserialize TU stats")?;
+            self.scan(filter, None, |_key, _value, stat| {
                 stats.push(stat);
+                true
+            })?;
+            Ok(stats)
+        }
+
+        /// Get the statistics matching `filter` that come after the record
+        /// with key `after`, oldest first, as a JSON array of at least one
+        /// record and about `page_size` bytes. Also returns the key of the
+        /// last record, to continue from, if there are more.
+        pub fn query_page(
+            &self,
+            filter: &TuStatsFilter,
+            after: Option<&[u8]>,
+            page_size: usize,
+        ) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+            let mut records = vec![b'['];
+            let mut last = None;
+            let mut more = false;
+            self.scan(filter, after, |key, value, _stat| {
+                if last.is_some() {
+                    if records.len() >= page_size {
+                        more = true;
+                        return false;
+                    }
+                    records.push(b',');
+                }
+                // Records are stored as JSON
+                records.extend_from_slice(value);
+                last = Some(key.to_vec());
+                true
+            })?;
+            records.push(b']');
+            Ok((records, last.filter(|_| more)))
+        }
+
+        /// Call `visit` with the key, stored value and statistics of every
+        /// record matching `filter` after the record with key `after`, oldest
+        /// first, until it returns `false`.
+        ///
+        /// A time window is served by a range scan over the records, and an
+        /// input file glob by a prefix scan over the by-file index.
+        fn scan(
+            &self,
+            filter: &TuStatsFilter,
+            after: Option<&[u8]>,
+            visit: impl FnMut(&[u8], &[u8], TranslationUnitStats) -> bool,
+        ) -> Result<()> {
+            match filter.file_glob {
+                Some(ref glob) => self.scan_by_file(filter, &FileGlob::new(glob)?, after, visit),
+                None => self.scan_by_time(filter, after, visit),
             }
+        }
 
-            Ok(stats)
+        fn scan_by_time(
+            &self,
+            filter: &TuStatsFilter,
+            after: Option<&[u8]>,
+            mut visit: impl FnMut(&[u8], &[u8], TranslationUnitStats) -> bool,
+        ) -> Result<()> {
+            let start = match (after, filter.since) {
+                // Record keys start with their timestamp, so `after` is past `since`
+                (Some(after), _) => Bound::Excluded(after.to_vec()),
+                (None, Some(since)) => Bound::Included(timestamp_key(since).to_vec()),
+                (None, None) => Bound::Unbounded,
+            };
+            let end = filter.until.map_or(Bound::Unbounded, |until| {
+                Bound::Excluded(timestamp_key(until).to_vec())
+            });
+
+            for item in self.records.range((start, end)) {
+                let (key, value) = item.context("Failed to read TU stats entry")?;
+                let stat: TranslationUnitStats =
+                    serde_json::from_slice(&value).context("Failed to deserialize TU stats")?;
+                if filter.matches_record(&stat) && !visit(&key, &value, stat) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        fn scan_by_file(
+            &self,
+            filter: &TuStatsFilter,
+            glob: &FileGlob,
+            after: Option<&[u8]>,
+            mut visit: impl FnMut(&[u8], &[u8], TranslationUnitStats) -> bool,
+        ) -> Result<()> {
+            // The next pages of a query use the keys found for its first one.
+            // Records pruned since are skipped below.
+            let cached = after.and_then(|_| {
+                let last = self.last_file_scan.lock().ok()?;
+                let (last_filter, keys) = last.as_ref()?;
+                (last_filter == filter).then(|| keys.clone())
+            });
+            let record_keys = match cached {
+                Some(keys) => keys,
+                None => {
+                    let keys: Arc<[Slice]> = self.file_scan_keys(filter, glob)?.into();
+                    if let Ok(mut last) = self.last_file_scan.lock() {
+                        *last = Some((filter.clone(), keys.clone()));
+                    }
+                    keys
+                }
+            };
+            let start = after.map_or(0, |after| {
+                record_keys.partition_point(|record_key| &record_key[..] <= after)
+            });
+
+            for record_key in &record_keys[start..] {
+                let Some(value) = self
+                    .records
+                    .get(record_key)
+                    .context("Failed to read TU stats entry")?
+                else {
+                    continue;
+                };
+                let stat: TranslationUnitStats =
+                    serde_json::from_slice(&value).context("Failed to deserialize TU stats")?;
+                if filter.matches_record(&stat) && !visit(record_key, &value, stat) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        /// The keys of the records in the time window of `filter` whose input
+        /// file matches `glob`, in time order
+        fn file_scan_keys(&self, filter: &TuStatsFilter, glob: &FileGlob) -> Result<Vec<Slice>> {
+            let since = filter.since.map(timestamp_key);
+            let until = filter.until.map(timestamp_key);
+
+            // The index is in file order, so the matching record keys are
+            // sorted afterwards
+            let mut record_keys = Vec::new();
+            for item in self.by_file.prefix(glob.literal_prefix()) {
+                let (index_key, record_key) = item.context("Failed to read TU stats index")?;
+                let Some((path, timestamp)) = split_file_index_key(&index_key) else {
+                    continue;
+                };
+                if since.is_some_and(|since| timestamp < &since[..])
+                    || until.is_some_and(|until| timestamp >= &until[..])
+                    || !glob.is_match(path)
+                {
+                    continue;
+                }
+                record_keys.push(record_key);
+            }
+            record_keys.sort();
+            Ok(record_keys)
         }
     }
 }
@@ -134,6 +979,7 @@ mod storage {
 pub use storage::TuStatsStorage;
 
 #[cfg(not(feature = "translation-unit-stats"))]
+#[derive(Clone)]
 pub struct TuStatsStorage;
 
 #[cfg(not(feature = "translation-unit-stats"))]
@@ -146,54 +992,305 @@ impl TuStatsStorage {
         Ok(())
     }
 
+    pub fn insert_batch(&self, _stats: &[TranslationUnitStats]) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn persist(&self) -> Result<()> {
+        Ok(())
+    }
+
     pub fn get_all(&self) -> Result<Vec<TranslationUnitStats>> {
         Ok(Vec::new())
     }
+
+    pub fn query(&self, _filter: &TuStatsFilter) -> Result<Vec<TranslationUnitStats>> {
+        Ok(Vec::new())
+    }
+
+    pub fn query_page(
+        &self,
+        _filter: &TuStatsFilter,
+        _after: Option<&[u8]>,
+        _page_size: usize,
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        Ok((b"[]".to_vec(), None))
+    }
+
+    pub fn prune(&self, _policy: &RetentionPolicy, _now: SystemTime) -> Result<u64> {
+        Ok(0)
+    }
+
+    pub fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Maximum number of records waiting to be written before new ones are dropped
+const QUEUE_CAPACITY: usize = 4096;
+/// Maximum number of records written to the database in a single batch
+const MAX_BATCH_SIZE: usize = 256;
+/// How often written records are synced to disk
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records dropped because the writer could not keep up
+static DROPPED_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the recorder is running, so that compilations should collect statistics
+static RECORDING: AtomicBool = AtomicBool::new(false);
+/// Whether compilations should record per-header line counts
+static PER_HEADER: AtomicBool = AtomicBool::new(false);
+/// Whether compilations should record their include tree
+static INCLUDE_GRAPH: AtomicBool = AtomicBool::new(false);
+/// Rules used by compilations to bucket their include files
+static INCLUDE_GROUPING: Lazy<Mutex<Arc<IncludeGrouping>>> = Lazy::new(Default::default);
+/// Limits compilations check their translation unit against
+static BUDGETS: Lazy<Mutex<Arc<TuBudgets>>> = Lazy::new(Default::default);
+
+/// Handle to the background thread that owns all database writes
+struct Recorder {
+    stats_file: PathBuf,
+    storage: TuStatsStorage,
+    sender: SyncSender<TranslationUnitStats>,
+    writer: thread::JoinHandle<()>,
 }
 
 /// Global statistics recorder
-static GLOBAL_RECORDER: Lazy<Mutex<Option<TuStatsStorage>>> = Lazy::new(|| Mutex::new(None));
+static GLOBAL_RECORDER: Lazy<Mutex<Option<Recorder>>> = Lazy::new(|| Mutex::new(None));
 
 /// Initialize the global TU stats recorder
 pub fn init_recorder(config: &crate::config::TranslationUnitStatsConfig) -> Result<()> {
+    // Budgets are enforced whether or not statistics are recorded
+    *BUDGETS.lock().unwrap() = Arc::new(TuBudgets::new(&config.budgets));
     if !config.enabled {
         return Ok(());
     }
 
-    let stats_file = if let Some(ref path) = config.stats_file {
-        path.clone()
-    } else {
-        // Default to sccache cache dir
-        crate::config::default_disk_cache_dir().join("tu_stats.db")
-    };
+    let stats_file = config.stats_file.clone().unwrap_or_else(default_stats_file);
 
+    let grouping = IncludeGrouping::new(&config.include_groups)?;
     let storage = TuStatsStorage::new(&stats_file)?;
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+    let writer = {
+        let storage = storage.clone();
+        thread::Builder::new()
+            .name("sccache-tu-stats".to_owned())
+            .spawn(move || run_writer(storage, receiver))
+            .context("Failed to start TU stats writer thread")?
+    };
+
+    PER_HEADER.store(config.per_header, Ordering::Relaxed);
+    INCLUDE_GRAPH.store(config.include_graph, Ordering::Relaxed);
+    *INCLUDE_GROUPING.lock().unwrap() = Arc::new(grouping);
     let mut recorder = GLOBAL_RECORDER.lock().unwrap();
-    *recorder = Some(storage);
+    *recorder = Some(Recorder {
+        stats_file,
+        storage,
+        sender,
+        writer,
+    });
+    RECORDING.store(true, Ordering::Relaxed);
     Ok(())
 }
 
-/// Record translation unit statistics
+/// Write queued records in batches until every sender is gone, syncing to
+/// disk at most once per `PERSIST_INTERVAL` and once more before returning.
+fn run_writer(storage: TuStatsStorage, receiver: Receiver<TranslationUnitStats>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    let mut unpersisted = false;
+    let mut last_persist = Instant::now();
+    let mut reported_dropped = 0;
+
+    loop {
+        let timeout = PERSIST_INTERVAL.saturating_sub(last_persist.elapsed());
+        let disconnected = match receiver.recv_timeout(timeout) {
+            Ok(stats) => {
+                batch.push(stats);
+                batch.extend(receiver.try_iter().take(MAX_BATCH_SIZE - 1));
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if !batch.is_empty() {
+            match storage.insert_batch(&batch) {
+                Ok(()) => unpersisted = true,
+                Err(e) => warn!("Failed to record {} TU stats: {}", batch.len(), e),
+            }
+            batch.clear();
+        }
+
+        if disconnected || last_persist.elapsed() >= PERSIST_INTERVAL {
+            if unpersisted {
+                if let Err(e) = storage.persist() {
+                    warn!("Failed to persist TU stats: {}", e);
+                }
+                unpersisted = false;
+            }
+            last_persist = Instant::now();
+
+            let dropped = DROPPED_RECORDS.load(Ordering::Relaxed);
+            if dropped > reported_dropped {
+                warn!(
+                    "Dropped {} TU stats records because the writer could not keep up",
+                    dropped - reported_dropped
+                );
+                reported_dropped = dropped;
+            }
+        }
+
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Stop the global recorder, waiting for all queued records to be written
+/// and synced to disk.
+pub fn shutdown_recorder() {
+    RECORDING.store(false, Ordering::Relaxed);
+    let recorder = match GLOBAL_RECORDER.lock() {
+        Ok(mut recorder) => recorder.take(),
+        Err(_) => None,
+    };
+    if let Some(Recorder { sender, writer, .. }) = recorder {
+        drop(sender);
+        if writer.join().is_err() {
+            warn!("TU stats writer thread panicked");
+        }
+    }
+}
+
+/// Whether statistics are recorded. Compilations only collect them if so,
+/// or if they are needed to check the budgets.
+pub fn recording_enabled() -> bool {
+    RECORDING.load(Ordering::Relaxed)
+}
+
+/// Have compilations collect statistics without starting the recorder
+#[cfg(test)]
+pub fn enable_recording_for_tests() {
+    RECORDING.store(true, Ordering::Relaxed);
+}
+
+/// Whether the recorder was configured to keep per-header line counts
+pub fn per_header_enabled() -> bool {
+    PER_HEADER.load(Ordering::Relaxed)
+}
+
+/// Whether the recorder was configured to keep include trees
+pub fn include_graph_enabled() -> bool {
+    INCLUDE_GRAPH.load(Ordering::Relaxed)
+}
+
+/// The include grouping rules the recorder was configured with
+pub fn include_grouping() -> Arc<IncludeGrouping> {
+    INCLUDE_GROUPING.lock().unwrap().clone()
+}
+
+/// The translation unit budgets the recorder was configured with
+pub fn budgets() -> Arc<TuBudgets> {
+    BUDGETS.lock().unwrap().clone()
+}
+
+/// How often the server applies the retention policy to the database
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically prune the global recorder's database according to the
+/// retention settings in `config`, starting right away.
+pub fn spawn_retention_task(
+    pool: &tokio::runtime::Handle,
+    config: &crate::config::TranslationUnitStatsConfig,
+) {
+    let policy = RetentionPolicy::from_config(config);
+    if !config.enabled || policy.is_unlimited() {
+        return;
+    }
+
+    pool.spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let storage = match GLOBAL_RECORDER.lock() {
+                Ok(recorder) => recorder.as_ref().map(|r| r.storage.clone()),
+                Err(_) => None,
+            };
+            let Some(storage) = storage else {
+                return;
+            };
+            match tokio::task::spawn_blocking(move || storage.prune(&policy, SystemTime::now()))
+                .await
+            {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => debug!("Pruned {} TU stats records", removed),
+                Ok(Err(e)) => warn!("Failed to prune TU stats: {}", e),
+                Err(e) => warn!("TU stats pruning task failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Queue translation unit statistics for recording.
+///
+/// This never waits for the database: if the writer has fallen behind, the
+/// record is dropped, counted, and reported in the server log.
 pub fn record_stats(stats: TranslationUnitStats) {
     if let Ok(recorder) = GLOBAL_RECORDER.lock() {
-        if let Some(ref storage) = *recorder {
-            if let Err(e) = storage.record(&stats) {
-                warn!("Failed to record TU stats: {}", e);
+        if let Some(ref recorder) = *recorder {
+            if let Err(TrySendError::Full(_)) = recorder.sender.try_send(stats) {
+                DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
             }
         }
     }
 }
 
-/// Query all translation unit statistics from the database
-pub fn query_stats(stats_file: Option<&Path>) -> Result<Vec<TranslationUnitStats>> {
-    let db_path = if let Some(path) = stats_file {
-        path.to_path_buf()
-    } else {
-        crate::config::default_disk_cache_dir().join("tu_stats.db")
-    };
+/// The database used when none is configured, in the sccache cache dir
+pub fn default_stats_file() -> PathBuf {
+    crate::config::default_disk_cache_dir().join("tu_stats.db")
+}
+
+/// The database the global recorder writes to, if that is `stats_file` (or
+/// the default database if `None`). Returns `None` if it is not, or if
+/// nothing is recorded, so that the caller can open the database itself.
+pub fn recorder_storage(stats_file: Option<&Path>) -> Option<TuStatsStorage> {
+    let recorder = GLOBAL_RECORDER.lock().ok()?;
+    let recorder = recorder.as_ref()?;
+    let requested = stats_file.map_or_else(default_stats_file, Path::to_path_buf);
+    let same_file = requested == recorder.stats_file
+        || matches!(
+            (std::fs::canonicalize(&requested), std::fs::canonicalize(&recorder.stats_file)),
+            (Ok(a), Ok(b)) if a == b
+        );
+    same_file.then(|| recorder.storage.clone())
+}
+
+/// Query the translation unit statistics matching `filter` from the database
+pub fn query_stats(
+    stats_file: Option<&Path>,
+    filter: &TuStatsFilter,
+) -> Result<Vec<TranslationUnitStats>> {
+    let db_path = stats_file.map_or_else(default_stats_file, Path::to_path_buf);
+
+    let storage = TuStatsStorage::new(&db_path)?;
+    storage.query(filter)
+}
+
+/// Apply `policy` to the database and compact it. Returns the number of
+/// deleted records.
+pub fn prune_stats(stats_file: Option<&Path>, policy: &RetentionPolicy) -> Result<u64> {
+    let db_path = stats_file.map_or_else(default_stats_file, Path::to_path_buf);
 
     let storage = TuStatsStorage::new(&db_path)?;
-    storage.get_all()
+    prune_storage(&storage, policy)
+}
+
+/// Apply `policy` to `storage` and compact it. Returns the number of deleted
+/// records.
+pub fn prune_storage(storage: &TuStatsStorage, policy: &RetentionPolicy) -> Result<u64> {
+    let removed = storage.prune(policy, SystemTime::now())?;
+    storage.compact()?;
+    Ok(removed)
 }
 
 /// Export statistics to CSV format
@@ -203,7 +1300,13 @@ pub fn export_to_csv(stats: &[TranslationUnitStats]) -> String {
     // Header - include top 3 by count and top 3 by size
     csv.push_str("timestamp,input_file,preprocessed_size,num_includes,preprocess_duration_ms,compile_duration_ms,dist_retry_count,is_distributed,");
     csv.push_str("top1_by_count,top1_count,top1_lines,top2_by_count,top2_count,top2_lines,top3_by_count,top3_count,top3_lines,");
-    csv.push_str("top1_by_size,top1_lines,top1_count,top2_by_size,top2_lines,top2_count,top3_by_size,top3_lines,top3_count\n");
+    csv.push_str("top1_by_size,top1_lines,top1_count,top2_by_size,top2_lines,top2_count,top3_by_size,top3_lines,top3_count,");
+    csv.push_str("cache_outcome,miss_reason,cache_key,hash_duration_ms,cache_lookup_duration_ms,cache_read_duration_ms,");
+    csv.push_str("crate_name,crate_types,num_externs,extern_bytes,num_source_files,");
+    csv.push_str("cache_write_duration_ms,dist_server_id,");
+    csv.push_str("dist_toolchain_duration_ms,dist_alloc_duration_ms,dist_inputs_duration_ms,");
+    csv.push_str("dist_upload_duration_ms,dist_execute_duration_ms,dist_outputs_duration_ms,");
+    csv.push_str("session\n");
 
     // Data rows
     for stat in stats {
@@ -215,7 +1318,7 @@ pub fn export_to_csv(stats: &[TranslationUnitStats]) -> String {
         csv.push_str(&format!(
             "{},{},{},{},{},{},{},{}",
             timestamp,
-            stat.input_file.display(),
+            csv_field(&stat.input_file.to_string_lossy()),
             stat.preprocessed_size,
             stat.num_includes,
             stat.preprocess_duration.as_millis(),
@@ -227,7 +1330,12 @@ pub fn export_to_csv(stats: &[TranslationUnitStats]) -> String {
         // Add top 3 by count
         for i in 0..3 {
             if let Some(inc) = stat.top_includes_by_count.get(i) {
-                csv.push_str(&format!(",{},{},{}", inc.path_prefix, inc.count, inc.lines));
+                csv.push_str(&format!(
+                    ",{},{},{}",
+                    csv_field(&inc.path_prefix),
+                    inc.count,
+                    inc.lines
+                ));
             } else {
                 csv.push_str(",,,");
             }
@@ -236,18 +1344,342 @@ pub fn export_to_csv(stats: &[TranslationUnitStats]) -> String {
         // Add top 3 by size
         for i in 0..3 {
             if let Some(inc) = stat.top_includes_by_size.get(i) {
-                csv.push_str(&format!(",{},{},{}", inc.path_prefix, inc.lines, inc.count));
+                csv.push_str(&format!(
+                    ",{},{},{}",
+                    csv_field(&inc.path_prefix),
+                    inc.lines,
+                    inc.count
+                ));
             } else {
                 csv.push_str(",,,");
             }
         }
 
+        csv.push_str(&format!(
+            ",{},{},{},{},{},{}",
+            stat.cache_outcome.as_str(),
+            stat.miss_reason.as_ref().map_or("", MissReason::as_str),
+            csv_field(&stat.cache_key),
+            stat.hash_duration.as_millis(),
+            stat.cache_lookup_duration.as_millis(),
+            stat.cache_read_duration.as_millis()
+        ));
+
+        if let Some(ref krate) = stat.rust_crate {
+            csv.push_str(&format!(
+                ",{},{},{},{},{}",
+                csv_field(&krate.crate_name),
+                csv_field(&krate.crate_types.join(" ")),
+                krate.num_externs,
+                krate.extern_bytes,
+                krate.num_source_files
+            ));
+        } else {
+            csv.push_str(",,,,,");
+        }
+
+        csv.push_str(&format!(
+            ",{},{},{},{},{},{},{},{}",
+            stat.cache_write_duration.as_millis(),
+            stat.dist_server_id
+                .map(|server_id| server_id.addr().to_string())
+                .unwrap_or_default(),
+            stat.dist_toolchain_duration.as_millis(),
+            stat.dist_alloc_duration.as_millis(),
+            stat.dist_inputs_duration.as_millis(),
+            stat.dist_upload_duration.as_millis(),
+            stat.dist_execute_duration.as_millis(),
+            stat.dist_outputs_duration.as_millis()
+        ));
+        csv.push(',');
+        csv.push_str(&csv_field(stat.session.as_deref().unwrap_or_default()));
+
         csv.push('\n');
     }
 
     csv
 }
 
+/// Quote a CSV field if it contains a separator, a quote or a line break, as
+/// described in RFC 4180
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Export statistics as JSON Lines, one object per record with all of its
+/// fields and include lists. Timestamps are RFC 3339 strings and each
+/// `<name>_duration` becomes a `<name>_duration_ms` number of milliseconds,
+/// so that the output loads into DuckDB or pandas as is.
+pub fn export_to_jsonl(stats: &[TranslationUnitStats]) -> Result<String> {
+    use serde_json::Value;
+
+    let mut jsonl = String::new();
+    for stat in stats {
+        let Value::Object(fields) = serde_json::to_value(stat)? else {
+            bail!("TU stats did not serialize to an object");
+        };
+        let record: serde_json::Map<String, Value> = fields
+            .into_iter()
+            .map(|(name, value)| {
+                if name.ends_with("_duration") {
+                    let secs = value["secs"].as_f64().unwrap_or_default();
+                    let nanos = value["nanos"].as_f64().unwrap_or_default();
+                    (format!("{}_ms", name), (secs * 1e3 + nanos / 1e6).into())
+                } else {
+                    (name, value)
+                }
+            })
+            .collect();
+        let mut record = Value::Object(record);
+        record["timestamp"] = chrono::DateTime::<chrono::Utc>::from(stat.timestamp)
+            .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+            .into();
+        jsonl.push_str(&record.to_string());
+        jsonl.push('\n');
+    }
+    Ok(jsonl)
+}
+
+/// A phase of a compile request, as an offset from the start of the request
+/// and a duration
+struct TracePhase {
+    name: &'static str,
+    offset: Duration,
+    duration: Duration,
+}
+
+/// A phase and the phases it is made of, which are laid out one after the
+/// other from its start
+struct PhaseTree(&'static str, Duration, Vec<PhaseTree>);
+
+impl PhaseTree {
+    fn leaf(name: &'static str, duration: Duration) -> Self {
+        PhaseTree(name, duration, Vec::new())
+    }
+}
+
+/// Lay out `trees` one after the other from `offset`, clamping them to `end`
+/// so that children never outlive their parent
+fn flatten_phases(
+    trees: Vec<PhaseTree>,
+    mut offset: Duration,
+    end: Duration,
+    phases: &mut Vec<TracePhase>,
+) {
+    for PhaseTree(name, duration, children) in trees {
+        let duration = duration.min(end.saturating_sub(offset));
+        if duration.is_zero() {
+            continue;
+        }
+        phases.push(TracePhase {
+            name,
+            offset,
+            duration,
+        });
+        flatten_phases(children, offset, offset + duration, phases);
+        offset += duration;
+    }
+}
+
+impl TranslationUnitStats {
+    /// Total time the request took, from hashing to the end of the cache write
+    fn request_duration(&self) -> Duration {
+        self.hash_duration
+            + self.cache_lookup_duration
+            + self.cache_read_duration
+            + self.compile_duration
+            + self.cache_write_duration
+    }
+
+    /// Phases of the request, parents before their children. Preprocessing
+    /// happens while the hash key is generated and the distributed job
+    /// phases are part of the compile, so they are nested in their
+    /// enclosing phase.
+    fn trace_phases(&self) -> Vec<TracePhase> {
+        let trees = vec![
+            PhaseTree(
+                "hash",
+                self.hash_duration,
+                vec![PhaseTree::leaf("preprocess", self.preprocess_duration)],
+            ),
+            PhaseTree::leaf("cache lookup", self.cache_lookup_duration),
+            PhaseTree::leaf("cache read", self.cache_read_duration),
+            PhaseTree(
+                "compile",
+                self.compile_duration,
+                vec![
+                    PhaseTree::leaf("toolchain", self.dist_toolchain_duration),
+                    PhaseTree::leaf("alloc", self.dist_alloc_duration),
+                    PhaseTree::leaf("inputs", self.dist_inputs_duration),
+                    PhaseTree::leaf("upload", self.dist_upload_duration),
+                    PhaseTree::leaf("execute", self.dist_execute_duration),
+                    PhaseTree::leaf("outputs", self.dist_outputs_duration),
+                ],
+            ),
+            PhaseTree::leaf("cache write", self.cache_write_duration),
+        ];
+        let mut phases = Vec::new();
+        flatten_phases(trees, Duration::ZERO, self.request_duration(), &mut phases);
+        phases
+    }
+}
+
+/// Export statistics in the Chrome Trace Event Format, which Perfetto and
+/// `chrome://tracing` can open. Every record becomes a slice spanning the
+/// whole request with its phases nested inside. Records are placed on the
+/// fewest lanes (trace threads) that keep overlapping requests apart, so the
+/// number of busy lanes at any time is the number of concurrent compiles.
+pub fn export_to_chrome_trace(stats: &[TranslationUnitStats]) -> serde_json::Value {
+    use serde_json::json;
+
+    const PID: u32 = 1;
+
+    fn micros(time: SystemTime) -> u64 {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+
+    // Records are timestamped when the request completes
+    let mut requests: Vec<(u64, u64, &TranslationUnitStats)> = stats
+        .iter()
+        .map(|stat| {
+            let end = micros(stat.timestamp);
+            let start = end.saturating_sub(stat.request_duration().as_micros() as u64);
+            (start, end, stat)
+        })
+        .collect();
+    requests.sort_by_key(|&(start, end, _)| (start, end));
+
+    let mut events = vec![json!({
+        "name": "process_name",
+        "ph": "M",
+        "pid": PID,
+        "args": { "name": "sccache" },
+    })];
+    // End time of the last request placed on each lane
+    let mut lanes: Vec<u64> = Vec::new();
+    for (start, end, stat) in requests {
+        let lane = match lanes.iter().position(|&lane_end| lane_end <= start) {
+            Some(lane) => lane,
+            None => {
+                events.push(json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": PID,
+                    "tid": lanes.len(),
+                    "args": { "name": format!("lane {}", lanes.len()) },
+                }));
+                lanes.push(0);
+                lanes.len() - 1
+            }
+        };
+        lanes[lane] = end;
+
+        let name = stat
+            .input_file
+            .file_name()
+            .unwrap_or(stat.input_file.as_os_str())
+            .to_string_lossy();
+        events.push(json!({
+            "name": name,
+            "cat": "tu",
+            "ph": "X",
+            "ts": start,
+            "dur": end - start,
+            "pid": PID,
+            "tid": lane,
+            "args": {
+                "input_file": stat.input_file.display().to_string(),
+                "cache_outcome": stat.cache_outcome.as_str(),
+                "miss_reason": stat.miss_reason.as_ref().map(MissReason::as_str),
+                "cache_key": stat.cache_key,
+                "preprocessed_size": stat.preprocessed_size,
+                "is_distributed": stat.is_distributed,
+                "dist_retry_count": stat.dist_retry_count,
+                "dist_server": stat.dist_server_id.map(|server_id| server_id.addr().to_string()),
+                "session": stat.session,
+            },
+        }));
+        for phase in stat.trace_phases() {
+            events.push(json!({
+                "name": phase.name,
+                "cat": "phase",
+                "ph": "X",
+                "ts": start + phase.offset.as_micros() as u64,
+                "dur": phase.duration.as_micros() as u64,
+                "pid": PID,
+                "tid": lane,
+            }));
+        }
+    }
+
+    json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    })
+}
+
+/// Render an include tree as a Graphviz digraph. Each file appears once,
+/// labelled with the lines it contributed, with an edge for every
+/// `#include` relationship between two files.
+pub fn include_graph_to_dot(tree: &IncludeNode) -> String {
+    fn escape(s: &str) -> String {
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    fn collect<'a>(
+        node: &'a IncludeNode,
+        lines: &mut HashMap<&'a str, usize>,
+        order: &mut Vec<&'a str>,
+        edges: &mut Vec<(&'a str, &'a str)>,
+        seen_edges: &mut HashSet<(&'a str, &'a str)>,
+    ) {
+        match lines.get_mut(node.path.as_str()) {
+            Some(total) => *total += node.lines,
+            None => {
+                lines.insert(&node.path, node.lines);
+                order.push(&node.path);
+            }
+        }
+        for child in &node.children {
+            let edge = (node.path.as_str(), child.path.as_str());
+            if seen_edges.insert(edge) {
+                edges.push(edge);
+            }
+            collect(child, lines, order, edges, seen_edges);
+        }
+    }
+
+    let mut lines = HashMap::new();
+    let mut order = Vec::new();
+    let mut edges = Vec::new();
+    let mut seen_edges = HashSet::new();
+    collect(tree, &mut lines, &mut order, &mut edges, &mut seen_edges);
+
+    let mut dot = String::from("digraph includes {\n    node [shape=box];\n");
+    for path in order {
+        dot.push_str(&format!(
+            "    \"{0}\" [label=\"{0}\\n{1} lines\"];\n",
+            escape(path),
+            lines[path]
+        ));
+    }
+    for (from, to) in edges {
+        dot.push_str(&format!(
+            "    \"{}\" -> \"{}\";\n",
+            escape(from),
+            escape(to)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
 /// Print statistics in human-readable format
 pub fn print_stats(stats: &[TranslationUnitStats]) {
     if stats.is_empty() {
@@ -261,14 +1693,56 @@ pub fn print_stats(stats: &[TranslationUnitStats]) {
     for (i, stat) in stats.iter().enumerate() {
         println!("Entry {}:", i + 1);
         println!("  File:              {}", stat.input_file.display());
-        println!("  Preprocessed size: {} bytes", stat.preprocessed_size);
-        println!("  Includes:          {}", stat.num_includes);
-        println!("  Preprocess time:   {:?}", stat.preprocess_duration);
+        if let Some(ref krate) = stat.rust_crate {
+            println!(
+                "  Crate:             {} ({})",
+                krate.crate_name,
+                krate.crate_types.join(", ")
+            );
+            println!("  Source files:      {}", krate.num_source_files);
+            println!(
+                "  Extern crates:     {} ({} bytes)",
+                krate.num_externs, krate.extern_bytes
+            );
+        } else {
+            println!("  Preprocessed size: {} bytes", stat.preprocessed_size);
+            println!("  Includes:          {}", stat.num_includes);
+            println!("  Preprocess time:   {:?}", stat.preprocess_duration);
+        }
+        if let Some(ref session) = stat.session {
+            println!("  Session:           {}", session);
+        }
+        println!("  Cache outcome:     {}", stat.cache_outcome.as_str());
+        if let Some(ref reason) = stat.miss_reason {
+            println!("  Miss reason:       {}", reason.as_str());
+        }
+        if !stat.cache_key.is_empty() {
+            println!("  Cache key:         {}", stat.cache_key);
+        }
+        println!("  Hash time:         {:?}", stat.hash_duration);
+        println!("  Cache lookup time: {:?}", stat.cache_lookup_duration);
+        if stat.cache_outcome == CacheOutcome::Hit {
+            println!("  Cache read time:   {:?}", stat.cache_read_duration);
+        }
         println!("  Compile time:      {:?}", stat.compile_duration);
         println!("  Distributed:       {}", if stat.is_distributed { "yes" } else { "no" });
+        if stat.is_distributed {
+            if let Some(server_id) = stat.dist_server_id {
+                println!("  Dist server:       {}", server_id.addr());
+            }
+            println!("  Dist toolchain:    {:?}", stat.dist_toolchain_duration);
+            println!("  Dist allocation:   {:?}", stat.dist_alloc_duration);
+            println!("  Dist inputs time:  {:?}", stat.dist_inputs_duration);
+            println!("  Dist upload time:  {:?}", stat.dist_upload_duration);
+            println!("  Dist execute time: {:?}", stat.dist_execute_duration);
+            println!("  Dist outputs time: {:?}", stat.dist_outputs_duration);
+        }
         if stat.dist_retry_count > 0 {
             println!("  Retry count:       {}", stat.dist_retry_count);
         }
+        if stat.cache_outcome == CacheOutcome::Miss {
+            println!("  Cache write time:  {:?}", stat.cache_write_duration);
+        }
 
         // Show top includes by count
         if !stat.top_includes_by_count.is_empty() {
@@ -291,3 +1765,1388 @@ pub fn print_stats(stats: &[TranslationUnitStats]) {
     }
 }
 
+/// p50/p90/max of one metric over a set of records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles<T> {
+    pub p50: T,
+    pub p90: T,
+    pub max: T,
+}
+
+impl<T: Copy + Default + Ord> Percentiles<T> {
+    fn from_values(mut values: Vec<T>) -> Self {
+        values.sort_unstable();
+        match values.last() {
+            Some(&max) => Self {
+                p50: nearest_rank(&values, 50),
+                p90: nearest_rank(&values, 90),
+                max,
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+/// Nearest-rank percentile of an already sorted, non-empty slice
+fn nearest_rank<T: Copy>(sorted: &[T], percent: usize) -> T {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Aggregated statistics for all records of a single input file
+#[derive(Debug, Clone)]
+pub struct FileSummary {
+    pub input_file: PathBuf,
+    /// Crate name of the most recent rustc compilation of this input file
+    pub crate_name: Option<String>,
+    /// Number of records for this input file
+    pub count: usize,
+    /// Number of records served from the cache
+    pub hits: usize,
+    /// Over records that were preprocessed
+    pub preprocessed_size: Percentiles<usize>,
+    /// Over records that were preprocessed
+    pub preprocess_duration: Percentiles<Duration>,
+    /// Over all records
+    pub hash_duration: Percentiles<Duration>,
+    /// Over records that were not served from the cache
+    pub compile_duration: Percentiles<Duration>,
+}
+
+impl FileSummary {
+    fn from_records(input_file: PathBuf, records: &[&TranslationUnitStats]) -> Self {
+        // Preprocessor cache hits skip preprocessing and record a size of 0
+        let preprocessed = || records.iter().filter(|s| s.preprocessed_size > 0);
+        let compiled = || {
+            records
+                .iter()
+                .filter(|s| s.cache_outcome != CacheOutcome::Hit)
+        };
+        Self {
+            input_file,
+            crate_name: records
+                .iter()
+                .rev()
+                .find_map(|s| s.rust_crate.as_ref())
+                .map(|krate| krate.crate_name.clone()),
+            count: records.len(),
+            hits: records.len() - compiled().count(),
+            preprocessed_size: Percentiles::from_values(
+                preprocessed().map(|s| s.preprocessed_size).collect(),
+            ),
+            preprocess_duration: Percentiles::from_values(
+                preprocessed().map(|s| s.preprocess_duration).collect(),
+            ),
+            hash_duration: Percentiles::from_values(
+                records.iter().map(|s| s.hash_duration).collect(),
+            ),
+            compile_duration: Percentiles::from_values(
+                compiled().map(|s| s.compile_duration).collect(),
+            ),
+        }
+    }
+}
+
+/// Summary of a set of translation unit statistics, grouped by input file
+#[derive(Debug, Clone)]
+pub struct TuStatsSummary {
+    /// Distribution over all records, regardless of input file
+    pub overall: FileSummary,
+    /// Per input file summaries, sorted by path
+    pub files: Vec<FileSummary>,
+}
+
+impl TuStatsSummary {
+    pub fn new(stats: &[TranslationUnitStats]) -> Self {
+        let mut by_file: HashMap<&Path, Vec<&TranslationUnitStats>> = HashMap::new();
+        for stat in stats {
+            by_file.entry(&stat.input_file).or_default().push(stat);
+        }
+
+        let mut files: Vec<FileSummary> = by_file
+            .into_iter()
+            .map(|(path, records)| FileSummary::from_records(path.to_owned(), &records))
+            .collect();
+        files.sort_by(|a, b| a.input_file.cmp(&b.input_file));
+
+        let all: Vec<&TranslationUnitStats> = stats.iter().collect();
+        Self {
+            overall: FileSummary::from_records(PathBuf::new(), &all),
+            files,
+        }
+    }
+
+    /// The `n` input files with the highest value of `key`, worst first
+    pub fn worst_by<K, F>(&self, n: usize, key: F) -> Vec<&FileSummary>
+    where
+        K: Ord,
+        F: Fn(&FileSummary) -> K,
+    {
+        let mut files: Vec<&FileSummary> = self.files.iter().collect();
+        // Stable sort keeps ties in path order
+        files.sort_by_key(|f| std::cmp::Reverse(key(f)));
+        files.truncate(n);
+        files
+    }
+}
+
+/// Print an aggregated summary, ranking the worst `top_n` input files by each metric
+pub fn print_summary(stats: &[TranslationUnitStats], top_n: usize) {
+    if stats.is_empty() {
+        println!("No translation unit statistics found.");
+        return;
+    }
+
+    let summary = TuStatsSummary::new(stats);
+    let overall = &summary.overall;
+
+    println!("Translation Unit Statistics Summary:");
+    println!("  Records:           {}", overall.count);
+    println!("  Input files:       {}", summary.files.len());
+    println!(
+        "  Cache hits:        {} ({:.2} %)",
+        overall.hits,
+        overall.hits as f64 * 100.0 / overall.count as f64
+    );
+    println!();
+    println!(
+        "  {:<20} {:>12} {:>12} {:>12}",
+        "Metric", "p50", "p90", "max"
+    );
+    println!(
+        "  {:<20} {:>12} {:>12} {:>12}",
+        "Preprocessed size",
+        overall.preprocessed_size.p50,
+        overall.preprocessed_size.p90,
+        overall.preprocessed_size.max
+    );
+    for (name, p) in [
+        ("Preprocess time", &overall.preprocess_duration),
+        ("Hash time", &overall.hash_duration),
+        ("Compile time", &overall.compile_duration),
+    ] {
+        println!(
+            "  {:<20} {:>12} {:>12} {:>12}",
+            name,
+            fmt_duration_as_secs(&p.p50),
+            fmt_duration_as_secs(&p.p90),
+            fmt_duration_as_secs(&p.max)
+        );
+    }
+
+    print_worst(
+        &summary,
+        top_n,
+        "preprocessed size (bytes)",
+        |f| f.preprocessed_size,
+        |v| v.to_string(),
+    );
+    print_worst(
+        &summary,
+        top_n,
+        "preprocess time",
+        |f| f.preprocess_duration,
+        fmt_duration_as_secs,
+    );
+    print_worst(
+        &summary,
+        top_n,
+        "compile time",
+        |f| f.compile_duration,
+        fmt_duration_as_secs,
+    );
+}
+
+fn print_worst<T: Copy + Default + Ord>(
+    summary: &TuStatsSummary,
+    top_n: usize,
+    title: &str,
+    metric: fn(&FileSummary) -> Percentiles<T>,
+    fmt: fn(&T) -> String,
+) {
+    println!();
+    println!("Top {} by {}:", top_n, title);
+    for (i, file) in summary
+        .worst_by(top_n, |f| metric(f).max)
+        .into_iter()
+        .enumerate()
+    {
+        let p = metric(file);
+        let name = match file.crate_name {
+            Some(ref crate_name) => format!("{} (crate {})", file.input_file.display(), crate_name),
+            None => file.input_file.display().to_string(),
+        };
+        println!(
+            "  {:>3}: {} ({} records, p50 {}, p90 {}, max {})",
+            i + 1,
+            name,
+            file.count,
+            fmt(&p.p50),
+            fmt(&p.p90),
+            fmt(&p.max)
+        );
+    }
+}
+
+/// Cost of a single header across the build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderSummary {
+    pub path: String,
+    /// Lines contributed to all translation units that include the header
+    pub total_lines: u64,
+    /// Number of translation units that include the header
+    pub including_tus: usize,
+}
+
+/// Per-header include costs aggregated over a set of translation unit statistics
+#[derive(Debug, Clone)]
+pub struct HeaderReport {
+    /// Number of translation units with per-header data
+    pub tus: usize,
+    /// Per header summaries, sorted by path
+    pub headers: Vec<HeaderSummary>,
+}
+
+impl HeaderReport {
+    /// Aggregate the most recent record with per-header data of each input
+    /// file, so that rebuilding a file does not count its headers twice.
+    pub fn new(stats: &[TranslationUnitStats]) -> Self {
+        let mut latest: HashMap<&Path, &TranslationUnitStats> = HashMap::new();
+        for stat in stats.iter().filter(|s| !s.headers.is_empty()) {
+            latest
+                .entry(&stat.input_file)
+                .and_modify(|prev| {
+                    if stat.timestamp >= prev.timestamp {
+                        *prev = stat;
+                    }
+                })
+                .or_insert(stat);
+        }
+
+        let mut by_header: HashMap<&str, HeaderSummary> = HashMap::new();
+        for stat in latest.values() {
+            for header in &stat.headers {
+                let summary = by_header
+                    .entry(&header.path)
+                    .or_insert_with(|| HeaderSummary {
+                        path: header.path.clone(),
+                        total_lines: 0,
+                        including_tus: 0,
+                    });
+                summary.total_lines += header.lines as u64;
+                summary.including_tus += 1;
+            }
+        }
+
+        let mut headers: Vec<HeaderSummary> = by_header.into_values().collect();
+        headers.sort_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            tus: latest.len(),
+            headers,
+        }
+    }
+
+    /// The `n` headers with the highest value of `key`, worst first
+    pub fn worst_by<K, F>(&self, n: usize, key: F) -> Vec<&HeaderSummary>
+    where
+        K: Ord,
+        F: Fn(&HeaderSummary) -> K,
+    {
+        let mut headers: Vec<&HeaderSummary> = self.headers.iter().collect();
+        // Stable sort keeps ties in path order
+        headers.sort_by_key(|h| std::cmp::Reverse(key(h)));
+        headers.truncate(n);
+        headers
+    }
+}
+
+/// Print the `top_n` headers contributing the most preprocessed lines and
+/// included by the most translation units
+pub fn print_headers(stats: &[TranslationUnitStats], top_n: usize) {
+    let report = HeaderReport::new(stats);
+    if report.tus == 0 {
+        println!("No per-header statistics found.");
+        println!(
+            "Set `per_header = true` in the [translation_unit_stats] configuration to record them."
+        );
+        return;
+    }
+
+    println!("Header Include Costs:");
+    println!("  Translation units: {}", report.tus);
+    println!("  Headers:           {}", report.headers.len());
+
+    println!();
+    println!("Top {} by total preprocessed lines:", top_n);
+    for (i, header) in report
+        .worst_by(top_n, |h| h.total_lines)
+        .into_iter()
+        .enumerate()
+    {
+        println!(
+            "  {:>3}: {} ({} lines, {} TUs, {} lines per TU)",
+            i + 1,
+            header.path,
+            header.total_lines,
+            header.including_tus,
+            header.total_lines / header.including_tus as u64
+        );
+    }
+
+    println!();
+    println!("Top {} by including translation units:", top_n);
+    for (i, header) in report
+        .worst_by(top_n, |h| h.including_tus)
+        .into_iter()
+        .enumerate()
+    {
+        println!(
+            "  {:>3}: {} ({} TUs, {} lines)",
+            i + 1,
+            header.path,
+            header.including_tus,
+            header.total_lines
+        );
+    }
+}
+
+/// What a diff compares for one input file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileSnapshot {
+    /// Median over records that were preprocessed, `None` without any
+    pub preprocessed_size: Option<u64>,
+    /// From the most recent record that was preprocessed
+    pub num_includes: Option<u64>,
+    /// Lines of the top include prefixes by size, from the most recent
+    /// record that was preprocessed
+    pub prefix_lines: HashMap<String, u64>,
+    /// Median over records that were not served from the cache, `None`
+    /// without any
+    pub compile_duration: Option<Duration>,
+}
+
+impl FileSnapshot {
+    fn from_records(records: &[&TranslationUnitStats]) -> Self {
+        let summary = FileSummary::from_records(PathBuf::new(), records);
+        // Records are in time order
+        let latest = records.iter().rev().find(|s| s.preprocessed_size > 0);
+        Self {
+            preprocessed_size: latest.map(|_| summary.preprocessed_size.p50 as u64),
+            num_includes: latest.map(|s| s.num_includes as u64),
+            prefix_lines: latest
+                .map(|s| {
+                    s.top_includes_by_size
+                        .iter()
+                        .map(|inc| (inc.path_prefix.clone(), inc.lines as u64))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            compile_duration: (summary.count > summary.hits)
+                .then_some(summary.compile_duration.p50),
+        }
+    }
+}
+
+/// An input file present on both sides of a diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub input_file: PathBuf,
+    pub before: FileSnapshot,
+    pub after: FileSnapshot,
+}
+
+impl FileDiff {
+    /// `None` unless both sides were preprocessed
+    pub fn size_change(&self) -> Option<i64> {
+        Some(self.after.preprocessed_size? as i64 - self.before.preprocessed_size? as i64)
+    }
+
+    /// In microseconds, `None` unless both sides were compiled
+    pub fn compile_change(&self) -> Option<i64> {
+        Some(
+            self.after.compile_duration?.as_micros() as i64
+                - self.before.compile_duration?.as_micros() as i64,
+        )
+    }
+}
+
+/// Lines contributed by an include prefix to the matched input files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixDiff {
+    pub path_prefix: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+impl PrefixDiff {
+    pub fn change(&self) -> i64 {
+        self.after as i64 - self.before as i64
+    }
+}
+
+/// Comparison of two sets of translation unit statistics, matched by input file
+#[derive(Debug, Clone)]
+pub struct TuStatsDiff {
+    /// Input files present on both sides, sorted by path
+    pub files: Vec<FileDiff>,
+    /// Input files only present before, sorted by path
+    pub only_before: Vec<PathBuf>,
+    /// Input files only present after, sorted by path
+    pub only_after: Vec<PathBuf>,
+    /// Include prefixes of the matched files, sorted by path prefix. Only
+    /// the top prefixes by size are recorded, so a file only counts for the
+    /// prefixes in its top ones on both sides: one that drops out of them
+    /// would otherwise look removed.
+    pub prefixes: Vec<PrefixDiff>,
+}
+
+impl TuStatsDiff {
+    pub fn new(before: &[TranslationUnitStats], after: &[TranslationUnitStats]) -> Self {
+        fn snapshots(stats: &[TranslationUnitStats]) -> BTreeMap<&Path, FileSnapshot> {
+            let mut by_file: HashMap<&Path, Vec<&TranslationUnitStats>> = HashMap::new();
+            for stat in stats {
+                by_file.entry(&stat.input_file).or_default().push(stat);
+            }
+            by_file
+                .into_iter()
+                .map(|(path, records)| (path, FileSnapshot::from_records(&records)))
+                .collect()
+        }
+
+        let mut before = snapshots(before);
+        let after = snapshots(after);
+        let mut files = Vec::new();
+        let mut only_after = Vec::new();
+        for (path, after) in after {
+            match before.remove(path) {
+                Some(before) => files.push(FileDiff {
+                    input_file: path.to_owned(),
+                    before,
+                    after,
+                }),
+                None => only_after.push(path.to_owned()),
+            }
+        }
+        let only_before = before.into_keys().map(Path::to_owned).collect();
+
+        fn prefix<'a, 'b>(
+            prefixes: &'b mut BTreeMap<&'a str, PrefixDiff>,
+            path_prefix: &'a str,
+        ) -> &'b mut PrefixDiff {
+            prefixes.entry(path_prefix).or_insert_with(|| PrefixDiff {
+                path_prefix: path_prefix.to_owned(),
+                before: 0,
+                after: 0,
+            })
+        }
+
+        let mut prefixes = BTreeMap::new();
+        for file in &files {
+            for (path_prefix, before) in &file.before.prefix_lines {
+                if let Some(after) = file.after.prefix_lines.get(path_prefix) {
+                    let prefix = prefix(&mut prefixes, path_prefix);
+                    prefix.before += before;
+                    prefix.after += after;
+                }
+            }
+        }
+        let prefixes = prefixes.into_values().collect();
+
+        Self {
+            files,
+            only_before,
+            only_after,
+            prefixes,
+        }
+    }
+
+    /// The `n` matched files with the largest positive `change`, biggest
+    /// first when `regressions`, or with the largest negative `change` otherwise.
+    /// Files without a `change` are left out.
+    pub fn biggest_changes<F>(&self, n: usize, regressions: bool, change: F) -> Vec<&FileDiff>
+    where
+        F: Fn(&FileDiff) -> Option<i64>,
+    {
+        let mut files: Vec<&FileDiff> = self
+            .files
+            .iter()
+            .filter(|f| change(f).map(i64::signum) == Some(if regressions { 1 } else { -1 }))
+            .collect();
+        // Stable sort keeps ties in path order
+        files.sort_by_key(|f| std::cmp::Reverse(change(f).unwrap_or_default().abs()));
+        files.truncate(n);
+        files
+    }
+}
+
+fn fmt_percent_change(before: f64, after: f64) -> String {
+    if before == 0.0 {
+        "-".to_owned()
+    } else {
+        format!("{:+.1} %", (after - before) * 100.0 / before)
+    }
+}
+
+fn fmt_duration_change(before: Duration, after: Duration) -> String {
+    if after >= before {
+        format!("+{}", fmt_duration_as_secs(&(after - before)))
+    } else {
+        format!("-{}", fmt_duration_as_secs(&(before - after)))
+    }
+}
+
+/// Print how the statistics changed from `before` to `after`: totals over
+/// the input files present in both, the include prefixes whose lines
+/// changed the most, and the `top_n` biggest regressions and improvements
+pub fn print_diff(before: &[TranslationUnitStats], after: &[TranslationUnitStats], top_n: usize) {
+    let diff = TuStatsDiff::new(before, after);
+    println!("Translation Unit Statistics Diff:");
+    println!("  Matched input files: {}", diff.files.len());
+    println!("  Only before:         {}", diff.only_before.len());
+    println!("  Only after:          {}", diff.only_after.len());
+    if diff.files.is_empty() {
+        return;
+    }
+
+    // Over the files with a value on both sides
+    let total = |f: fn(&FileSnapshot) -> Option<u64>| -> (u64, u64) {
+        diff.files
+            .iter()
+            .filter_map(|file| Some((f(&file.before)?, f(&file.after)?)))
+            .fold((0, 0), |(before, after), (b, a)| (before + b, after + a))
+    };
+    println!();
+    println!(
+        "  {:<20} {:>14} {:>14} {:>14} {:>10}",
+        "Total", "before", "after", "change", ""
+    );
+    for (name, (before, after)) in [
+        ("Preprocessed size", total(|s| s.preprocessed_size)),
+        ("Includes", total(|s| s.num_includes)),
+    ] {
+        println!(
+            "  {:<20} {:>14} {:>14} {:>+14} {:>10}",
+            name,
+            before,
+            after,
+            after as i64 - before as i64,
+            fmt_percent_change(before as f64, after as f64)
+        );
+    }
+    let (before_time, after_time) = diff
+        .files
+        .iter()
+        .filter_map(|f| Some((f.before.compile_duration?, f.after.compile_duration?)))
+        .fold(
+            (Duration::ZERO, Duration::ZERO),
+            |(before, after), (b, a)| (before + b, after + a),
+        );
+    println!(
+        "  {:<20} {:>14} {:>14} {:>14} {:>10}",
+        "Compile time",
+        fmt_duration_as_secs(&before_time),
+        fmt_duration_as_secs(&after_time),
+        fmt_duration_change(before_time, after_time),
+        fmt_percent_change(before_time.as_secs_f64(), after_time.as_secs_f64())
+    );
+
+    let mut prefixes: Vec<&PrefixDiff> = diff.prefixes.iter().filter(|p| p.change() != 0).collect();
+    prefixes.sort_by_key(|p| std::cmp::Reverse(p.change().abs()));
+    if !prefixes.is_empty() {
+        println!();
+        println!("Include prefixes with the biggest change in lines (top prefixes only):");
+        for (i, prefix) in prefixes.into_iter().take(top_n).enumerate() {
+            println!(
+                "  {:>3}: {} ({} -> {} lines, {:+}, {})",
+                i + 1,
+                prefix.path_prefix,
+                prefix.before,
+                prefix.after,
+                prefix.change(),
+                fmt_percent_change(prefix.before as f64, prefix.after as f64)
+            );
+        }
+    }
+
+    for (kind, regressions) in [("regressions", true), ("improvements", false)] {
+        let files = diff.biggest_changes(top_n, regressions, FileDiff::size_change);
+        if !files.is_empty() {
+            println!();
+            println!("Biggest {} by preprocessed size (bytes):", kind);
+            for (i, file) in files.into_iter().enumerate() {
+                // Only files preprocessed on both sides are listed
+                let (before, after) = (
+                    file.before.preprocessed_size.unwrap_or_default(),
+                    file.after.preprocessed_size.unwrap_or_default(),
+                );
+                println!(
+                    "  {:>3}: {} ({} -> {}, {:+}, {})",
+                    i + 1,
+                    file.input_file.display(),
+                    before,
+                    after,
+                    after as i64 - before as i64,
+                    fmt_percent_change(before as f64, after as f64)
+                );
+            }
+        }
+    }
+
+    for (kind, regressions) in [("regressions", true), ("improvements", false)] {
+        let files = diff.biggest_changes(top_n, regressions, FileDiff::compile_change);
+        if !files.is_empty() {
+            println!();
+            println!("Biggest {} by compile time:", kind);
+            for (i, file) in files.into_iter().enumerate() {
+                // Only files compiled on both sides are listed
+                let (before, after) = (
+                    file.before.compile_duration.unwrap_or_default(),
+                    file.after.compile_duration.unwrap_or_default(),
+                );
+                println!(
+                    "  {:>3}: {} ({} -> {}, {}, {})",
+                    i + 1,
+                    file.input_file.display(),
+                    fmt_duration_as_secs(&before),
+                    fmt_duration_as_secs(&after),
+                    fmt_duration_change(before, after),
+                    fmt_percent_change(before.as_secs_f64(), after.as_secs_f64())
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn record(input_file: &str, preprocessed_size: usize, compile_ms: u64) -> TranslationUnitStats {
+        record_at(input_file, preprocessed_size, compile_ms, 0)
+    }
+
+    fn record_at(
+        input_file: &str,
+        preprocessed_size: usize,
+        compile_ms: u64,
+        secs: u64,
+    ) -> TranslationUnitStats {
+        TranslationUnitStats {
+            input_file: PathBuf::from(input_file),
+            preprocessed_size,
+            num_includes: 0,
+            preprocess_duration: Duration::from_millis(1),
+            compile_duration: Duration::from_millis(compile_ms),
+            dist_retry_count: 0,
+            is_distributed: false,
+            top_includes_by_count: Vec::new(),
+            top_includes_by_size: Vec::new(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            cache_outcome: CacheOutcome::Miss,
+            miss_reason: Some(MissReason::Normal),
+            cache_key: String::new(),
+            hash_duration: Duration::ZERO,
+            cache_lookup_duration: Duration::ZERO,
+            cache_read_duration: Duration::ZERO,
+            cache_write_duration: Duration::ZERO,
+            dist_toolchain_duration: Duration::ZERO,
+            dist_alloc_duration: Duration::ZERO,
+            dist_inputs_duration: Duration::ZERO,
+            dist_upload_duration: Duration::ZERO,
+            dist_execute_duration: Duration::ZERO,
+            dist_outputs_duration: Duration::ZERO,
+            dist_server_id: None,
+            session: None,
+            headers: Vec::new(),
+            include_tree: None,
+            rust_crate: None,
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let p = Percentiles::from_values((1..=10).collect::<Vec<usize>>());
+        assert_eq!(
+            p,
+            Percentiles {
+                p50: 5,
+                p90: 9,
+                max: 10
+            }
+        );
+        assert_eq!(Percentiles::from_values(vec![7usize]).p50, 7);
+        assert_eq!(
+            Percentiles::<usize>::from_values(vec![]),
+            Percentiles::default()
+        );
+    }
+
+    #[test]
+    fn test_summary_groups_by_input_file() {
+        let stats = vec![
+            record("a.c", 100, 10),
+            record("b.c", 500, 5),
+            record("a.c", 300, 30),
+            record("c.c", 50, 100),
+        ];
+        let summary = TuStatsSummary::new(&stats);
+
+        assert_eq!(summary.overall.count, 4);
+        assert_eq!(summary.overall.preprocessed_size.max, 500);
+        assert_eq!(summary.files.len(), 3);
+        assert_eq!(summary.files[0].input_file, PathBuf::from("a.c"));
+        assert_eq!(summary.files[0].count, 2);
+        assert_eq!(summary.files[0].preprocessed_size.p50, 100);
+        assert_eq!(summary.files[0].preprocessed_size.max, 300);
+
+        let worst: Vec<_> = summary
+            .worst_by(2, |f| f.preprocessed_size.max)
+            .into_iter()
+            .map(|f| f.input_file.clone())
+            .collect();
+        assert_eq!(worst, vec![PathBuf::from("b.c"), PathBuf::from("a.c")]);
+
+        let worst = summary.worst_by(1, |f| f.compile_duration.max);
+        assert_eq!(worst[0].input_file, PathBuf::from("c.c"));
+    }
+
+    #[test]
+    fn test_summary_excludes_hits_from_compile_time() {
+        let mut hit = record("a.c", 100, 0);
+        hit.cache_outcome = CacheOutcome::Hit;
+        hit.miss_reason = None;
+        // A preprocessor cache hit skips preprocessing entirely
+        let mut direct_hit = record("a.c", 0, 0);
+        direct_hit.cache_outcome = CacheOutcome::Hit;
+        let stats = vec![hit, direct_hit, record("a.c", 300, 30)];
+        let summary = TuStatsSummary::new(&stats);
+
+        assert_eq!(summary.overall.hits, 2);
+        assert_eq!(
+            summary.overall.compile_duration.p50,
+            Duration::from_millis(30)
+        );
+        assert_eq!(summary.overall.preprocessed_size.p50, 100);
+    }
+
+    #[test]
+    fn test_tu_stats_diff() {
+        let with_prefix = |mut stats: TranslationUnitStats, lines| {
+            stats.top_includes_by_size = vec![IncludeStats {
+                path_prefix: "usr/include".to_owned(),
+                count: 1,
+                lines,
+            }];
+            stats
+        };
+        // Only served from the cache after, so it has nothing to compare
+        let mut hit = record("e.c", 0, 0);
+        hit.cache_outcome = CacheOutcome::Hit;
+        let before = vec![
+            with_prefix(record("a.c", 100, 10), 50),
+            record("b.c", 200, 20),
+            record("c.c", 300, 30),
+            with_prefix(record("e.c", 500, 50), 70),
+        ];
+        let after = vec![
+            with_prefix(record("a.c", 150, 5), 80),
+            record("b.c", 100, 30),
+            record("d.c", 400, 40),
+            hit,
+        ];
+
+        let diff = TuStatsDiff::new(&before, &after);
+        let paths = |files: Vec<&FileDiff>| -> Vec<PathBuf> {
+            files.into_iter().map(|f| f.input_file.clone()).collect()
+        };
+        assert_eq!(diff.files.len(), 3);
+        assert_eq!(diff.files[2].size_change(), None);
+        assert_eq!(diff.files[2].compile_change(), None);
+        assert_eq!(diff.only_before, [PathBuf::from("c.c")]);
+        assert_eq!(diff.only_after, [PathBuf::from("d.c")]);
+        assert_eq!(
+            paths(diff.biggest_changes(10, true, FileDiff::size_change)),
+            [PathBuf::from("a.c")]
+        );
+        assert_eq!(
+            paths(diff.biggest_changes(10, false, FileDiff::size_change)),
+            [PathBuf::from("b.c")]
+        );
+        assert_eq!(
+            paths(diff.biggest_changes(10, true, FileDiff::compile_change)),
+            [PathBuf::from("b.c")]
+        );
+        assert_eq!(diff.files[0].compile_change(), Some(-5_000));
+        assert_eq!(
+            diff.prefixes,
+            [PrefixDiff {
+                path_prefix: "usr/include".to_owned(),
+                before: 50,
+                after: 80,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_header_report() {
+        let header = |path: &str, lines| HeaderStats {
+            path: path.to_owned(),
+            lines,
+        };
+        let mut old_a = record_at("a.c", 0, 0, 1);
+        old_a.headers = vec![header("/inc/big.h", 1000)];
+        let mut a = record_at("a.c", 0, 0, 2);
+        a.headers = vec![header("/inc/big.h", 500), header("/inc/common.h", 10)];
+        let mut b = record_at("b.c", 0, 0, 1);
+        b.headers = vec![header("/inc/common.h", 10)];
+        let mut c = record_at("c.c", 0, 0, 1);
+        c.headers = vec![header("/inc/common.h", 10)];
+        // Records without per-header data are ignored
+        let stats = vec![old_a, a, b, c, record_at("d.c", 0, 0, 3)];
+        let report = HeaderReport::new(&stats);
+
+        assert_eq!(report.tus, 3);
+        assert_eq!(
+            report.headers,
+            vec![
+                HeaderSummary {
+                    path: "/inc/big.h".to_owned(),
+                    total_lines: 500,
+                    including_tus: 1,
+                },
+                HeaderSummary {
+                    path: "/inc/common.h".to_owned(),
+                    total_lines: 30,
+                    including_tus: 3,
+                },
+            ]
+        );
+        assert_eq!(report.worst_by(1, |h| h.total_lines)[0].path, "/inc/big.h");
+        assert_eq!(
+            report.worst_by(1, |h| h.including_tus)[0].path,
+            "/inc/common.h"
+        );
+    }
+
+    #[test]
+    fn test_export_to_csv_quotes_fields() {
+        let mut stats = record_at("/src/a,b.c", 10, 0, 60);
+        stats.top_includes_by_size = vec![IncludeStats {
+            path_prefix: "say \"hi\"".to_owned(),
+            count: 1,
+            lines: 2,
+        }];
+        let csv = export_to_csv(&[stats]);
+        let mut lines = csv.lines();
+        let columns = lines.next().unwrap().split(',').count();
+        let row = lines.next().unwrap();
+        assert!(row.starts_with("60,\"/src/a,b.c\",10,"));
+        assert!(row.contains(",\"say \"\"hi\"\"\",2,1,"));
+        // Commas inside quotes are not separators
+        assert_eq!(row.split(',').count(), columns + 1);
+    }
+
+    #[test]
+    fn test_export_to_jsonl() {
+        let mut a = record_at("/src/a.c", 10, 1500, 60);
+        a.top_includes_by_count = (0..5)
+            .map(|i| IncludeStats {
+                path_prefix: format!("dir{}", i),
+                count: i,
+                lines: i,
+            })
+            .collect();
+        let b = record_at("/src/b.c", 20, 0, 61);
+
+        let jsonl = export_to_jsonl(&[a, b]).unwrap();
+        let records: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["input_file"], "/src/a.c");
+        assert_eq!(records[0]["timestamp"], "1970-01-01T00:01:00Z");
+        assert_eq!(records[0]["compile_duration_ms"], 1500.0);
+        assert_eq!(records[0]["preprocess_duration_ms"], 1.0);
+        assert!(records[0].get("compile_duration").is_none());
+        let top = &records[0]["top_includes_by_count"];
+        assert_eq!(top[4]["path_prefix"], "dir4");
+        assert_eq!(records[1]["preprocessed_size"], 20);
+    }
+
+    #[test]
+    fn test_chrome_trace_lanes() {
+        let mut a = record_at("src/a.c", 0, 4000, 10);
+        a.hash_duration = Duration::from_millis(500);
+        a.dist_toolchain_duration = Duration::from_millis(300);
+        a.dist_alloc_duration = Duration::from_millis(700);
+        a.dist_upload_duration = Duration::from_millis(500);
+        a.dist_execute_duration = Duration::from_millis(1500);
+        let b = record_at("src/b.c", 0, 2000, 9);
+        let c = record_at("src/c.c", 0, 1000, 12);
+
+        let trace = export_to_chrome_trace(&[c, b, a]);
+        let slices: Vec<_> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["ph"] == "X")
+            .map(|event| {
+                (
+                    event["name"].as_str().unwrap(),
+                    event["ts"].as_u64().unwrap(),
+                    event["dur"].as_u64().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            vec![
+                ("a.c", 5_500_000, 4_500_000, 0),
+                ("hash", 5_500_000, 500_000, 0),
+                ("preprocess", 5_500_000, 1_000, 0),
+                ("compile", 6_000_000, 4_000_000, 0),
+                ("toolchain", 6_000_000, 300_000, 0),
+                ("alloc", 6_300_000, 700_000, 0),
+                ("upload", 7_000_000, 500_000, 0),
+                ("execute", 7_500_000, 1_500_000, 0),
+                // Overlaps a.c, so it gets a lane of its own
+                ("b.c", 7_000_000, 2_000_000, 1),
+                ("compile", 7_000_000, 2_000_000, 1),
+                // Starts after a.c ended and reuses its lane
+                ("c.c", 11_000_000, 1_000_000, 0),
+                ("compile", 11_000_000, 1_000_000, 0),
+            ]
+        );
+        let lanes = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["name"] == "thread_name")
+            .count();
+        assert_eq!(lanes, 2);
+    }
+
+    #[test]
+    fn test_include_graph_to_dot() {
+        let node = |path: &str, lines, children| IncludeNode {
+            path: path.to_owned(),
+            lines,
+            children,
+        };
+        let tree = node(
+            "/src/a.cpp",
+            10,
+            vec![
+                node("/inc/b.h", 5, vec![node("/inc/\"c\".h", 100, vec![])]),
+                node("/inc/\"c\".h", 0, vec![]),
+            ],
+        );
+        assert_eq!(tree.total_lines(), 115);
+        assert_eq!(
+            include_graph_to_dot(&tree),
+            r#"digraph includes {
+    node [shape=box];
+    "/src/a.cpp" [label="/src/a.cpp\n10 lines"];
+    "/inc/b.h" [label="/inc/b.h\n5 lines"];
+    "/inc/\"c\".h" [label="/inc/\"c\".h\n100 lines"];
+    "/src/a.cpp" -> "/inc/b.h";
+    "/inc/b.h" -> "/inc/\"c\".h";
+    "/src/a.cpp" -> "/inc/\"c\".h";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_file_glob() {
+        let glob = FileGlob::new("/src/**/*.cpp").unwrap();
+        assert_eq!(glob.literal_prefix(), "/src/");
+        assert!(glob.is_match(b"/src/a.cpp"));
+        assert!(glob.is_match(b"/src/agent/hw/a.cpp"));
+        assert!(!glob.is_match(b"/src/a.c"));
+        assert!(!glob.is_match(b"/other/src/a.cpp"));
+
+        let glob = FileGlob::new("/src/*/a?.c").unwrap();
+        assert!(glob.is_match(b"/src/x/ab.c"));
+        assert!(!glob.is_match(b"/src/x/y/ab.c"));
+        assert!(!glob.is_match(b"/src/x/abc.c"));
+
+        let glob = FileGlob::new("/src/a+b[1].c").unwrap();
+        assert!(glob.is_match(b"/src/a+b[1].c"));
+        assert!(!glob.is_match(b"/src/aab1.c"));
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_include_grouping() {
+        use crate::config::IncludeGroupRule;
+
+        let grouping = IncludeGrouping::new(&[
+            IncludeGroupRule {
+                regex: Some("^/usr/include/c\\+\\+/".to_owned()),
+                name: Some("libstdc++".to_owned()),
+                ..Default::default()
+            },
+            IncludeGroupRule {
+                glob: Some("external/folly/**".to_owned()),
+                name: Some("folly".to_owned()),
+                ..Default::default()
+            },
+            IncludeGroupRule {
+                glob: Some("fboss/**".to_owned()),
+                depth: Some(2),
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            grouping.bucket("/usr/include/c++/13/vector"),
+            IncludeBucket::Named("libstdc++")
+        );
+        assert_eq!(
+            grouping.bucket("external/folly/io/IOBuf.h"),
+            IncludeBucket::Named("folly")
+        );
+        assert_eq!(
+            grouping.bucket("fboss/agent/hw/Switch.h"),
+            IncludeBucket::Depth(2)
+        );
+        assert_eq!(
+            grouping.bucket("/usr/include/stdio.h"),
+            IncludeBucket::Depth(DEFAULT_PREFIX_DEPTH)
+        );
+
+        let invalid = |rule| IncludeGrouping::new(&[rule]).is_err();
+        assert!(invalid(IncludeGroupRule {
+            name: Some("none".to_owned()),
+            ..Default::default()
+        }));
+        assert!(invalid(IncludeGroupRule {
+            glob: Some("a/**".to_owned()),
+            regex: Some("^a/".to_owned()),
+            ..Default::default()
+        }));
+        assert!(invalid(IncludeGroupRule {
+            glob: Some("a/**".to_owned()),
+            name: Some("a".to_owned()),
+            depth: Some(1),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_budgets() {
+        use crate::config::{BudgetAction, PrefixBudgetRule, TuBudgetConfig};
+
+        let include = |path_prefix: &str, lines| IncludeStats {
+            path_prefix: path_prefix.to_owned(),
+            count: 1,
+            lines,
+        };
+        let includes = [
+            include("usr/include/boost/any.hpp", 60),
+            include("usr/include/boost/asio/io_context.hpp", 50),
+            include("usr/include/boost_extra/big.hpp", 1000),
+            include("src/a.h", 10),
+        ];
+        let budgets = TuBudgets::new(&TuBudgetConfig {
+            max_preprocessed_size: Some(1000),
+            max_includes: Some(4),
+            prefixes: vec![
+                PrefixBudgetRule {
+                    prefix: "usr/include/boost/".to_owned(),
+                    max_lines: 100,
+                },
+                PrefixBudgetRule {
+                    prefix: "src".to_owned(),
+                    max_lines: 100,
+                },
+                // Deeper than the default buckets
+                PrefixBudgetRule {
+                    prefix: "usr/include/boost/asio".to_owned(),
+                    max_lines: 40,
+                },
+            ],
+            action: BudgetAction::Warn,
+        });
+        assert!(!budgets.fail());
+        assert!(budgets.check(1000, 4, &includes[2..]).is_empty());
+        assert_eq!(
+            budgets.check(1001, 5, &includes),
+            [
+                "preprocessed size of 1001 bytes exceeds the budget of 1000 bytes",
+                "5 included files exceed the budget of 4",
+                "110 lines included from `usr/include/boost/` exceed the budget of 100",
+                "50 lines included from `usr/include/boost/asio` exceed the budget of 40",
+            ]
+        );
+        let unlimited = TuBudgets::default();
+        assert!(unlimited.check(usize::MAX, 5, &includes).is_empty());
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_keys_sort_by_time() {
+        use storage::{file_index_key, record_key, split_file_index_key, timestamp_key};
+
+        let early = record_at("/z.c", 0, 0, 9);
+        let late = record_at("/a.c", 0, 0, 300);
+        assert!(record_key(&early) < record_key(&late));
+        assert!(timestamp_key(early.timestamp) < timestamp_key(late.timestamp));
+
+        let index_key = file_index_key(&late);
+        let (path, timestamp) = split_file_index_key(&index_key).unwrap();
+        assert_eq!(path, b"/a.c");
+        assert_eq!(timestamp, timestamp_key(late.timestamp));
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_storage_query() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = TuStatsStorage::new(&tempdir.path().join("tu_stats.db")).unwrap();
+        // Insert out of order to check that results come back sorted by time
+        for (file, secs) in [("/src/b.c", 30), ("/src/a.c", 10), ("/lib/c.c", 20)] {
+            storage.record(&record_at(file, 0, 0, secs)).unwrap();
+        }
+        let mut distributed = record_at("/src/d.c", 0, 0, 40);
+        distributed.is_distributed = true;
+        distributed.session = Some("ci-42".to_owned());
+        storage.record(&distributed).unwrap();
+
+        let files = |filter: TuStatsFilter| -> Vec<PathBuf> {
+            storage
+                .query(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.input_file)
+                .collect()
+        };
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(
+            files(TuStatsFilter::default()),
+            ["/src/a.c", "/lib/c.c", "/src/b.c", "/src/d.c"].map(PathBuf::from)
+        );
+        assert_eq!(
+            files(TuStatsFilter {
+                since: at(20),
+                until: at(40),
+                ..Default::default()
+            }),
+            ["/lib/c.c", "/src/b.c"].map(PathBuf::from)
+        );
+        assert_eq!(
+            files(TuStatsFilter {
+                file_glob: Some("/src/*.c".to_string()),
+                until: at(40),
+                ..Default::default()
+            }),
+            ["/src/a.c", "/src/b.c"].map(PathBuf::from)
+        );
+        assert_eq!(
+            files(TuStatsFilter {
+                distributed: Some(true),
+                ..Default::default()
+            }),
+            ["/src/d.c"].map(PathBuf::from)
+        );
+        assert_eq!(
+            files(TuStatsFilter {
+                file_glob: Some("/src/**".to_string()),
+                session: Some("ci-42".to_string()),
+                ..Default::default()
+            }),
+            ["/src/d.c"].map(PathBuf::from)
+        );
+        assert!(
+            files(TuStatsFilter {
+                session: Some("ci-43".to_string()),
+                ..Default::default()
+            })
+            .is_empty()
+        );
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_storage_query_page() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = TuStatsStorage::new(&tempdir.path().join("tu_stats.db")).unwrap();
+        for (file, secs) in [("/src/b.c", 30), ("/src/a.c", 10), ("/lib/c.c", 20)] {
+            storage.record(&record_at(file, 0, 0, secs)).unwrap();
+        }
+        storage.record(&record_at("/src/d.c", 0, 0, 40)).unwrap();
+
+        let all_pages = |filter: &TuStatsFilter, prune_after_first: bool| -> Vec<PathBuf> {
+            let mut files = Vec::new();
+            let mut after = None;
+            loop {
+                // Pages hold at least one record
+                let (records, next) = storage.query_page(filter, after.as_deref(), 1).unwrap();
+                let records: Vec<TranslationUnitStats> = serde_json::from_slice(&records).unwrap();
+                assert_eq!(records.len(), 1);
+                files.extend(records.into_iter().map(|s| s.input_file));
+                if prune_after_first && files.len() == 1 {
+                    let policy = RetentionPolicy {
+                        max_records: Some(3),
+                        ..Default::default()
+                    };
+                    assert_eq!(storage.prune(&policy, SystemTime::now()).unwrap(), 1);
+                }
+                match next {
+                    Some(next) => after = Some(next),
+                    None => return files,
+                }
+            }
+        };
+
+        let whole = storage
+            .query_page(&TuStatsFilter::default(), None, usize::MAX)
+            .unwrap();
+        let whole: Vec<TranslationUnitStats> = serde_json::from_slice(&whole.0).unwrap();
+        assert_eq!(whole.len(), 4);
+        assert_eq!(
+            all_pages(
+                &TuStatsFilter {
+                    file_glob: Some("/src/*.c".to_string()),
+                    ..Default::default()
+                },
+                false
+            ),
+            ["/src/a.c", "/src/b.c", "/src/d.c"].map(PathBuf::from)
+        );
+        // Pruning records already returned doesn't shift the next pages
+        assert_eq!(
+            all_pages(&TuStatsFilter::default(), true),
+            ["/src/a.c", "/lib/c.c", "/src/b.c", "/src/d.c"].map(PathBuf::from)
+        );
+        // The next pages of a query by file reuse the records found for its
+        // first one
+        let filter = TuStatsFilter {
+            file_glob: Some("/src/*.c".to_string()),
+            ..Default::default()
+        };
+        let (_, next) = storage.query_page(&filter, None, 1).unwrap();
+        storage.record(&record_at("/src/e.c", 0, 0, 50)).unwrap();
+        let (records, next) = storage
+            .query_page(&filter, next.as_deref(), usize::MAX)
+            .unwrap();
+        let records: Vec<TranslationUnitStats> = serde_json::from_slice(&records).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].input_file, Path::new("/src/d.c"));
+        assert_eq!(next, None);
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_storage_migrates_legacy_keys() {
+        use fjall::{Config, PartitionCreateOptions};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("tu_stats.db");
+        {
+            let keyspace = Config::new(&path).open().unwrap();
+            let legacy = keyspace
+                .open_partition("tu_stats", PartitionCreateOptions::default())
+                .unwrap();
+            for stats in [record_at("/b.c", 0, 0, 20), record_at("/a.c", 0, 0, 10)] {
+                let key = format!("{:?}:{}", stats.timestamp, stats.input_file.display());
+                legacy
+                    .insert(key, serde_json::to_vec(&stats).unwrap())
+                    .unwrap();
+            }
+            keyspace.persist(fjall::PersistMode::SyncAll).unwrap();
+        }
+
+        let storage = TuStatsStorage::new(&path).unwrap();
+        let files: Vec<_> = storage
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.input_file)
+            .collect();
+        assert_eq!(files, ["/a.c", "/b.c"].map(PathBuf::from));
+        drop(storage);
+
+        // Reopening must not duplicate the migrated records
+        let storage = TuStatsStorage::new(&path).unwrap();
+        assert_eq!(storage.get_all().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_storage_prune() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = TuStatsStorage::new(&tempdir.path().join("tu_stats.db")).unwrap();
+        for secs in 1..=10 {
+            storage
+                .record(&record_at(&format!("/src/{}.c", secs), 0, 0, secs))
+                .unwrap();
+        }
+        let now = UNIX_EPOCH + Duration::from_secs(10);
+        let oldest = |storage: &TuStatsStorage| {
+            let all = storage.get_all().unwrap();
+            (all.len(), all[0].input_file.clone())
+        };
+
+        assert_eq!(storage.prune(&RetentionPolicy::default(), now).unwrap(), 0);
+
+        let by_age = RetentionPolicy {
+            max_age: Some(Duration::from_secs(7)),
+            ..Default::default()
+        };
+        assert_eq!(storage.prune(&by_age, now).unwrap(), 2);
+        assert_eq!(oldest(&storage), (8, PathBuf::from("/src/3.c")));
+
+        let by_count = RetentionPolicy {
+            max_records: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(storage.prune(&by_count, now).unwrap(), 3);
+        assert_eq!(oldest(&storage), (5, PathBuf::from("/src/6.c")));
+
+        let record_size = {
+            let stats = record_at("/src/10.c", 0, 0, 10);
+            (storage::record_key(&stats).len() + serde_json::to_vec(&stats).unwrap().len()) as u64
+        };
+        let by_size = RetentionPolicy {
+            max_size: Some(record_size * 2),
+            ..Default::default()
+        };
+        assert_eq!(storage.prune(&by_size, now).unwrap(), 3);
+        assert_eq!(oldest(&storage), (2, PathBuf::from("/src/9.c")));
+
+        // Pruned records must be gone from the by-file index too
+        let filter = TuStatsFilter {
+            file_glob: Some("/src/*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(storage.query(&filter).unwrap().len(), 2);
+        storage.compact().unwrap();
+    }
+
+    #[cfg(feature = "translation-unit-stats")]
+    #[test]
+    fn test_writer_flushes_on_disconnect() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = TuStatsStorage::new(&tempdir.path().join("tu_stats.db")).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = {
+            let storage = storage.clone();
+            thread::spawn(move || run_writer(storage, receiver))
+        };
+
+        for secs in 0..(MAX_BATCH_SIZE as u64 * 2 + 1) {
+            sender.send(record_at("/src/a.c", 0, 0, secs)).unwrap();
+        }
+        drop(sender);
+        writer.join().unwrap();
+
+        assert_eq!(storage.get_all().unwrap().len(), MAX_BATCH_SIZE * 2 + 1);
+    }
+}
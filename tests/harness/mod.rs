@@ -171,6 +171,8 @@ pub fn sccache_client_cfg(
     };
     sccache::config::FileConfig {
         cache: sccache::config::CacheConfigs {
+            order: None,
+            write: None,
             azure: None,
             disk: Some(disk_cache),
             gcs: None,
@@ -180,6 +182,7 @@ pub fn sccache_client_cfg(
             s3: None,
             webdav: None,
             oss: None,
+            shared: None,
         },
         dist: sccache::config::DistConfig {
             auth: Default::default(), // dangerously_insecure
@@ -193,6 +196,7 @@ pub fn sccache_client_cfg(
         },
         server_startup_timeout_ms: None,
         translation_unit_stats: Default::default(),
+        persist_stats: false,
     }
 }
 
@@ -63,6 +63,7 @@ fn config_with_dist_auth(
         },
         server_startup_timeout_ms: None,
         translation_unit_stats: Default::default(),
+        persist_stats: false,
     }
 }
 
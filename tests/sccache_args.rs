@@ -77,3 +77,20 @@ fn test_s3_invalid_args() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_tu_stats_filters_require_tu_stats() {
+    for args in [
+        ["--since", "2h"],
+        ["--until", "2h"],
+        ["--file", "*.c"],
+        ["--dist", "local"],
+    ] {
+        Command::new(SCCACHE_BIN.as_os_str())
+            .arg("--show-stats")
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("--tu-stats"));
+    }
+}